@@ -1,6 +1,13 @@
-use std::{convert::Infallible, fmt::Display, sync::Arc};
-
-use okaywal::{Configuration, LogVoid, WriteAheadLog};
+use std::{
+    convert::Infallible, fmt::Display, marker::PhantomData, path::Path, sync::Arc, time::Duration,
+};
+
+use okaywal::{
+    file_manager::{fs::StdFileManager, FileManager},
+    Configuration, GroupCommitWindow, LogVoid, WriteAheadLog,
+};
+#[cfg(unix)]
+use okaywal::{WriteThrough, WriteThroughFileManager};
 use tempfile::TempDir;
 use timings::{Benchmark, BenchmarkImplementation, Label, LabeledTimings, Timings};
 
@@ -25,7 +32,17 @@ fn main() {
         },
     ])
     .with_each_number_of_threads([1, 2, 4, 8, 16])
-    .with::<OkayWal>();
+    .with::<OkayWal<Defaults>>()
+    .with::<OkayWal<GroupCommit>>();
+
+    #[cfg(unix)]
+    let bench = bench.with::<OkayWal<DataSync>>();
+
+    #[cfg(target_os = "linux")]
+    let bench = bench.with::<OkayWal<Direct>>();
+
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    let bench = bench.with::<OkayWal<IoUring>>();
 
     #[cfg(feature = "sharded-log")]
     let bench = bench.with::<shardedlog::ShardedLog>();
@@ -48,17 +65,100 @@ struct InsertConfig {
     iters: usize,
 }
 
-struct OkayWal {
+/// How the log measured by [`OkayWal`] is configured.
+trait LogConfig: Send + Sync + 'static {
+    type Manager: FileManager;
+
+    const LABEL: &'static str;
+
+    fn configure(directory: &Path) -> Configuration<Self::Manager>;
+}
+
+/// The default configuration.
+struct Defaults;
+
+impl LogConfig for Defaults {
+    type Manager = StdFileManager;
+
+    const LABEL: &'static str = "okaywal";
+
+    fn configure(directory: &Path) -> Configuration<Self::Manager> {
+        Configuration::default_for(directory)
+    }
+}
+
+/// Waits for more commits before each `fsync`, which allows comparing
+/// throughput as more threads commit at the same time.
+struct GroupCommit;
+
+impl LogConfig for GroupCommit {
+    type Manager = StdFileManager;
+
+    const LABEL: &'static str = "okaywal-grouped";
+
+    fn configure(directory: &Path) -> Configuration<Self::Manager> {
+        Configuration::default_for(directory)
+            .group_commit_window(GroupCommitWindow::new(Duration::from_micros(500)))
+    }
+}
+
+/// Opens segments with `O_DSYNC` instead of calling `fsync`.
+#[cfg(unix)]
+struct DataSync;
+
+#[cfg(unix)]
+impl LogConfig for DataSync {
+    type Manager = WriteThroughFileManager;
+
+    const LABEL: &'static str = "okaywal-dsync";
+
+    fn configure(directory: &Path) -> Configuration<Self::Manager> {
+        Configuration::default_write_through_for(directory, WriteThrough::DataSync)
+    }
+}
+
+/// Opens segments with `O_DIRECT` and `O_DSYNC`, bypassing the page cache.
+#[cfg(target_os = "linux")]
+struct Direct;
+
+#[cfg(target_os = "linux")]
+impl LogConfig for Direct {
+    type Manager = WriteThroughFileManager;
+
+    const LABEL: &'static str = "okaywal-direct";
+
+    fn configure(directory: &Path) -> Configuration<Self::Manager> {
+        Configuration::default_write_through_for(directory, WriteThrough::Direct)
+    }
+}
+
+/// Writes and synchronizes segments using `io_uring`.
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+struct IoUring;
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+impl LogConfig for IoUring {
+    type Manager = okaywal::IoUringFileManager;
+
+    const LABEL: &'static str = "okaywal-io-uring";
+
+    fn configure(directory: &Path) -> Configuration<Self::Manager> {
+        Configuration::default_io_uring_for(directory)
+    }
+}
+
+struct OkayWal<C: LogConfig> {
     config: InsertConfig,
     _dir: Arc<TempDir>,
-    log: WriteAheadLog,
+    log: WriteAheadLog<C::Manager>,
+    _log_config: PhantomData<C>,
 }
 
-impl BenchmarkImplementation<Label, InsertConfig, Infallible> for OkayWal {
-    type SharedConfig = (InsertConfig, Arc<TempDir>, WriteAheadLog);
+impl<C: LogConfig> BenchmarkImplementation<Label, InsertConfig, Infallible> for OkayWal<C> {
+    type SharedConfig = (InsertConfig, Arc<TempDir>, WriteAheadLog<C::Manager>);
 
     fn label(number_of_threads: usize, _config: &InsertConfig) -> Label {
-        Label::from(format!("okaywal-{number_of_threads:02}t"))
+        Label::from(format!("{}-{number_of_threads:02}t", C::LABEL))
     }
 
     fn initialize_shared_config(
@@ -66,7 +166,7 @@ impl BenchmarkImplementation<Label, InsertConfig, Infallible> for OkayWal {
         config: &InsertConfig,
     ) -> Result<Self::SharedConfig, Infallible> {
         let dir = Arc::new(TempDir::new_in(".").unwrap());
-        let log = Configuration::default_for(&*dir).open(LogVoid).unwrap();
+        let log = C::configure(dir.path()).open(LogVoid).unwrap();
         Ok((*config, dir, log))
     }
 
@@ -82,6 +182,7 @@ impl BenchmarkImplementation<Label, InsertConfig, Infallible> for OkayWal {
             config,
             log,
             _dir: dir,
+            _log_config: PhantomData,
         })
     }
 
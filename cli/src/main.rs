@@ -0,0 +1,301 @@
+//! Inspects the segment files in an okaywal log directory without opening the
+//! log.
+
+use std::{
+    env, fs,
+    io::{self, ErrorKind, Read, Seek, SeekFrom},
+    path::Path,
+    process,
+};
+
+use okaywal::{
+    file_manager::{fs::StdFileManager, PathId},
+    list_segments, EntryId, ReadChunkResult, SegmentFile, SegmentReader, NEW_ENTRY,
+};
+
+const USAGE: &str = "\
+Usage: okaywal <command> <directory> [options]
+
+Commands:
+  list      List the segment files and the version info in their headers.
+  dump      Print every entry and chunk, and where each segment's valid data
+            ends.
+  verify    Check the CRC of every chunk. Exits with a non-zero status if any
+            checks fail.
+
+Options:
+  --segment <id>  Only inspect the segment with this id.
+  --data          Print the contents of each chunk when dumping.";
+
+fn main() {
+    let args = match Args::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{message}\n\n{USAGE}");
+            process::exit(2);
+        }
+    };
+
+    match run(&args) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(err) => {
+            eprintln!("error: {err}");
+            process::exit(1);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Command {
+    List,
+    Dump,
+    Verify,
+}
+
+#[derive(Debug)]
+struct Args {
+    command: Command,
+    directory: PathId,
+    segment: Option<u64>,
+    print_data: bool,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let command = match args.next().as_deref() {
+            Some("list") => Command::List,
+            Some("dump") => Command::Dump,
+            Some("verify") => Command::Verify,
+            Some(other) => return Err(format!("unknown command: {other}")),
+            None => return Err(String::from("a command is required")),
+        };
+
+        let mut directory = None;
+        let mut segment = None;
+        let mut print_data = false;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--segment" => {
+                    let id = args.next().ok_or("--segment requires an id")?;
+                    segment = Some(
+                        id.parse()
+                            .map_err(|_| format!("invalid segment id: {id}"))?,
+                    );
+                }
+                "--data" => print_data = true,
+                _ if directory.is_none() && !arg.starts_with("--") => directory = Some(arg),
+                _ => return Err(format!("unexpected argument: {arg}")),
+            }
+        }
+
+        let directory = directory.ok_or("a directory is required")?;
+        Ok(Self {
+            command,
+            directory: PathId::from(Path::new(&directory)),
+            segment,
+            print_data,
+        })
+    }
+}
+
+/// Runs the command, returning false if any problems were found.
+fn run(args: &Args) -> io::Result<bool> {
+    let file_manager = StdFileManager::default();
+    let segments = list_segments(&file_manager, &args.directory)?
+        .into_iter()
+        .filter(|segment| !matches!(args.segment, Some(id) if segment.id != id))
+        .collect::<Vec<_>>();
+    if segments.is_empty() {
+        println!("no segments found");
+    }
+
+    let mut no_problems = true;
+    for segment in &segments {
+        match args.command {
+            Command::List => list_segment(segment, &file_manager)?,
+            Command::Dump | Command::Verify => {
+                no_problems &= inspect_segment(segment, &file_manager, args)?;
+            }
+        }
+    }
+
+    Ok(no_problems)
+}
+
+fn list_segment(segment: &SegmentFile, file_manager: &StdFileManager) -> io::Result<()> {
+    let length = fs::metadata(&*segment.path)?.len();
+    let header = match SegmentReader::new(&segment.path, segment.id, file_manager) {
+        Ok(reader) => format!(
+            "format {}\tversion_info \"{}\"",
+            reader.header().format_version,
+            escape(&reader.header().version_info)
+        ),
+        Err(err) => format!("unreadable header: {err}"),
+    };
+    println!(
+        "{}\t{}\t{length} bytes\t{header}",
+        file_name(segment),
+        segment_state(segment)
+    );
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct Summary {
+    entries: u64,
+    chunks: u64,
+    crc_failures: u64,
+    torn_entries: u64,
+}
+
+/// Reads every entry in `segment`, returning false if any chunk's CRC did not
+/// match or the header could not be read.
+fn inspect_segment(
+    segment: &SegmentFile,
+    file_manager: &StdFileManager,
+    args: &Args,
+) -> io::Result<bool> {
+    let dump = args.command == Command::Dump;
+    println!("{} ({})", file_name(segment), segment_state(segment));
+    let mut reader = match SegmentReader::new(&segment.path, segment.id, file_manager) {
+        Ok(reader) => reader,
+        Err(err) => {
+            println!("  unreadable header: {err}");
+            return Ok(false);
+        }
+    };
+    if dump {
+        println!("  format {}", reader.header().format_version);
+        println!(
+            "  version_info \"{}\"",
+            escape(&reader.header().version_info)
+        );
+    }
+
+    let mut summary = Summary::default();
+    let mut last_torn_entry = None;
+    while let Some(mut entry) = reader.read_entry()? {
+        let entry_id = entry.id();
+        summary.entries += 1;
+        if dump {
+            println!("  entry {}", entry_id.0);
+        }
+
+        let torn = loop {
+            let mut chunk = match entry.read_chunk() {
+                Ok(ReadChunkResult::Chunk(chunk)) => chunk,
+                Ok(ReadChunkResult::EndOfEntry) => break false,
+                Ok(ReadChunkResult::AbortedEntry) => break true,
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => break true,
+                // Encrypted chunks can't be decoded without the log's cipher,
+                // but their CRC is checked before decryption is attempted.
+                Err(err) if err.kind() == ErrorKind::Unsupported => {
+                    summary.chunks += 1;
+                    if dump {
+                        println!("    chunk could not be decoded, crc ok: {err}");
+                    }
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            let position = chunk.log_position();
+            let data = chunk.read_all()?;
+            if chunk.bytes_remaining() > 0 {
+                break true;
+            }
+            let crc_matches = match chunk.check_crc() {
+                Ok(crc_matches) => crc_matches,
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => break true,
+                Err(err) => return Err(err),
+            };
+
+            summary.chunks += 1;
+            if !crc_matches {
+                summary.crc_failures += 1;
+            }
+            if dump || !crc_matches {
+                println!(
+                    "    chunk at {}:{}, {} bytes, crc {}",
+                    position.file_id(),
+                    position.offset(),
+                    data.len(),
+                    if crc_matches { "ok" } else { "MISMATCH" }
+                );
+            }
+            if dump && args.print_data {
+                println!("      \"{}\"", escape(&data));
+            }
+        };
+
+        if torn {
+            summary.torn_entries += 1;
+            if dump {
+                println!("    torn: the entry was not completely written");
+            }
+            last_torn_entry = Some(entry_id);
+        } else {
+            last_torn_entry = None;
+        }
+    }
+
+    let valid_until = reader.valid_until();
+    let reason = if let Some(EntryId(entry_id)) = last_torn_entry {
+        format!("entry {entry_id} is torn")
+    } else {
+        end_reason(&segment.path, segment.id, valid_until)?
+    };
+    println!(
+        "  {} entries, {} chunks, {} crc failures, {} torn entries",
+        summary.entries, summary.chunks, summary.crc_failures, summary.torn_entries
+    );
+    println!("  valid until {valid_until}: {reason}");
+
+    Ok(summary.crc_failures == 0)
+}
+
+/// Describes why reading entries stopped at `valid_until`.
+fn end_reason(path: &Path, segment_id: u64, valid_until: u64) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    file.seek(SeekFrom::Start(valid_until))?;
+    let mut header = Vec::with_capacity(9);
+    file.take(9).read_to_end(&mut header)?;
+
+    Ok(match header.first().copied() {
+        None => String::from("end of file"),
+        Some(_) if header.iter().all(|byte| *byte == 0) => String::from("zeroed preallocation"),
+        Some(NEW_ENTRY) if header.len() < 9 => String::from("truncated entry header"),
+        Some(NEW_ENTRY) => {
+            let entry_id = u64::from_le_bytes(header[1..9].try_into().expect("u64 is 8 bytes"));
+            format!(
+                "bad id: entry {entry_id} precedes the segment id {segment_id}, which is left \
+                 over from before the segment was recycled"
+            )
+        }
+        Some(byte) => format!("unexpected byte 0x{byte:02x} where an entry was expected"),
+    })
+}
+
+fn file_name(segment: &SegmentFile) -> String {
+    segment
+        .path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+const fn segment_state(segment: &SegmentFile) -> &'static str {
+    if segment.checkpointed {
+        "checkpointed"
+    } else {
+        "pending"
+    }
+}
+
+fn escape(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .flat_map(|byte| std::ascii::escape_default(*byte))
+        .map(char::from)
+        .collect()
+}
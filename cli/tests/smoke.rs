@@ -0,0 +1,90 @@
+use std::{
+    fs::OpenOptions,
+    io::{Seek, SeekFrom, Write},
+    path::Path,
+    process::{Command, Output},
+};
+
+use okaywal::{Configuration, LogPosition, LogVoid};
+use tempfile::tempdir;
+
+/// Writes a log containing one entry with a single chunk to `directory`,
+/// returning the chunk's position.
+fn write_log(directory: &Path) -> LogPosition {
+    let wal = Configuration::default_for(directory).open(LogVoid).unwrap();
+    let mut writer = wal.begin_entry().unwrap();
+    let record = writer.write_chunk(b"hello").unwrap();
+    writer.commit().unwrap();
+    wal.shutdown().unwrap();
+    record.position
+}
+
+fn okaywal(args: &[&str], directory: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_okaywal"))
+        .arg(args[0])
+        .arg(directory)
+        .args(&args[1..])
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[test]
+fn list() {
+    let directory = tempdir().unwrap();
+    write_log(directory.path());
+
+    let output = okaywal(&["list"], directory.path());
+    assert!(output.status.success());
+    let printed = stdout(&output);
+    assert!(printed.starts_with("wal-1\tpending\t"), "{printed}");
+    assert!(printed.contains("format 1"), "{printed}");
+
+    let output = okaywal(&["list", "--segment", "2"], directory.path());
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "no segments found\n");
+}
+
+#[test]
+fn dump() {
+    let directory = tempdir().unwrap();
+    write_log(directory.path());
+
+    let output = okaywal(&["dump", "--data"], directory.path());
+    assert!(output.status.success());
+    let printed = stdout(&output);
+    assert!(printed.contains("  entry 1\n"), "{printed}");
+    assert!(printed.contains("      \"hello\"\n"), "{printed}");
+    assert!(
+        printed.contains("  1 entries, 1 chunks, 0 crc failures, 0 torn entries\n"),
+        "{printed}"
+    );
+}
+
+#[test]
+fn verify() {
+    let directory = tempdir().unwrap();
+    let position = write_log(directory.path());
+
+    let output = okaywal(&["verify"], directory.path());
+    assert!(output.status.success(), "{}", stdout(&output));
+
+    // Corrupt the first byte of the chunk's data, which follows the chunk's
+    // marker and length.
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(directory.path().join("wal-1"))
+        .unwrap();
+    file.seek(SeekFrom::Start(position.offset() + 5)).unwrap();
+    file.write_all(b"j").unwrap();
+    drop(file);
+
+    let output = okaywal(&["verify"], directory.path());
+    assert!(!output.status.success());
+    let printed = stdout(&output);
+    assert!(printed.contains("crc MISMATCH"), "{printed}");
+    assert!(printed.contains("1 crc failures"), "{printed}");
+}
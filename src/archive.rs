@@ -0,0 +1,141 @@
+use std::{
+    fmt::Debug,
+    io::{self, Read},
+    path::Path,
+};
+
+use file_manager::{File, FileManager, OpenOptions, PathId};
+
+/// Preserves segments of a [`WriteAheadLog`](crate::WriteAheadLog) once they
+/// have been checkpointed.
+///
+/// Without an archiver, a checkpointed segment is renamed to `wal-<id>-cp`
+/// and reused for new entries. When an archiver is configured using
+/// [`Configuration::archive_with()`](crate::Configuration::archive_with), each
+/// segment is passed to [`Archiver::archive()`] after
+/// [`LogManager::checkpoint_to()`](crate::LogManager::checkpoint_to) succeeds
+/// and before the segment is renamed or reused.
+///
+/// A crash between checkpointing and renaming a segment causes the segment to
+/// be recovered, checkpointed and archived again the next time the log is
+/// opened. Archivers should overwrite any previous copy of a segment.
+///
+/// If an error is returned, the checkpointing thread stops as described in
+/// [`CheckpointRetry`](crate::CheckpointRetry). The segment is checkpointed
+/// and archived again when
+/// [`WriteAheadLog::restart_checkpointer()`](crate::WriteAheadLog::restart_checkpointer)
+/// is called.
+pub trait Archiver<M>: Send + Sync + Debug + 'static {
+    /// Archives a checkpointed segment.
+    ///
+    /// The archiver may copy the segment, in which case the log reuses the
+    /// segment file afterwards, or move it, in which case the log creates new
+    /// segment files as needed.
+    fn archive(&self, segment: &CheckpointedSegment<'_, M>) -> io::Result<Archived>;
+}
+
+/// A segment that has been checkpointed and is being archived.
+#[derive(Debug)]
+pub struct CheckpointedSegment<'a, M> {
+    /// The id of the segment, which is the first entry id it can contain.
+    pub id: u64,
+    /// The current path of the segment file.
+    pub path: &'a PathId,
+    /// The number of bytes at the start of the file that contain the
+    /// segment's header and entries. Any bytes that follow are preallocated
+    /// space or left over from before the segment was recycled.
+    pub length: u64,
+    /// The file manager of the log.
+    pub file_manager: &'a M,
+}
+
+impl<M> CheckpointedSegment<'_, M> {
+    /// Returns the name the segment file had before it was checkpointed,
+    /// `wal-<id>`. Archived segments should keep this name so that they can
+    /// be read with [`SegmentReader`](crate::SegmentReader).
+    #[must_use]
+    pub fn file_name(&self) -> String {
+        format!("wal-{}", self.id)
+    }
+}
+
+/// The result of [`Archiver::archive()`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Archived {
+    /// The segment was copied, and the segment file may be reused.
+    Copied,
+    /// The segment file was moved or removed, and must not be reused.
+    Moved,
+}
+
+/// An [`Archiver`] that stores checkpointed segments in a directory.
+///
+/// Segments are stored using their original `wal-<id>` name, truncated to
+/// their [`CheckpointedSegment::length`].
+#[derive(Debug, Clone)]
+pub struct ArchiveDirectory {
+    directory: PathId,
+    move_segments: bool,
+}
+
+impl ArchiveDirectory {
+    /// Returns an archiver that copies each segment into `directory`. The log
+    /// reuses the segment files after they have been copied.
+    pub fn copy_to<P: AsRef<Path>>(directory: P) -> Self {
+        Self {
+            directory: PathId::from(directory.as_ref()),
+            move_segments: false,
+        }
+    }
+
+    /// Returns an archiver that moves each segment into `directory`. This
+    /// avoids copying the segments, but the log must create a new segment
+    /// file each time one is archived. `directory` must be on the same
+    /// filesystem as the log.
+    pub fn move_to<P: AsRef<Path>>(directory: P) -> Self {
+        Self {
+            directory: PathId::from(directory.as_ref()),
+            move_segments: true,
+        }
+    }
+
+    /// Returns the directory segments are archived in.
+    #[must_use]
+    pub const fn directory(&self) -> &PathId {
+        &self.directory
+    }
+}
+
+impl<M> Archiver<M> for ArchiveDirectory
+where
+    M: FileManager,
+{
+    fn archive(&self, segment: &CheckpointedSegment<'_, M>) -> io::Result<Archived> {
+        let file_manager = segment.file_manager;
+        if !file_manager.exists(&self.directory) {
+            file_manager.create_dir_all(&self.directory)?;
+        }
+
+        let archived_path = PathId::from(self.directory.join(segment.file_name()));
+        let archived = if self.move_segments {
+            file_manager.rename(segment.path, archived_path.clone())?;
+            let file = file_manager.open(&archived_path, OpenOptions::new().write(true))?;
+            file.set_len(segment.length)?;
+            file.sync_all()?;
+            Archived::Moved
+        } else {
+            let source = file_manager.open(segment.path, OpenOptions::new().read(true))?;
+            let mut archived = file_manager.open(
+                &archived_path,
+                OpenOptions::new().create(true).write(true).read(true),
+            )?;
+            archived.set_len(0)?;
+            io::copy(&mut source.take(segment.length), &mut archived)?;
+            archived.sync_all()?;
+            Archived::Copied
+        };
+        file_manager.sync_all(&self.directory)?;
+
+        Ok(archived)
+    }
+}
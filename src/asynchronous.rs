@@ -0,0 +1,314 @@
+use std::{
+    io::{self, ErrorKind},
+    sync::{Arc, Weak},
+};
+
+use file_manager::{fs::StdFileManager, FileManager};
+
+use crate::{
+    staged::StagedChunks, to_io_result::ToIoResult, ChunkReader, CommittedEntry, Data, EntryId,
+    Error, LogPosition, OpenedChunk, WriteAheadLog,
+};
+
+/// An asynchronous interface to a [`WriteAheadLog`].
+///
+/// Operations that can block on disk I/O or on other writers are performed by
+/// a single background thread shared by all asynchronous interfaces to the
+/// same log, and the returned futures resolve once that work completes.
+/// Entries committed while the background thread is busy are written together
+/// and synchronized to disk with a single call. Waiting for an entry to be
+/// checkpointed does not involve the background thread at all: the
+/// checkpointing thread wakes the future directly.
+///
+/// This type does not depend on any particular async runtime.
+#[derive(Debug, Clone)]
+pub struct AsyncWriteAheadLog<M = StdFileManager>
+where
+    M: FileManager,
+{
+    wal: WriteAheadLog<M>,
+}
+
+impl<M> AsyncWriteAheadLog<M>
+where
+    M: FileManager,
+{
+    /// Returns an asynchronous interface to `wal`.
+    #[must_use]
+    pub const fn new(wal: WriteAheadLog<M>) -> Self {
+        Self { wal }
+    }
+
+    /// Returns the underlying, blocking [`WriteAheadLog`].
+    #[must_use]
+    pub const fn wal(&self) -> &WriteAheadLog<M> {
+        &self.wal
+    }
+
+    /// Returns the underlying, blocking [`WriteAheadLog`].
+    #[must_use]
+    pub fn into_inner(self) -> WriteAheadLog<M> {
+        self.wal
+    }
+
+    /// Begins writing an entry to this log.
+    ///
+    /// Like [`WriteAheadLog::begin_staged_entry()`], this function does not
+    /// acquire a lock on the active file. Chunks written to the returned writer
+    /// are staged in memory until the entry is committed, at which point the
+    /// entry id is allocated and the entry is copied into the log.
+    pub fn begin_entry(&self) -> AsyncEntryWriter<M> {
+        AsyncEntryWriter {
+            wal: self.wal.clone(),
+            chunks: StagedChunks::new(&self.wal.data.config),
+        }
+    }
+
+    /// Waits for `entry_id` to be checkpointed.
+    ///
+    /// If the checkpointing thread has stopped because of an error, the error
+    /// is returned wrapped in [`Error::CheckpointerFailed`].
+    ///
+    /// The returned future is woken by the checkpointing thread, and no
+    /// additional threads are used while waiting. To wait with a timeout, use
+    /// the timeout facilities provided by your async runtime.
+    pub async fn wait_checkpointed(&self, entry_id: EntryId) -> io::Result<()> {
+        let waiter = {
+            let mut files = self.wal.data.files.lock();
+            if files
+                .last_checkpointed_entry_id
+                .map_or(false, |checkpointed| checkpointed >= entry_id)
+            {
+                return Ok(());
+            } else if let Some(error) = &files.checkpointer_error {
+                return Err(error.clone().into());
+            }
+
+            let (sender, receiver) = flume::bounded(1);
+            files.checkpoint_waiters.push((entry_id, sender));
+            receiver
+        };
+
+        waiter
+            .recv_async()
+            .await
+            .map_err(|_| Error::Closed)?
+            .map_err(io::Error::from)
+    }
+
+    /// Opens the log to read previously written data.
+    ///
+    /// If the data at `position` has not been synchronized to disk yet, the
+    /// returned future resolves once it has been.
+    ///
+    /// # Errors
+    ///
+    /// May error if:
+    ///
+    /// - The file cannot be read.
+    /// - The position refers to data that has been checkpointed.
+    pub async fn read_at(&self, position: LogPosition) -> io::Result<ChunkReader<'_, M>> {
+        let (result, receiver) = flume::bounded(1);
+        send_task(&self.wal, Task::Read { position, result })?;
+        let chunk = receive(receiver).await?;
+        Ok(self.wal.chunk_reader(position.file_id, chunk))
+    }
+}
+
+impl<M> From<WriteAheadLog<M>> for AsyncWriteAheadLog<M>
+where
+    M: FileManager,
+{
+    fn from(wal: WriteAheadLog<M>) -> Self {
+        Self::new(wal)
+    }
+}
+
+/// A writer for an entry in an [`AsyncWriteAheadLog`].
+///
+/// Chunks are kept in memory until [`AsyncEntryWriter::commit()`] is called.
+/// Dropping this writer without committing discards the entry without
+/// allocating an entry id.
+#[derive(Debug)]
+#[must_use = "entries are discarded unless committed"]
+pub struct AsyncEntryWriter<M = StdFileManager>
+where
+    M: FileManager,
+{
+    wal: WriteAheadLog<M>,
+    chunks: StagedChunks,
+}
+
+impl<M> AsyncEntryWriter<M>
+where
+    M: FileManager,
+{
+    /// Appends a chunk of data to this log entry.
+    ///
+    /// The [`ChunkRecord`](crate::ChunkRecord) for this chunk is returned in
+    /// the [`CommittedEntry`] once the entry is committed.
+    pub fn write_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        self.chunks.write_chunk(data)
+    }
+
+    /// Commits this entry to the log. The returned future resolves once all
+    /// data is atomically updated and synchronized to disk.
+    pub async fn commit(self) -> io::Result<CommittedEntry> {
+        self.commit_with(false).await
+    }
+
+    /// Commits this entry to the log and forces a checkpoint to happen.
+    ///
+    /// See [`AsyncEntryWriter::commit()`].
+    pub async fn commit_and_checkpoint(self) -> io::Result<CommittedEntry> {
+        self.commit_with(true).await
+    }
+
+    async fn commit_with(self, checkpoint: bool) -> io::Result<CommittedEntry> {
+        let (result, receiver) = flume::bounded(1);
+        send_task(
+            &self.wal,
+            Task::Commit {
+                chunks: self.chunks,
+                checkpoint,
+                result,
+            },
+        )?;
+        receive(receiver).await
+    }
+}
+
+/// An operation performed by the background thread of an
+/// [`AsyncWriteAheadLog`].
+#[derive(Debug)]
+pub(crate) enum Task<M>
+where
+    M: FileManager,
+{
+    Commit {
+        chunks: StagedChunks,
+        checkpoint: bool,
+        result: flume::Sender<io::Result<CommittedEntry>>,
+    },
+    Read {
+        position: LogPosition,
+        result: flume::Sender<io::Result<OpenedChunk<M::File>>>,
+    },
+}
+
+/// Sends `task` to the background thread of `wal`, spawning the thread if it
+/// isn't running.
+fn send_task<M: FileManager>(wal: &WriteAheadLog<M>, task: Task<M>) -> io::Result<()> {
+    let mut worker = wal.data.async_worker.lock();
+    let sender = if let Some(sender) = &*worker {
+        sender
+    } else {
+        let (sender, receiver) = flume::unbounded();
+        let data = Arc::downgrade(&wal.data);
+        std::thread::Builder::new()
+            .name(String::from("okaywal-async"))
+            .spawn(move || worker_thread(&data, &receiver))?;
+        worker.insert(sender)
+    };
+    sender.send(task).map_err(|_| Error::Closed)?;
+    Ok(())
+}
+
+/// Waits for the background thread to send the result of a task.
+async fn receive<T>(receiver: flume::Receiver<io::Result<T>>) -> io::Result<T> {
+    receiver
+        .recv_async()
+        .await
+        .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "background operation panicked"))?
+}
+
+fn worker_thread<M: FileManager>(data: &Weak<Data<M>>, tasks: &flume::Receiver<Task<M>>) {
+    let mut next_task = None;
+    // The thread stops once the log is dropped, which drops the sender.
+    while let Some(task) = next_task.take().or_else(|| tasks.recv().ok()) {
+        let wal = if let Some(data) = data.upgrade() {
+            WriteAheadLog { data }
+        } else {
+            break;
+        };
+
+        match task {
+            Task::Commit {
+                chunks,
+                checkpoint,
+                result,
+            } => {
+                // Commit any other entries that are already waiting along
+                // with the first one.
+                let mut commits = vec![(chunks, checkpoint, result)];
+                for task in tasks.try_iter() {
+                    match task {
+                        Task::Commit {
+                            chunks,
+                            checkpoint,
+                            result,
+                        } => commits.push((chunks, checkpoint, result)),
+                        task @ Task::Read { .. } => {
+                            next_task = Some(task);
+                            break;
+                        }
+                    }
+                }
+                commit_all(&wal, commits);
+            }
+            Task::Read { position, result } => {
+                // The future may have been dropped, which is fine.
+                let _ = result.send(wal.open_chunk(position));
+            }
+        }
+    }
+}
+
+pub(crate) type Commit = (
+    StagedChunks,
+    bool,
+    flume::Sender<io::Result<CommittedEntry>>,
+);
+
+/// Writes `commits` to the log and synchronizes them to disk together.
+pub(crate) fn commit_all<M: FileManager>(wal: &WriteAheadLog<M>, commits: Vec<Commit>) {
+    if let [(chunks, checkpoint, result)] = &commits[..] {
+        // The future may have been dropped, which is fine.
+        let _ = result.send(wal.commit_staged(chunks, *checkpoint));
+        return;
+    }
+
+    let force_checkpoint = commits.iter().any(|(_, checkpoint, _)| *checkpoint);
+    let written = u64::try_from(commits.len())
+        .to_io()
+        .and_then(|entry_count| {
+            wal.try_append_entries(entry_count, force_checkpoint, |writer, first_entry_id| {
+                commits
+                    .iter()
+                    .zip(first_entry_id.0..)
+                    .map(|((chunks, ..), id)| chunks.write_to(writer, EntryId(id)))
+                    .collect::<io::Result<Vec<_>>>()
+            })
+        });
+    match written {
+        Ok(Ok(entries)) => {
+            for ((.., result), entry) in commits.into_iter().zip(entries) {
+                let _ = result.send(Ok(entry));
+            }
+        }
+        Ok(Err(_)) => {
+            // The batch was rolled back. Committing each entry on its own
+            // reports the error to the entries it belongs to.
+            for (chunks, checkpoint, result) in commits {
+                let _ = result.send(wal.commit_staged(&chunks, checkpoint));
+            }
+        }
+        Err(err) => {
+            // The batch may have been written, so committing the entries
+            // again could write them twice.
+            for (.., result) in commits {
+                let _ = result.send(Err(io::Error::new(err.kind(), err.to_string())));
+            }
+        }
+    }
+}
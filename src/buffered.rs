@@ -34,8 +34,16 @@ where
         if !self.buffer.is_empty() {
             self.file.write_all(&self.buffer)?;
             let bytes_written = u64::try_from(self.buffer.len()).to_io()?;
-            self.position += bytes_written;
-            self.length = self.length.max(self.position);
+            self.length = self.length.max(self.position + bytes_written);
+            // After seeking backwards within the buffer, the bytes past the
+            // write position are still written, but the position must not
+            // move past them.
+            let buffer_write_position = u64::try_from(self.buffer_write_position).to_io()?;
+            if buffer_write_position < bytes_written {
+                self.file
+                    .seek(SeekFrom::Start(self.position + buffer_write_position))?;
+            }
+            self.position += buffer_write_position;
             self.buffer_write_position = 0;
             self.buffer.clear();
         }
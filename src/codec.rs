@@ -0,0 +1,236 @@
+use std::{
+    borrow::Cow,
+    io::{self, ErrorKind, Read, Write},
+};
+
+use crate::{
+    encryption::ChunkEncryption,
+    entry::{CHUNK, ENCODED_CHUNK},
+    to_io_result::ToIoResult,
+};
+
+/// A compression codec applied to each chunk written to a
+/// [`WriteAheadLog`](crate::WriteAheadLog).
+///
+/// The codec used is recorded in each chunk's header, which allows the
+/// configured compression to be changed without affecting the ability to read
+/// previously written chunks. A chunk is only stored compressed if doing so
+/// makes it smaller.
+///
+/// Reading a chunk that was compressed with a codec whose feature is not
+/// enabled returns an error.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Compression {
+    /// Chunks are stored as they are written.
+    None,
+    /// Chunks are compressed using LZ4. Requires the `lz4` feature.
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// Chunks are compressed using Zstandard at the given level. Requires the
+    /// `zstd` feature.
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+const CODEC_NONE: u8 = 0;
+const CODEC_LZ4: u8 = 1;
+const CODEC_ZSTD: u8 = 2;
+/// Set in the codec byte of chunks whose stored bytes are encrypted.
+const ENCRYPTED: u8 = 0x80;
+
+impl Compression {
+    /// Compresses `data`, returning the codec id and the compressed bytes. If
+    /// no compression is configured or the data did not compress, `None` is
+    /// returned.
+    #[cfg_attr(not(feature = "zstd"), allow(clippy::unnecessary_wraps))]
+    fn compress(self, data: &[u8]) -> io::Result<Option<(u8, Vec<u8>)>> {
+        let compressed: Option<(u8, Vec<u8>)> = match self {
+            Self::None => None,
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => Some((CODEC_LZ4, lz4_flex::block::compress(data))),
+            #[cfg(feature = "zstd")]
+            Self::Zstd(level) => Some((CODEC_ZSTD, zstd::bulk::compress(data, level)?)),
+        };
+
+        Ok(compressed.filter(|(_, compressed)| compressed.len() < data.len()))
+    }
+}
+
+/// Writes `data` as a complete chunk, including its header and CRC. Returns
+/// the CRC of the bytes stored.
+///
+/// The data is compressed before it is encrypted. If neither is applied, the
+/// chunk is written using [`CHUNK`].
+pub(crate) fn write_chunk<W: Write>(
+    compression: Compression,
+    encryption: Option<&ChunkEncryption>,
+    data: &[u8],
+    mut destination: W,
+) -> io::Result<u32> {
+    let decoded_length = u32::try_from(data.len()).to_io()?;
+    let (mut codec, mut stored) = match compression.compress(data)? {
+        Some((codec, compressed)) => (codec, Cow::Owned(compressed)),
+        None => (CODEC_NONE, Cow::Borrowed(data)),
+    };
+    if let Some(encryption) = encryption {
+        stored = Cow::Owned(encryption.encrypt(&stored)?);
+        codec |= ENCRYPTED;
+    }
+
+    if codec == CODEC_NONE {
+        destination.write_all(&[CHUNK])?;
+        destination.write_all(&decoded_length.to_le_bytes())?;
+    } else {
+        destination.write_all(&[ENCODED_CHUNK, codec])?;
+        destination.write_all(&decoded_length.to_le_bytes())?;
+        destination.write_all(&u32::try_from(stored.len()).to_io()?.to_le_bytes())?;
+    }
+    destination.write_all(&stored)?;
+    let crc = crc32c::crc32c(&stored);
+    destination.write_all(&crc.to_le_bytes())?;
+    Ok(crc)
+}
+
+/// A chunk stored with [`ENCODED_CHUNK`], which has been read into memory and
+/// decoded.
+#[derive(Debug)]
+pub(crate) struct DecodedChunk {
+    pub data: Vec<u8>,
+    pub read_offset: usize,
+    pub stored_crc32: u32,
+    pub calculated_crc32: u32,
+}
+
+impl DecodedChunk {
+    /// Reads the remainder of an encoded chunk whose marker byte has already
+    /// been consumed. `encryption` is used to decrypt the chunk if it was
+    /// encrypted.
+    ///
+    /// The CRC covers the stored bytes. If it does not match, the data is not
+    /// decoded and the chunk is treated as empty, leaving it to the caller to
+    /// check the CRC.
+    pub fn read_from<R: Read>(
+        mut reader: R,
+        encryption: Option<&ChunkEncryption>,
+    ) -> io::Result<Self> {
+        let mut header_bytes = [0; 9];
+        reader.read_exact(&mut header_bytes)?;
+        let codec = header_bytes[0];
+        let decoded_length =
+            u32::from_le_bytes(header_bytes[1..5].try_into().expect("u32 is 4 bytes"));
+        let stored_length =
+            u32::from_le_bytes(header_bytes[5..9].try_into().expect("u32 is 4 bytes"));
+
+        // The stored bytes are read through `take()` so that a corrupted
+        // length can't cause a huge allocation.
+        let mut stored = Vec::new();
+        (&mut reader)
+            .take(u64::from(stored_length))
+            .read_to_end(&mut stored)?;
+        if stored.len() < usize::try_from(stored_length).to_io()? {
+            return Err(io::Error::from(ErrorKind::UnexpectedEof));
+        }
+        let mut stored_crc32 = [0; 4];
+        reader.read_exact(&mut stored_crc32)?;
+        let stored_crc32 = u32::from_le_bytes(stored_crc32);
+        let calculated_crc32 = crc32c::crc32c(&stored);
+
+        let data = if stored_crc32 == calculated_crc32 {
+            if codec & ENCRYPTED != 0 {
+                let encryption = encryption.ok_or_else(|| {
+                    io::Error::new(
+                        ErrorKind::Unsupported,
+                        "chunk is encrypted, but no cipher is configured",
+                    )
+                })?;
+                stored = encryption.decrypt(&stored)?;
+            }
+            decode(
+                codec & !ENCRYPTED,
+                stored,
+                usize::try_from(decoded_length).to_io()?,
+            )?
+        } else {
+            Vec::new()
+        };
+
+        Ok(Self {
+            data,
+            read_offset: 0,
+            stored_crc32,
+            calculated_crc32,
+        })
+    }
+
+    pub fn bytes_remaining(&self) -> usize {
+        self.data.len() - self.read_offset
+    }
+}
+
+impl Read for DecodedChunk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = buf.len().min(self.bytes_remaining());
+        buf[..bytes_read]
+            .copy_from_slice(&self.data[self.read_offset..self.read_offset + bytes_read]);
+        self.read_offset += bytes_read;
+        Ok(bytes_read)
+    }
+}
+
+fn decode(codec: u8, stored: Vec<u8>, decoded_length: usize) -> io::Result<Vec<u8>> {
+    let decoded = match codec {
+        CODEC_NONE => stored,
+        CODEC_LZ4 => decode_lz4(&stored, decoded_length)?,
+        CODEC_ZSTD => decode_zstd(&stored, decoded_length)?,
+        _ => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown compression codec {codec}"),
+            ))
+        }
+    };
+
+    if decoded.len() == decoded_length {
+        Ok(decoded)
+    } else {
+        Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "decoded chunk length does not match expected length",
+        ))
+    }
+}
+
+#[cfg(feature = "lz4")]
+fn decode_lz4(stored: &[u8], decoded_length: usize) -> io::Result<Vec<u8>> {
+    lz4_flex::block::decompress(stored, decoded_length)
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+}
+
+#[cfg(not(feature = "lz4"))]
+fn decode_lz4(_stored: &[u8], _decoded_length: usize) -> io::Result<Vec<u8>> {
+    Err(codec_not_enabled("lz4"))
+}
+
+#[cfg(feature = "zstd")]
+fn decode_zstd(stored: &[u8], decoded_length: usize) -> io::Result<Vec<u8>> {
+    zstd::bulk::decompress(stored, decoded_length)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decode_zstd(_stored: &[u8], _decoded_length: usize) -> io::Result<Vec<u8>> {
+    Err(codec_not_enabled("zstd"))
+}
+
+#[cfg(any(not(feature = "lz4"), not(feature = "zstd")))]
+fn codec_not_enabled(feature: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::Unsupported,
+        format!("chunk is compressed with {feature}, but the `{feature}` feature is not enabled"),
+    )
+}
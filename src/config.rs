@@ -1,8 +1,15 @@
-use std::{io, ops::Mul, path::Path, sync::Arc};
+use std::{io, ops::Mul, path::Path, sync::Arc, time::Duration};
 
 use file_manager::{fs::StdFileManager, FileManager, PathId};
 
-use crate::{LogManager, WriteAheadLog};
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::IoUringFileManager;
+use crate::{
+    upgrade, Archiver, Cipher, Compression, LogManager, RecoveryMode, RecoveryReport, Replicator,
+    WriteAheadLog, FORMAT_VERSION,
+};
+#[cfg(unix)]
+use crate::{WriteThrough, WriteThroughFileManager};
 
 /// A [`WriteAheadLog`] configuration.
 #[derive(Debug, Clone)]
@@ -38,6 +45,47 @@ pub struct Configuration<M> {
     /// The maximum disk usage, in percent, before writes start to be rejected.
     /// Must be a value between 0 and 100.
     pub max_disk_usage_percent: u16,
+    /// If set, every range of bytes synchronized to a segment file is sent to
+    /// this replicator. See [`Replicator`] for more information.
+    pub replicator: Option<Arc<dyn Replicator>>,
+    /// The compression applied to each chunk written to the log. See
+    /// [`Compression`] for more information.
+    pub compression: Compression,
+    /// If set, the data of each chunk written to the log is encrypted using
+    /// this cipher. See [`Cipher`] for more information.
+    pub cipher: Option<Arc<dyn Cipher>>,
+    /// Controls how many times a failed
+    /// [`LogManager::checkpoint_to()`](crate::LogManager::checkpoint_to) is
+    /// retried before the checkpointing thread stops. See [`CheckpointRetry`]
+    /// for more information.
+    pub checkpoint_retry: CheckpointRetry,
+    /// If set, each segment is passed to this archiver once it has been
+    /// checkpointed, before it is reused. See [`Archiver`] for more
+    /// information.
+    pub archiver: Option<Arc<dyn Archiver<M>>>,
+    /// If set, a background thread synchronizes entries committed with
+    /// [`Durability::Flush`](crate::Durability::Flush) or
+    /// [`Durability::Buffered`](crate::Durability::Buffered) to disk at this
+    /// interval.
+    pub flush_interval: Option<Duration>,
+    /// If set, a thread synchronizing a segment for a commit waits for more
+    /// commits before synchronizing the segment, allowing one `fsync` to
+    /// cover more commits. See [`GroupCommitWindow`] for more information.
+    pub group_commit_window: Option<GroupCommitWindow>,
+    /// The maximum number of segments passed to
+    /// [`LogManager::checkpoint_to_many()`](crate::LogManager::checkpoint_to_many)
+    /// at once. Defaults to 1.
+    pub checkpoint_batch_size: usize,
+    /// How segments whose entries end before more complete entries are
+    /// recovered. See [`RecoveryMode`] for more information.
+    pub recovery_mode: RecoveryMode,
+    /// If true, each recovered entry's chunks are read and their CRCs are
+    /// verified before the entry is passed to
+    /// [`LogManager::recover()`](crate::LogManager::recover). Defaults to true.
+    pub verify_recovered_chunks: bool,
+    /// The version of the segment format new segments are written in.
+    /// Defaults to [`FORMAT_VERSION`].
+    pub format_version: u8,
 }
 
 impl Default for Configuration<StdFileManager> {
@@ -59,6 +107,30 @@ impl Configuration<StdFileManager> {
     }
 }
 
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+impl Configuration<IoUringFileManager> {
+    /// Returns the default configuration for a given directory, using an
+    /// [`IoUringFileManager`] to write and synchronize segments with
+    /// `io_uring`.
+    ///
+    /// If `io_uring` isn't supported, the standard library is used instead.
+    pub fn default_io_uring_for<P: AsRef<Path>>(path: P) -> Self {
+        Self::default_with_manager(path, IoUringFileManager::new())
+    }
+}
+
+#[cfg(unix)]
+impl Configuration<WriteThroughFileManager> {
+    /// Returns the default configuration for a given directory, using a
+    /// [`WriteThroughFileManager`] that opens files using `mode`.
+    ///
+    /// Because each write is synchronized to disk as it is made, synchronizing
+    /// a segment after a commit doesn't need to call `fsync`.
+    pub fn default_write_through_for<P: AsRef<Path>>(path: P, mode: WriteThrough) -> Self {
+        Self::default_with_manager(path, WriteThroughFileManager::new(mode))
+    }
+}
+
 impl<M> Configuration<M>
 where
     M: FileManager,
@@ -80,6 +152,17 @@ where
             version_info: Arc::default(),
             max_inactive_files: 10,
             max_disk_usage_percent: 95,
+            replicator: None,
+            compression: Compression::None,
+            cipher: None,
+            checkpoint_retry: CheckpointRetry::default(),
+            archiver: None,
+            flush_interval: None,
+            group_commit_window: None,
+            checkpoint_batch_size: 1,
+            recovery_mode: RecoveryMode::Tolerant,
+            verify_recovered_chunks: true,
+            format_version: FORMAT_VERSION,
         }
     }
     /// Sets the number of bytes to preallocate for each segment file. Returns `self`.
@@ -120,12 +203,276 @@ where
         self
     }
 
+    /// Sets the replicator that receives every range of bytes synchronized to
+    /// the log's segment files. Returns `self`.
+    ///
+    /// A [`Follower`](crate::Follower) can be used as the replicator to keep a
+    /// hot standby of this log.
+    pub fn replicate_to<R: Replicator>(mut self, replicator: R) -> Self {
+        self.replicator = Some(Arc::new(replicator));
+        self
+    }
+
+    /// Sets the compression applied to each chunk written to the log. Returns
+    /// `self`.
+    ///
+    /// Compressed chunks are buffered in memory until they are finished, even
+    /// when written using [`EntryWriter::begin_chunk()`](crate::EntryWriter::begin_chunk).
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets the cipher used to encrypt the data of each chunk written to the
+    /// log. Returns `self`.
+    ///
+    /// The cipher must be able to decrypt every key id recorded in the
+    /// headers of the log's existing segments.
+    pub fn encrypt_with<C: Cipher>(mut self, cipher: C) -> Self {
+        self.cipher = Some(Arc::new(cipher));
+        self
+    }
+
+    /// Sets the policy for retrying a failed
+    /// [`LogManager::checkpoint_to()`](crate::LogManager::checkpoint_to).
+    /// Returns `self`.
+    pub fn checkpoint_retry(mut self, retry: CheckpointRetry) -> Self {
+        self.checkpoint_retry = retry;
+        self
+    }
+
+    /// Sets the archiver that each segment is passed to once it has been
+    /// checkpointed. Returns `self`.
+    ///
+    /// [`ArchiveDirectory`](crate::ArchiveDirectory) can be used to copy or
+    /// move the segments into another directory.
+    pub fn archive_with<A: Archiver<M>>(mut self, archiver: A) -> Self {
+        self.archiver = Some(Arc::new(archiver));
+        self
+    }
+
+    /// Sets the interval at which entries committed with
+    /// [`Durability::Flush`](crate::Durability::Flush) or
+    /// [`Durability::Buffered`](crate::Durability::Buffered) are synchronized
+    /// to disk by a background thread. Returns `self`.
+    ///
+    /// This bounds how long an entry committed without
+    /// [`Durability::Fsync`](crate::Durability::Fsync) can be lost for if the
+    /// process or operating system crashes.
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = Some(interval);
+        self
+    }
+
+    /// Sets the window a thread synchronizing a segment for a commit waits
+    /// for more commits to join it. Returns `self`.
+    ///
+    /// This trades latency for throughput: each commit may take up to
+    /// [`GroupCommitWindow::max_delay`] longer, but with many threads
+    /// committing, fewer `fsync`s are needed. The effect can be measured
+    /// using [`Stats::commits_per_fsync()`](crate::Stats::commits_per_fsync).
+    pub fn group_commit_window(mut self, window: GroupCommitWindow) -> Self {
+        self.group_commit_window = Some(window);
+        self
+    }
+
+    /// Sets the maximum number of segments passed to
+    /// [`LogManager::checkpoint_to_many()`](crate::LogManager::checkpoint_to_many)
+    /// at once. Returns `self`.
+    ///
+    /// When segments are filled faster than they can be checkpointed, the
+    /// segments waiting to be checkpointed are passed to the log manager
+    /// together, which can checkpoint them concurrently. Values less than 1
+    /// are treated as 1.
+    pub fn checkpoint_batch_size(mut self, segments: usize) -> Self {
+        self.checkpoint_batch_size = segments.max(1);
+        self
+    }
+
+    /// Sets how segments whose entries end before more complete entries are
+    /// recovered. Returns `self`.
+    pub fn recovery_mode(mut self, mode: RecoveryMode) -> Self {
+        self.recovery_mode = mode;
+        self
+    }
+
+    /// Sets whether each recovered entry's chunks are verified before the
+    /// entry is passed to [`LogManager::recover()`](crate::LogManager::recover).
+    /// Returns `self`.
+    ///
+    /// When enabled, an entry that is incomplete or has a chunk whose CRC
+    /// doesn't match is never passed to the log manager. The segment's
+    /// readable entries end where that entry begins, and it is counted in the
+    /// [`RecoveryReport`] as an aborted entry or a CRC failure. The entries
+    /// that follow it are handled according to
+    /// [`Configuration::recovery_mode`].
+    ///
+    /// When disabled, each entry's chunks are only read once, by the log
+    /// manager, which is responsible for checking their CRCs using
+    /// [`EntryChunk::check_crc()`](crate::EntryChunk::check_crc).
+    pub fn verify_recovered_chunks(mut self, verify: bool) -> Self {
+        self.verify_recovered_chunks = verify;
+        self
+    }
+
+    /// Sets the version of the segment format new segments are written in.
+    /// Returns `self`.
+    ///
+    /// Writing an older version allows the log to be read by releases that
+    /// don't support the newer versions, such as while rolling out a new
+    /// release. Segments written in any version up to [`FORMAT_VERSION`] can
+    /// be read regardless of this setting. If the segment being written to
+    /// when the log was closed was written in a different version, a new
+    /// segment is started when the log is opened. Versions newer than
+    /// [`FORMAT_VERSION`] are treated as [`FORMAT_VERSION`].
+    pub fn format_version(mut self, version: u8) -> Self {
+        self.format_version = version.min(FORMAT_VERSION);
+        self
+    }
+
+    /// Rewrites each segment in [`Configuration::directory`] that hasn't been
+    /// checkpointed and was written in an older version of the segment format
+    /// than [`Configuration::format_version`], returning the ids of the
+    /// rewritten segments.
+    ///
+    /// Returns [`Error::DirectoryInUse`](crate::Error::DirectoryInUse) if a
+    /// log or [`Follower`](crate::Follower) is open in the directory, and logs
+    /// can't be opened in the directory until the upgrade finishes. A log
+    /// remains open until each of its clones has been dropped and its
+    /// background threads have finished the work in progress.
+    ///
+    /// Only logs opened by this process are detected, so this must not be
+    /// called while another process has a log open in the directory. Logs
+    /// whose files are stored on disk using the standard library, such as with
+    /// [`StdFileManager`], are detected regardless of the path they were
+    /// opened with. Other file managers are identified by their type, so logs
+    /// opened with the same path and type of file manager are detected even
+    /// if they use separate instances that don't share their files.
+    ///
+    /// Each segment's entries are read the same way they are when recovering
+    /// the log, and the entries that would be recovered are written to a new
+    /// segment file using this configuration's
+    /// [`compression`](Self::compression) and [`cipher`](Self::cipher). The
+    /// new file keeps the segment's id and version info, and replaces the
+    /// segment once it has been synchronized. If the upgrade is interrupted,
+    /// the new file is removed when the log is opened or upgraded again.
+    /// Because the entries are rewritten, the positions of their chunks
+    /// change, and the [`ChunkRecord`](crate::ChunkRecord)s returned when they
+    /// were written can no longer be used to read them. The new positions are
+    /// passed to [`LogManager::recover()`] when the log is opened.
+    ///
+    /// Segments replicated to a [`Follower`](crate::Follower) aren't upgraded
+    /// on the follower. Its directory must be upgraded at the same time, while
+    /// neither log is open.
+    pub fn upgrade_format(&self) -> io::Result<Vec<u64>> {
+        upgrade::upgrade_segments(self)
+    }
+
     /// Opens the log using the provided log manager with this configuration.
     pub fn open<Manager: LogManager<M>>(self, manager: Manager) -> io::Result<WriteAheadLog<M>> {
+        WriteAheadLog::open(self, manager).map(|(wal, _)| wal)
+    }
+
+    /// Opens the log using the provided log manager with this configuration,
+    /// returning a [`RecoveryReport`] describing what was recovered and what
+    /// was discarded.
+    pub fn open_with_report<Manager: LogManager<M>>(
+        self,
+        manager: Manager,
+    ) -> io::Result<(WriteAheadLog<M>, RecoveryReport)> {
         WriteAheadLog::open(self, manager)
     }
 }
 
+/// The policy for retrying a failed
+/// [`LogManager::checkpoint_to()`](crate::LogManager::checkpoint_to).
+///
+/// After each failed attempt, the checkpointing thread waits before trying
+/// again. The first wait is `initial_backoff`, and each following wait is
+/// twice as long as the previous one, up to `max_backoff`. Once `max_retries`
+/// retries have failed, the checkpointing thread stops, and the error is
+/// returned from [`WriteAheadLog::wait_checkpointed_for()`],
+/// [`WriteAheadLog::begin_entry()`] and [`WriteAheadLog::shutdown()`] until
+/// [`WriteAheadLog::restart_checkpointer()`] is called.
+///
+/// By default, failed checkpoints are not retried.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct CheckpointRetry {
+    /// The number of times a failed checkpoint is retried.
+    pub max_retries: u32,
+    /// The time to wait before the first retry.
+    pub initial_backoff: Duration,
+    /// The longest time to wait between retries.
+    pub max_backoff: Duration,
+}
+
+impl Default for CheckpointRetry {
+    fn default() -> Self {
+        Self {
+            max_retries: 0,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl CheckpointRetry {
+    /// Returns the time to wait before retrying after `failed_attempts`
+    /// attempts have failed.
+    #[must_use]
+    pub fn backoff(&self, failed_attempts: u32) -> Duration {
+        let multiplier = 1_u32
+            .checked_shl(failed_attempts.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.initial_backoff
+            .saturating_mul(multiplier)
+            .min(self.max_backoff)
+    }
+}
+
+/// How long a thread synchronizing a segment for a commit waits for more
+/// commits to join it.
+///
+/// Without a window, commits are only synchronized together when they are
+/// made while another thread is already synchronizing the segment. With a
+/// window, the first thread to synchronize a segment waits until `max_delay`
+/// has elapsed or, if `max_bytes` is set, until at least that many committed
+/// bytes are waiting to be synchronized. Commits made while it waits are
+/// synchronized by the same `fsync`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[must_use]
+pub struct GroupCommitWindow {
+    /// The longest time to wait for more commits.
+    pub max_delay: Duration,
+    /// If set, stop waiting once this many committed bytes are waiting to be
+    /// synchronized.
+    pub max_bytes: Option<u64>,
+}
+
+impl GroupCommitWindow {
+    /// Returns a window that waits up to `max_delay` for more commits.
+    pub const fn new(max_delay: Duration) -> Self {
+        Self {
+            max_delay,
+            max_bytes: None,
+        }
+    }
+
+    /// Stops waiting once `bytes` committed bytes are waiting to be
+    /// synchronized. Returns `self`.
+    pub const fn max_bytes(mut self, bytes: u64) -> Self {
+        self.max_bytes = Some(bytes);
+        self
+    }
+
+    /// Returns true if `unsynchronized_bytes` is enough to stop waiting for
+    /// more commits.
+    pub(crate) fn is_full(&self, unsynchronized_bytes: u64) -> bool {
+        self.max_bytes
+            .map_or(false, |max_bytes| unsynchronized_bytes >= max_bytes)
+    }
+}
+
 fn megabytes<T: Mul<Output = T> + From<u16>>(megs: T) -> T {
     kilobytes(megs) * T::from(1024)
 }
@@ -0,0 +1,117 @@
+use std::{any::TypeId, io, path::PathBuf};
+
+use file_manager::{fs::StdFileManager, FileManager};
+use parking_lot::{const_mutex, Mutex};
+
+use crate::{Configuration, Error};
+
+/// The directories in use by this process, and how each is being used.
+static DIRECTORIES: Mutex<Vec<(Directory, Usage)>> = const_mutex(Vec::new());
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Usage {
+    /// The number of logs and followers open in the directory.
+    Open(usize),
+    Upgrading,
+}
+
+/// Identifies a directory by the file manager it is accessed through and its
+/// path.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Directory {
+    /// The type of the file manager, or `None` for file managers that store
+    /// files on disk using the standard library, whose paths are
+    /// canonicalized.
+    manager: Option<TypeId>,
+    path: PathBuf,
+}
+
+impl Directory {
+    /// Returns the directory `config` stores its files in, which must exist
+    /// if its file manager stores files on disk.
+    fn for_config<M: FileManager>(config: &Configuration<M>) -> io::Result<Self> {
+        let manager = TypeId::of::<M>();
+        if stores_files_on_disk(manager) {
+            Ok(Self {
+                manager: None,
+                path: std::fs::canonicalize(&config.directory)?,
+            })
+        } else {
+            Ok(Self {
+                manager: Some(manager),
+                path: config.directory.to_path_buf(),
+            })
+        }
+    }
+}
+
+/// Returns true if the file manager with the type `manager` stores files on
+/// disk using the standard library.
+fn stores_files_on_disk(manager: TypeId) -> bool {
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if manager == TypeId::of::<crate::IoUringFileManager>() {
+        return true;
+    }
+    #[cfg(unix)]
+    if manager == TypeId::of::<crate::WriteThroughFileManager>() {
+        return true;
+    }
+    manager == TypeId::of::<StdFileManager>()
+}
+
+/// Records that a directory is in use until it is dropped.
+///
+/// Only uses by this process are known. Directories accessed through file
+/// managers that don't store files on disk are identified by the type of the
+/// file manager and their configured path, so separate instances of the same
+/// file manager type using the same path are treated as the same directory.
+#[derive(Debug)]
+pub(crate) struct DirectoryGuard {
+    directory: Directory,
+}
+
+impl DirectoryGuard {
+    /// Records that a log or follower is open in the directory of `config`,
+    /// which must exist. Returns [`Error::DirectoryInUse`] if its segments are
+    /// being upgraded.
+    pub(crate) fn open<M: FileManager>(config: &Configuration<M>) -> io::Result<Self> {
+        let directory = Directory::for_config(config)?;
+        let mut directories = DIRECTORIES.lock();
+        match directories.iter_mut().find(|(used, _)| used == &directory) {
+            Some((_, Usage::Open(count))) => *count += 1,
+            Some((_, Usage::Upgrading)) => return Err(Error::DirectoryInUse.into()),
+            None => directories.push((directory.clone(), Usage::Open(1))),
+        }
+        Ok(Self { directory })
+    }
+
+    /// Records that the segments in the directory of `config`, which must
+    /// exist, are being upgraded. Returns [`Error::DirectoryInUse`] if the
+    /// directory is already in use.
+    pub(crate) fn upgrade<M: FileManager>(config: &Configuration<M>) -> io::Result<Self> {
+        let directory = Directory::for_config(config)?;
+        let mut directories = DIRECTORIES.lock();
+        if directories.iter().any(|(used, _)| used == &directory) {
+            return Err(Error::DirectoryInUse.into());
+        }
+        directories.push((directory.clone(), Usage::Upgrading));
+        Ok(Self { directory })
+    }
+}
+
+impl Drop for DirectoryGuard {
+    fn drop(&mut self) {
+        let mut directories = DIRECTORIES.lock();
+        if let Some(index) = directories
+            .iter()
+            .position(|(used, _)| used == &self.directory)
+        {
+            match &mut directories[index].1 {
+                Usage::Open(count) if *count > 1 => *count -= 1,
+                _ => {
+                    directories.swap_remove(index);
+                }
+            }
+        }
+    }
+}
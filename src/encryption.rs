@@ -0,0 +1,233 @@
+use std::{fmt::Debug, io, sync::Arc};
+
+/// Encrypts and decrypts the data of each chunk written to a
+/// [`WriteAheadLog`](crate::WriteAheadLog).
+///
+/// A cipher can hold multiple keys, each identified by a `u32` key id. When a
+/// segment file is created or recycled, [`Cipher::current_key_id()`] is
+/// recorded in its header, and every chunk written to that segment is
+/// encrypted with that key. This allows keys to be rotated: once a new key is
+/// current, older keys only need to be retained until all segments written
+/// with them have been checkpointed.
+///
+/// Chunks are compressed before they are encrypted. The CRC stored with each
+/// chunk covers the encrypted bytes.
+pub trait Cipher: Send + Sync + Debug + 'static {
+    /// Returns the id of the key that newly created or recycled segments
+    /// should be encrypted with.
+    fn current_key_id(&self) -> u32;
+
+    /// Encrypts `plaintext` using the key identified by `key_id`, returning
+    /// the bytes to store.
+    ///
+    /// `context` identifies where the chunk is being stored. Implementations
+    /// should authenticate it alongside the data, which prevents stored chunks
+    /// from being moved to another location undetected.
+    fn encrypt(&self, key_id: u32, context: &ChunkContext, plaintext: &[u8])
+        -> io::Result<Vec<u8>>;
+
+    /// Decrypts `ciphertext` that was previously returned from
+    /// [`Cipher::encrypt()`] with the same `key_id` and `context`.
+    fn decrypt(
+        &self,
+        key_id: u32,
+        context: &ChunkContext,
+        ciphertext: &[u8],
+    ) -> io::Result<Vec<u8>>;
+}
+
+/// The location of a chunk being encrypted or decrypted by a [`Cipher`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ChunkContext {
+    /// The id of the segment the chunk is stored in.
+    pub segment_id: u64,
+    /// The offset of the chunk's header within the segment.
+    pub offset: u64,
+}
+
+impl ChunkContext {
+    /// Returns this context encoded as bytes, suitable for use as associated
+    /// data in an AEAD construction.
+    #[must_use]
+    pub fn to_bytes(&self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&self.segment_id.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.offset.to_le_bytes());
+        bytes
+    }
+}
+
+/// The cipher and key used to encrypt or decrypt a chunk.
+#[derive(Debug, Clone)]
+pub(crate) struct ChunkEncryption {
+    pub cipher: Arc<dyn Cipher>,
+    pub key_id: u32,
+    pub context: ChunkContext,
+}
+
+impl ChunkEncryption {
+    /// Returns the encryption of the chunk at `offset` in the segment with
+    /// `segment_id`, or `None` if either no cipher is configured or the
+    /// segment isn't encrypted.
+    pub fn for_chunk(
+        cipher: Option<&Arc<dyn Cipher>>,
+        key_id: Option<u32>,
+        segment_id: u64,
+        offset: u64,
+    ) -> Option<Self> {
+        match (cipher, key_id) {
+            (Some(cipher), Some(key_id)) => Some(Self {
+                cipher: cipher.clone(),
+                key_id,
+                context: ChunkContext { segment_id, offset },
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        self.cipher.encrypt(self.key_id, &self.context, plaintext)
+    }
+
+    pub fn decrypt(&self, ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+        self.cipher.decrypt(self.key_id, &self.context, ciphertext)
+    }
+}
+
+#[cfg(any(feature = "aes256-gcm", feature = "chacha20-poly1305"))]
+pub use self::aead_cipher::AeadCipher;
+
+/// A [`Cipher`] using AES-256-GCM. Requires the `aes256-gcm` feature.
+#[cfg(feature = "aes256-gcm")]
+pub type Aes256GcmCipher = AeadCipher<aes_gcm::Aes256Gcm>;
+
+/// A [`Cipher`] using ChaCha20-Poly1305. Requires the `chacha20-poly1305`
+/// feature.
+#[cfg(feature = "chacha20-poly1305")]
+pub type ChaCha20Poly1305Cipher = AeadCipher<chacha20poly1305::ChaCha20Poly1305>;
+
+#[cfg(any(feature = "aes256-gcm", feature = "chacha20-poly1305"))]
+mod aead_cipher {
+    use std::{
+        collections::HashMap,
+        fmt::{self, Debug},
+        io::{self, ErrorKind},
+    };
+
+    use aead::{
+        generic_array::{typenum::Unsigned, GenericArray},
+        Aead, AeadCore, Key, KeyInit, OsRng, Payload,
+    };
+
+    use super::{ChunkContext, Cipher};
+
+    /// A [`Cipher`] built on an AEAD algorithm from the `aead` ecosystem.
+    ///
+    /// Each chunk is encrypted with a randomly generated nonce, which is
+    /// stored in front of the ciphertext. The chunk's [`ChunkContext`] is
+    /// authenticated as associated data.
+    pub struct AeadCipher<C> {
+        keys: HashMap<u32, C>,
+        current_key_id: u32,
+    }
+
+    impl<C> AeadCipher<C>
+    where
+        C: Aead + AeadCore + KeyInit,
+    {
+        /// Returns a cipher that encrypts using `key`, identified by `key_id`.
+        #[must_use]
+        pub fn new(key_id: u32, key: &Key<C>) -> Self {
+            let mut keys = HashMap::new();
+            keys.insert(key_id, C::new(key));
+            Self {
+                keys,
+                current_key_id: key_id,
+            }
+        }
+
+        /// Adds a previously used `key`, identified by `key_id`, which is
+        /// used to decrypt segments written before the key was rotated.
+        /// Returns `self`.
+        #[must_use]
+        pub fn with_previous_key(mut self, key_id: u32, key: &Key<C>) -> Self {
+            self.keys.insert(key_id, C::new(key));
+            self
+        }
+
+        fn key(&self, key_id: u32) -> io::Result<&C> {
+            self.keys.get(&key_id).ok_or_else(|| {
+                io::Error::new(ErrorKind::NotFound, format!("unknown key id {key_id}"))
+            })
+        }
+    }
+
+    impl<C> Debug for AeadCipher<C> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let mut key_ids = self.keys.keys().collect::<Vec<_>>();
+            key_ids.sort_unstable();
+            f.debug_struct("AeadCipher")
+                .field("key_ids", &key_ids)
+                .field("current_key_id", &self.current_key_id)
+                .finish_non_exhaustive()
+        }
+    }
+
+    impl<C> Cipher for AeadCipher<C>
+    where
+        C: Aead + AeadCore + KeyInit + Send + Sync + 'static,
+    {
+        fn current_key_id(&self) -> u32 {
+            self.current_key_id
+        }
+
+        fn encrypt(
+            &self,
+            key_id: u32,
+            context: &ChunkContext,
+            plaintext: &[u8],
+        ) -> io::Result<Vec<u8>> {
+            let nonce = C::generate_nonce(&mut OsRng);
+            let ciphertext = self
+                .key(key_id)?
+                .encrypt(
+                    &nonce,
+                    Payload {
+                        msg: plaintext,
+                        aad: &context.to_bytes(),
+                    },
+                )
+                .map_err(|_| io::Error::new(ErrorKind::Other, "chunk encryption failed"))?;
+
+            let mut stored = Vec::with_capacity(nonce.len() + ciphertext.len());
+            stored.extend_from_slice(&nonce);
+            stored.extend_from_slice(&ciphertext);
+            Ok(stored)
+        }
+
+        fn decrypt(
+            &self,
+            key_id: u32,
+            context: &ChunkContext,
+            ciphertext: &[u8],
+        ) -> io::Result<Vec<u8>> {
+            let nonce_size = C::NonceSize::USIZE;
+            if ciphertext.len() < nonce_size {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "encrypted chunk is too short",
+                ));
+            }
+            let (nonce, ciphertext) = ciphertext.split_at(nonce_size);
+            self.key(key_id)?
+                .decrypt(
+                    GenericArray::from_slice(nonce),
+                    Payload {
+                        msg: ciphertext,
+                        aad: &context.to_bytes(),
+                    },
+                )
+                .map_err(|_| io::Error::new(ErrorKind::InvalidData, "chunk decryption failed"))
+        }
+    }
+}
@@ -1,12 +1,17 @@
 use crc32c::crc32c_append;
 use file_manager::FileManager;
 use parking_lot::MutexGuard;
-use std::io::{self, Read, Write};
+use std::{
+    io::{self, Read, Write},
+    time::Instant,
+};
 
 use crate::{
+    codec,
+    encryption::ChunkEncryption,
     log_file::{LogFile, LogFileWriter},
     to_io_result::ToIoResult,
-    WriteAheadLog, WriteResult,
+    Compression, Error, WriteAheadLog, WriteResult,
 };
 
 /// A writer for an entry in a [`WriteAheadLog`].
@@ -22,11 +27,18 @@ where
     log: &'a WriteAheadLog<M>,
     file: Option<LogFile<M::File>>,
     original_length: u64,
+    started_at: Instant,
 }
 
+/// The byte that begins each entry in a segment, followed by the entry's id
+/// as a little-endian `u64`.
 pub const NEW_ENTRY: u8 = 1;
 pub const CHUNK: u8 = 2;
 pub const END_OF_ENTRY: u8 = 3;
+pub const ENCODED_CHUNK: u8 = 4;
+/// Written after a segment's version info when the segment is encrypted,
+/// followed by the id of the key its chunks are encrypted with.
+pub const KEY_ID: u8 = 5;
 
 impl<'a, M> EntryWriter<'a, M>
 where
@@ -39,9 +51,7 @@ where
     ) -> io::Result<Self> {
         let mut writer = file.lock();
         let original_length = writer.position();
-
-        writer.write_all(&[NEW_ENTRY])?;
-        writer.write_all(&id.0.to_le_bytes())?;
+        write_entry_header(&mut writer, id)?;
         drop(writer);
 
         Ok(Self {
@@ -49,6 +59,7 @@ where
             log,
             file: Some(file),
             original_length,
+            started_at: Instant::now(),
         })
     }
 
@@ -64,9 +75,9 @@ where
     ) -> io::Result<u64> {
         let file = self.file.as_ref().expect("Already committed");
         let mut writer = file.lock();
-        writer.write_all(&[END_OF_ENTRY])?;
+        writer.end_entry()?;
         let new_length = writer.position();
-        writer.set_last_entry_id(Some(self.id));
+        writer.record_commit(self.id);
         callback(&mut writer)?;
         drop(writer);
         Ok(new_length)
@@ -76,11 +87,20 @@ where
     ///
     /// See `commit`.
     pub fn commit_and_checkpoint(mut self) -> io::Result<EntryId> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("commit", entry_id = self.id.0).entered();
         let new_length = self.commit_internal(|_file| Ok(()))?;
         let id = self.id;
         let file = self.file.take().expect("Already committed");
-        self.log
-            .reclaim(file, WriteResult::Entry { new_length }, true)?;
+        self.log.reclaim(
+            file,
+            WriteResult::Entry {
+                new_length,
+                durability: Durability::Fsync,
+            },
+            true,
+        )?;
+        self.record_committed();
         Ok(id)
     }
 
@@ -91,21 +111,51 @@ where
     /// write to the log. See [`WriteAheadLog::begin_entry()`] for more
     /// information.
     pub fn commit(self) -> io::Result<EntryId> {
-        self.commit_and(|_file| Ok(()))
+        self.commit_with(Durability::Fsync)
+    }
+
+    /// Commits this entry to the log. Once this call returns, the entry is as
+    /// durable as `durability` requires.
+    ///
+    /// An entry committed with [`Durability::Flush`] or
+    /// [`Durability::Buffered`] is synchronized to disk by the next commit
+    /// that uses [`Durability::Fsync`], by the background flusher configured
+    /// using [`Configuration::flush_interval`](crate::Configuration::flush_interval),
+    /// before it is read using [`WriteAheadLog::read_at()`] or checkpointed,
+    /// or when the log is shut down.
+    pub fn commit_with(self, durability: Durability) -> io::Result<EntryId> {
+        self.commit_and(durability, |_file| Ok(()))
     }
 
     pub(crate) fn commit_and<F: FnOnce(&mut LogFileWriter<M::File>) -> io::Result<()>>(
         mut self,
+        durability: Durability,
         callback: F,
     ) -> io::Result<EntryId> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("commit", entry_id = self.id.0).entered();
         let new_length = self.commit_internal(callback)?;
         let id = self.id;
         let file = self.file.take().expect("file already dropped");
-        self.log
-            .reclaim(file, WriteResult::Entry { new_length }, false)?;
+        self.log.reclaim(
+            file,
+            WriteResult::Entry {
+                new_length,
+                durability,
+            },
+            false,
+        )?;
+        self.record_committed();
         Ok(id)
     }
 
+    fn record_committed(&self) {
+        self.log
+            .data
+            .metrics
+            .record_entries(1, self.started_at.elapsed());
+    }
+
     /// Abandons this entry, preventing the entry from being recovered in the
     /// future. This is automatically done when dropped, but errors that occur
     /// during drop will panic.
@@ -139,7 +189,13 @@ where
     /// The writer returned already contains an internal buffer. This function
     /// can be used to write a complex payload without needing to first
     /// combine it in another buffer.
+    ///
+    /// If [`Configuration::compression`](crate::Configuration::compression)
+    /// or [`Configuration::cipher`](crate::Configuration::cipher) is set, the
+    /// chunk's data is buffered in memory and encoded when the chunk is
+    /// finished.
     pub fn begin_chunk(&mut self, length: u32) -> io::Result<ChunkWriter<'_, M::File>> {
+        let compression = self.log.data.config.compression;
         let mut file = self.file.as_ref().expect("already dropped").lock();
 
         let position = LogPosition {
@@ -147,8 +203,14 @@ where
             offset: file.position(),
         };
 
-        file.write_all(&[CHUNK])?;
-        file.write_all(&length.to_le_bytes())?;
+        let encryption = file.chunk_encryption();
+        let buffered = if compression == Compression::None && encryption.is_none() {
+            file.write_all(&[CHUNK])?;
+            file.write_all(&length.to_le_bytes())?;
+            None
+        } else {
+            Some(Vec::with_capacity(usize::try_from(length).to_io()?))
+        };
 
         Ok(ChunkWriter {
             file,
@@ -156,6 +218,9 @@ where
             length,
             bytes_remaining: length,
             crc32: 0,
+            compression,
+            encryption,
+            buffered,
             finished: false,
         })
     }
@@ -172,6 +237,49 @@ where
     }
 }
 
+pub(crate) fn write_entry_header<F: file_manager::File>(
+    file: &mut LogFileWriter<F>,
+    id: EntryId,
+) -> io::Result<()> {
+    file.begin_entry(id);
+    file.write_all(&[NEW_ENTRY])?;
+    file.write_all(&id.0.to_le_bytes())
+}
+
+/// Writes a complete entry containing `chunks` to `file`.
+pub(crate) fn write_entry<F, Chunk>(
+    file: &mut LogFileWriter<F>,
+    id: EntryId,
+    chunks: &[Chunk],
+    compression: Compression,
+) -> io::Result<CommittedEntry>
+where
+    F: file_manager::File,
+    Chunk: AsRef<[u8]>,
+{
+    write_entry_header(file, id)?;
+    let chunks = chunks
+        .iter()
+        .map(|chunk| {
+            let data = chunk.as_ref();
+            let position = LogPosition {
+                file_id: file.id(),
+                offset: file.position(),
+            };
+            let encryption = file.chunk_encryption();
+            let crc = codec::write_chunk(compression, encryption.as_ref(), data, &mut *file)?;
+            Ok(ChunkRecord {
+                position,
+                crc,
+                length: u32::try_from(data.len()).to_io()?,
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    file.end_entry()?;
+
+    Ok(CommittedEntry { id, chunks })
+}
+
 pub struct ChunkWriter<'a, F>
 where
     F: file_manager::File,
@@ -181,6 +289,10 @@ where
     length: u32,
     bytes_remaining: u32,
     crc32: u32,
+    compression: Compression,
+    encryption: Option<ChunkEncryption>,
+    /// The data written so far, if this chunk is encoded once finished.
+    buffered: Option<Vec<u8>>,
     finished: bool,
 }
 
@@ -201,13 +313,20 @@ where
         self.finished = true;
 
         if self.bytes_remaining != 0 {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "written length does not match expected length",
-            ));
+            return Err(Error::ChunkLengthMismatch.into());
         }
 
-        self.file.write_all(&self.crc32.to_le_bytes())
+        if let Some(data) = self.buffered.take() {
+            self.crc32 = codec::write_chunk(
+                self.compression,
+                self.encryption.as_ref(),
+                &data,
+                &mut *self.file,
+            )?;
+            Ok(())
+        } else {
+            self.file.write_all(&self.crc32.to_le_bytes())
+        }
     }
 }
 
@@ -232,7 +351,12 @@ where
             .len()
             .min(usize::try_from(self.bytes_remaining).to_io()?);
 
-        let bytes_written = self.file.write(&buf[..bytes_to_write])?;
+        let bytes_written = if let Some(data) = &mut self.buffered {
+            data.extend_from_slice(&buf[..bytes_to_write]);
+            bytes_to_write
+        } else {
+            self.file.write(&buf[..bytes_to_write])?
+        };
         if bytes_written > 0 {
             self.bytes_remaining -= u32::try_from(bytes_written).to_io()?;
             self.crc32 = crc32c_append(self.crc32, &buf[..bytes_written]);
@@ -257,6 +381,18 @@ impl LogPosition {
     /// [`LogPosition::serialize_to()`].
     pub const SERIALIZED_LENGTH: u8 = 16;
 
+    /// Returns the id of the segment containing this position.
+    #[must_use]
+    pub const fn file_id(&self) -> u64 {
+        self.file_id
+    }
+
+    /// Returns the offset of this position within its segment.
+    #[must_use]
+    pub const fn offset(&self) -> u64 {
+        self.offset
+    }
+
     /// Serializes this position to `destination`.
     ///
     /// This writes [`LogPosition::SERIALIZED_LENGTH`] bytes to `destination`.
@@ -303,12 +439,46 @@ fn log_position_serialization() {
 pub struct ChunkRecord {
     /// The position of the chunk.
     pub position: LogPosition,
-    /// The CRC calculated for the chunk.
+    /// The CRC calculated for the chunk. If the chunk was compressed or
+    /// encrypted, this is the CRC of the bytes stored.
     pub crc: u32,
-    /// The length of the data contained inside of the chunk.
+    /// The length of the data contained inside of the chunk, before any
+    /// compression or encryption was applied.
     pub length: u32,
 }
 
+/// An entry that was committed to a [`WriteAheadLog`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CommittedEntry {
+    /// The unique id of the entry.
+    pub id: EntryId,
+    /// The records of each chunk written in this entry, in the order they were
+    /// written.
+    pub chunks: Vec<ChunkRecord>,
+}
+
+/// How durable an entry must be before
+/// [`EntryWriter::commit_with()`] returns.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Durability {
+    /// The entry is synchronized to disk using `fsync`, which ensures it
+    /// survives a crash of the process or the operating system. This is what
+    /// [`EntryWriter::commit()`] uses.
+    Fsync,
+    /// The entry is written to the operating system, which ensures it
+    /// survives a crash of the process, but not of the operating system.
+    Flush,
+    /// The entry may remain in the log's buffer in memory. It can be lost if
+    /// the process crashes before it is synchronized.
+    Buffered,
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Self::Fsync
+    }
+}
+
 /// The unique id of an entry written to a [`WriteAheadLog`]. These IDs are
 /// ordered by the time the [`EntryWriter`] was created for the entry written with this id.
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Copy, Clone, Default, Hash)]
@@ -0,0 +1,167 @@
+use std::{
+    fmt::{self, Display},
+    io::{self, ErrorKind},
+    sync::Arc,
+};
+
+use crate::{EntryId, LogPosition};
+
+/// A failure specific to a [`WriteAheadLog`](crate::WriteAheadLog).
+///
+/// This crate's functions return [`io::Error`] so that failures of the
+/// underlying storage can be returned unchanged. When a failure originates in
+/// the log itself, the returned [`io::Error`] wraps one of these variants,
+/// which can be retrieved using [`Error::from_io_error()`]:
+///
+/// ```rust
+/// # fn example(wal: &okaywal::WriteAheadLog, entry_id: okaywal::EntryId) {
+/// use std::time::Duration;
+///
+/// if let Err(err) = wal.wait_checkpointed_for(&entry_id, Duration::from_secs(1)) {
+///     match okaywal::Error::from_io_error(&err) {
+///         Some(okaywal::Error::Timeout) => { /* try again later */ }
+///         _ => panic!("unexpected error: {err}"),
+///     }
+/// }
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum Error {
+    /// The position refers to a segment that is no longer part of the log,
+    /// because it has been checkpointed.
+    PositionCheckpointed {
+        /// The position that was requested.
+        position: LogPosition,
+    },
+    /// The entry has been checkpointed and can no longer be read from the
+    /// log.
+    EntryCheckpointed {
+        /// The id of the entry that was requested.
+        entry_id: EntryId,
+    },
+    /// The entry has not been committed to the log. Either its id has not
+    /// been used yet, it is still being written, or it was rolled back.
+    EntryNotFound {
+        /// The id of the entry that was requested.
+        entry_id: EntryId,
+    },
+    /// The disk usage exceeds
+    /// [`Configuration::max_disk_usage_percent`](crate::Configuration::max_disk_usage_percent).
+    StorageFull {
+        /// The number of bytes available on the disk.
+        available: u64,
+        /// The total number of bytes on the disk.
+        total: u64,
+    },
+    /// The CRC stored with a chunk did not match its data.
+    CrcMismatch {
+        /// The position of the chunk.
+        position: LogPosition,
+    },
+    /// The number of bytes written to a chunk did not match the length it was
+    /// started with.
+    ChunkLengthMismatch,
+    /// A segment's entries end at an unreadable position that is followed by
+    /// more complete entries, and the segment couldn't be recovered using the
+    /// configured [`RecoveryMode`](crate::RecoveryMode).
+    SegmentCorrupted {
+        /// The position where the segment's readable entries end.
+        position: LogPosition,
+    },
+    /// [`LogManager::checkpoint_to()`](crate::LogManager::checkpoint_to)
+    /// returned an error.
+    CheckpointerFailed(Arc<io::Error>),
+    /// [`Replicator::replicate()`](crate::Replicator::replicate) returned an
+    /// error, or a synchronized range couldn't be read to replicate it.
+    ReplicationFailed(Arc<io::Error>),
+    /// The operation did not complete before its timeout elapsed.
+    Timeout,
+    /// The log was shut down before the operation completed.
+    Closed,
+    /// A log's segments can't be upgraded using
+    /// [`Configuration::upgrade_format()`](crate::Configuration::upgrade_format)
+    /// while it is open, and it can't be opened while they are being upgraded.
+    DirectoryInUse,
+}
+
+impl Error {
+    /// Returns the [`Error`] wrapped by `err`, if `err` was returned by this
+    /// crate for a failure specific to the log.
+    #[must_use]
+    pub fn from_io_error(err: &io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+
+    /// Returns the [`ErrorKind`] used when this error is converted into an
+    /// [`io::Error`].
+    #[must_use]
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::PositionCheckpointed { .. }
+            | Self::EntryCheckpointed { .. }
+            | Self::EntryNotFound { .. } => ErrorKind::NotFound,
+            Self::StorageFull { .. } => ErrorKind::OutOfMemory,
+            Self::CrcMismatch { .. } | Self::SegmentCorrupted { .. } => ErrorKind::InvalidData,
+            Self::ChunkLengthMismatch | Self::DirectoryInUse => ErrorKind::Other,
+            Self::CheckpointerFailed(err) | Self::ReplicationFailed(err) => err.kind(),
+            Self::Timeout => ErrorKind::TimedOut,
+            Self::Closed => ErrorKind::BrokenPipe,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PositionCheckpointed { position } => write!(
+                f,
+                "position {}:{} has been checkpointed",
+                position.file_id, position.offset
+            ),
+            Self::EntryCheckpointed { entry_id } => {
+                write!(f, "entry {} has been checkpointed", entry_id.0)
+            }
+            Self::EntryNotFound { entry_id } => {
+                write!(f, "entry {} has not been committed", entry_id.0)
+            }
+            Self::StorageFull { available, total } => write!(
+                f,
+                "storage is full: {available} of {total} bytes are available"
+            ),
+            Self::CrcMismatch { position } => write!(
+                f,
+                "crc check failed for the chunk at {}:{}",
+                position.file_id, position.offset
+            ),
+            Self::ChunkLengthMismatch => {
+                f.write_str("written length does not match expected length")
+            }
+            Self::SegmentCorrupted { position } => write!(
+                f,
+                "segment is corrupted at {}:{}, and is followed by more entries",
+                position.file_id, position.offset
+            ),
+            Self::CheckpointerFailed(err) => write!(f, "checkpointer failed: {err}"),
+            Self::ReplicationFailed(err) => write!(f, "replication failed: {err}"),
+            Self::Timeout => f.write_str("operation timed out"),
+            Self::Closed => f.write_str("log has been shut down"),
+            Self::DirectoryInUse => f.write_str("log directory is in use"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::CheckpointerFailed(err) | Self::ReplicationFailed(err) => Some(&**err),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        Self::new(err.kind(), err)
+    }
+}
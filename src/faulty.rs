@@ -0,0 +1,506 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use file_manager::{memory::MemoryFileManager, File, FileManager, OpenOptions, PathId};
+use parking_lot::{Mutex, MutexGuard};
+
+use crate::to_io_result::ToIoResult;
+
+/// A [`FileManager`] that simulates crashes and failing operations, for
+/// testing that data survives them. Requires the `test-util` feature.
+///
+/// Every write and length change made through this manager since its file
+/// was last synchronized is recorded. [`FaultyFileManager::crash()`] undoes
+/// some or all of those changes, leaving the files as they could be found
+/// after the process or machine crashed. Errors can be injected into
+/// individual operations using [`FaultyFileManager::fail_after()`].
+///
+/// Renames and removals take effect immediately, and are not undone by a
+/// crash.
+#[derive(Debug, Clone)]
+pub struct FaultyFileManager<M = MemoryFileManager>
+where
+    M: FileManager,
+{
+    inner: M,
+    state: Arc<Mutex<FaultState>>,
+    generation: u64,
+}
+
+/// An operation that can be made to fail using
+/// [`FaultyFileManager::fail_after()`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum FileOperation {
+    /// Opening a file.
+    Open,
+    /// Reading from a file or listing a directory.
+    Read,
+    /// Writing to a file.
+    Write,
+    /// Changing a file's length.
+    SetLen,
+    /// [`File::sync_data()`].
+    SyncData,
+    /// [`File::sync_all()`].
+    SyncAll,
+    /// Synchronizing a directory using [`FileManager::sync_all()`].
+    SyncDirectory,
+    /// Renaming a file.
+    Rename,
+    /// Removing a file.
+    RemoveFile,
+}
+
+/// How [`FaultyFileManager::crash()`] treats the writes that were not
+/// synchronized.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Crash {
+    /// All changes made since each file was last synchronized are lost.
+    LoseUnsynced,
+    /// For each file, the changes made since it was last synchronized are
+    /// kept up to a randomly chosen change, which is torn: only a random
+    /// number of its leading bytes are kept. All later changes are lost.
+    /// `seed` makes the choices repeatable.
+    Torn {
+        /// The seed for choosing which changes are kept.
+        seed: u64,
+    },
+}
+
+#[derive(Debug, Default)]
+struct FaultState {
+    generation: u64,
+    next_file_id: u64,
+    /// Identifies each file, so that its changes follow it when it is renamed.
+    file_ids: HashMap<PathBuf, u64>,
+    unsynced: HashMap<u64, UnsyncedChanges>,
+    faults: HashMap<FileOperation, u32>,
+}
+
+#[derive(Debug)]
+struct UnsyncedChanges {
+    path: PathBuf,
+    changes: Vec<Change>,
+}
+
+impl FaultState {
+    /// Records an unsynchronized change to the file identified by `id`.
+    fn record(&mut self, id: u64, change: Change) {
+        // Changes to a file that has been removed can't be undone.
+        let path = if let Some(path) = self
+            .file_ids
+            .iter()
+            .find_map(|(path, file_id)| (*file_id == id).then(|| path.clone()))
+        {
+            path
+        } else {
+            return;
+        };
+        self.unsynced
+            .entry(id)
+            .or_insert_with(|| UnsyncedChanges {
+                path,
+                changes: Vec::new(),
+            })
+            .changes
+            .push(change);
+    }
+
+    fn file_id(&mut self, path: PathBuf) -> u64 {
+        let next_file_id = &mut self.next_file_id;
+        *self.file_ids.entry(path).or_insert_with(|| {
+            *next_file_id += 1;
+            *next_file_id
+        })
+    }
+
+    fn check(&mut self, generation: u64, operation: FileOperation) -> io::Result<()> {
+        if generation != self.generation {
+            return Err(io::Error::new(
+                ErrorKind::BrokenPipe,
+                "the file manager has crashed",
+            ));
+        }
+
+        match self.faults.get_mut(&operation) {
+            Some(0) => {
+                self.faults.remove(&operation);
+                Err(io::Error::new(
+                    ErrorKind::Other,
+                    format!("injected {operation:?} failure"),
+                ))
+            }
+            Some(remaining) => {
+                *remaining -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// A change made to a file that has not been synchronized, along with what is
+/// needed to undo it.
+#[derive(Debug)]
+enum Change {
+    Write {
+        offset: u64,
+        length: u64,
+        previous_bytes: Vec<u8>,
+        previous_length: u64,
+    },
+    SetLen {
+        previous_length: u64,
+        truncated_bytes: Vec<u8>,
+    },
+}
+
+impl Default for FaultyFileManager<MemoryFileManager> {
+    fn default() -> Self {
+        Self::new(MemoryFileManager::default())
+    }
+}
+
+impl<M> FaultyFileManager<M>
+where
+    M: FileManager,
+{
+    /// Returns a manager that stores its files using `inner`.
+    pub fn new(inner: M) -> Self {
+        Self {
+            inner,
+            state: Arc::default(),
+            generation: 0,
+        }
+    }
+
+    /// Makes the next call of `operation` fail after `successes` more calls
+    /// have succeeded.
+    pub fn fail_after(&self, operation: FileOperation, successes: u32) {
+        self.state.lock().faults.insert(operation, successes);
+    }
+
+    /// Returns the number of changes that have not been synchronized.
+    #[must_use]
+    pub fn unsynced_changes(&self) -> usize {
+        self.state
+            .lock()
+            .unsynced
+            .values()
+            .map(|file| file.changes.len())
+            .sum()
+    }
+
+    /// Locks the shared state, returning an error if `operation` should fail.
+    fn lock(&self, operation: FileOperation) -> io::Result<MutexGuard<'_, FaultState>> {
+        let mut state = self.state.lock();
+        state.check(self.generation, operation)?;
+        Ok(state)
+    }
+
+    /// Simulates a crash, undoing unsynchronized changes as described by
+    /// `crash`.
+    ///
+    /// Once crashed, every operation made using this manager, its clones, or
+    /// the files they opened fails. The returned manager can be used to
+    /// access the files as they are after the crash, such as to recover a
+    /// log.
+    pub fn crash(&self, crash: Crash) -> io::Result<Self> {
+        let mut state = self.state.lock();
+        state.generation += 1;
+        state.faults.clear();
+        let mut rng = match crash {
+            Crash::LoseUnsynced => None,
+            Crash::Torn { seed } => Some(XorShift(seed | 1)),
+        };
+
+        let mut unsynced = state
+            .unsynced
+            .drain()
+            .map(|(_, file)| file)
+            .collect::<Vec<_>>();
+        // Process the files in a consistent order so that seeds are
+        // repeatable.
+        unsynced.sort_by(|a, b| a.path.cmp(&b.path));
+        for UnsyncedChanges { path, mut changes } in unsynced {
+            let mut file = self.inner.open(
+                &PathId::from(path),
+                OpenOptions::new().read(true).write(true),
+            )?;
+            let (kept, torn_bytes) = if let Some(rng) = &mut rng {
+                let kept = usize::try_from(rng.below(changes.len() as u64 + 1)).to_io()?;
+                let torn_bytes = match changes.get(kept) {
+                    Some(Change::Write { length, .. }) => rng.below(*length),
+                    _ => 0,
+                };
+                (kept, torn_bytes)
+            } else {
+                (0, 0)
+            };
+
+            // Undo every change after the torn one, and then tear it.
+            let undone = changes.split_off(kept);
+            for (index, change) in undone.into_iter().enumerate().rev() {
+                undo(&mut file, change, if index == 0 { torn_bytes } else { 0 })?;
+            }
+        }
+
+        Ok(Self {
+            inner: self.inner.clone(),
+            state: self.state.clone(),
+            generation: state.generation,
+        })
+    }
+}
+
+/// Undoes `change`, keeping the first `kept_bytes` bytes of a write.
+fn undo<F: File>(file: &mut F, change: Change, kept_bytes: u64) -> io::Result<()> {
+    match change {
+        Change::Write {
+            offset,
+            length,
+            previous_bytes,
+            previous_length,
+        } => {
+            let kept_bytes = kept_bytes.min(length);
+            let restore_from = usize::try_from(kept_bytes)
+                .to_io()?
+                .min(previous_bytes.len());
+            file.seek(SeekFrom::Start(offset + restore_from as u64))?;
+            file.write_all(&previous_bytes[restore_from..])?;
+            let restored_length = previous_length.max(offset + kept_bytes);
+            if file.len()? > restored_length {
+                file.set_len(restored_length)?;
+            }
+        }
+        Change::SetLen {
+            previous_length,
+            truncated_bytes,
+        } => {
+            let current_length = file.len()?;
+            file.set_len(previous_length)?;
+            if !truncated_bytes.is_empty() {
+                file.seek(SeekFrom::Start(current_length))?;
+                file.write_all(&truncated_bytes)?;
+            }
+        }
+    }
+    file.flush()
+}
+
+impl<M> FileManager for FaultyFileManager<M>
+where
+    M: FileManager,
+{
+    type File = FaultyFile<M>;
+
+    fn list(&self, directory: &PathId) -> io::Result<Vec<PathId>> {
+        let _state = self.lock(FileOperation::Read)?;
+        self.inner.list(directory)
+    }
+
+    fn exists(&self, path: &PathId) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn create_dir_all(&self, path: &PathId) -> io::Result<()> {
+        let _state = self.lock(FileOperation::Open)?;
+        self.inner.create_dir_all(path)
+    }
+
+    fn open(&self, path: &PathId, options: OpenOptions) -> io::Result<Self::File> {
+        let mut state = self.lock(FileOperation::Open)?;
+        Ok(FaultyFile {
+            inner: self.inner.open(path, options)?,
+            manager: self.clone(),
+            id: state.file_id(path.to_path_buf()),
+        })
+    }
+
+    fn remove_file(&self, path: &PathId) -> io::Result<()> {
+        let mut state = self.lock(FileOperation::RemoveFile)?;
+        self.inner.remove_file(path)?;
+        if let Some(id) = state.file_ids.remove(&path.to_path_buf()) {
+            state.unsynced.remove(&id);
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &PathId, to: PathId) -> io::Result<()> {
+        let mut state = self.lock(FileOperation::Rename)?;
+        let to_path = to.to_path_buf();
+        self.inner.rename(from, to)?;
+        // The file being replaced no longer exists, so its changes can't be
+        // undone.
+        if to_path != from.to_path_buf() {
+            if let Some(replaced) = state.file_ids.remove(&to_path) {
+                state.unsynced.remove(&replaced);
+            }
+        }
+        if let Some(id) = state.file_ids.remove(&from.to_path_buf()) {
+            state.file_ids.insert(to_path.clone(), id);
+            if let Some(file) = state.unsynced.get_mut(&id) {
+                file.path = to_path;
+            }
+        }
+        Ok(())
+    }
+
+    fn sync_all(&self, path: &PathId) -> io::Result<()> {
+        let _state = self.lock(FileOperation::SyncDirectory)?;
+        self.inner.sync_all(path)
+    }
+
+    fn available_space_bytes(&self, path: &PathId) -> io::Result<u64> {
+        self.inner.available_space_bytes(path)
+    }
+
+    fn total_space_bytes(&self, path: &PathId) -> io::Result<u64> {
+        self.inner.total_space_bytes(path)
+    }
+}
+
+/// A file opened by a [`FaultyFileManager`].
+///
+/// Each operation holds the manager's lock while it is performed, which
+/// ensures a crash can't happen part way through an operation.
+#[derive(Debug)]
+pub struct FaultyFile<M>
+where
+    M: FileManager,
+{
+    inner: M::File,
+    manager: FaultyFileManager<M>,
+    id: u64,
+}
+
+impl<M> Read for FaultyFile<M>
+where
+    M: FileManager,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let _state = self.manager.lock(FileOperation::Read)?;
+        self.inner.read(buf)
+    }
+}
+
+impl<M> Write for FaultyFile<M>
+where
+    M: FileManager,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.manager.lock(FileOperation::Write)?;
+        let offset = self.inner.stream_position()?;
+        let previous_length = self.inner.len()?;
+        let mut previous_bytes = Vec::new();
+        (&mut self.inner)
+            .take(buf.len() as u64)
+            .read_to_end(&mut previous_bytes)?;
+        self.inner.seek(SeekFrom::Start(offset))?;
+
+        let written = self.inner.write(buf)?;
+        previous_bytes.truncate(written);
+        state.record(
+            self.id,
+            Change::Write {
+                offset,
+                length: written as u64,
+                previous_bytes,
+                previous_length,
+            },
+        );
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let _state = self.manager.lock(FileOperation::Write)?;
+        self.inner.flush()
+    }
+}
+
+impl<M> Seek for FaultyFile<M>
+where
+    M: FileManager,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl<M> File for FaultyFile<M>
+where
+    M: FileManager,
+{
+    type Manager = FaultyFileManager<M>;
+
+    fn len(&self) -> io::Result<u64> {
+        self.inner.len()
+    }
+
+    fn set_len(&self, new_length: u64) -> io::Result<()> {
+        let mut state = self.manager.lock(FileOperation::SetLen)?;
+        let previous_length = self.inner.len()?;
+        let truncated_bytes = if new_length < previous_length {
+            let mut reader = self.inner.try_clone()?;
+            reader.seek(SeekFrom::Start(new_length))?;
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes)?;
+            bytes
+        } else {
+            Vec::new()
+        };
+        self.inner.set_len(new_length)?;
+        state.record(
+            self.id,
+            Change::SetLen {
+                previous_length,
+                truncated_bytes,
+            },
+        );
+        Ok(())
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        let _state = self.manager.lock(FileOperation::Open)?;
+        Ok(Self {
+            inner: self.inner.try_clone()?,
+            manager: self.manager.clone(),
+            id: self.id,
+        })
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        let mut state = self.manager.lock(FileOperation::SyncAll)?;
+        self.inner.sync_all()?;
+        state.unsynced.remove(&self.id);
+        Ok(())
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        let mut state = self.manager.lock(FileOperation::SyncData)?;
+        self.inner.sync_data()?;
+        state.unsynced.remove(&self.id);
+        Ok(())
+    }
+}
+
+/// A small, deterministic random number generator, used to choose which
+/// changes survive a [`Crash::Torn`].
+struct XorShift(u64);
+
+impl XorShift {
+    fn below(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        if bound == 0 {
+            0
+        } else {
+            self.0 % bound
+        }
+    }
+}
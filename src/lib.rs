@@ -16,9 +16,12 @@
 use std::{
     collections::{HashMap, VecDeque},
     ffi::OsStr,
-    io::{self, BufReader, Error, ErrorKind, Read, Seek, SeekFrom},
+    io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom},
     path::Path,
-    sync::{Arc, Weak},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
     thread::JoinHandle,
     time::{Duration, Instant},
 };
@@ -27,21 +30,77 @@ use file_manager::{fs::StdFileManager, FileManager, OpenOptions, PathId};
 use log::{debug, error, info, warn};
 use parking_lot::{Condvar, Mutex, MutexGuard};
 
+#[cfg(feature = "async")]
+pub use crate::asynchronous::{AsyncEntryWriter, AsyncWriteAheadLog};
+#[cfg(any(feature = "aes256-gcm", feature = "chacha20-poly1305"))]
+pub use crate::encryption::AeadCipher;
+#[cfg(feature = "aes256-gcm")]
+pub use crate::encryption::Aes256GcmCipher;
+#[cfg(feature = "chacha20-poly1305")]
+pub use crate::encryption::ChaCha20Poly1305Cipher;
+#[cfg(feature = "test-util")]
+pub use crate::faulty::{Crash, FaultyFile, FaultyFileManager, FileOperation};
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub use crate::uring::{IoUringFile, IoUringFileManager};
+#[cfg(unix)]
+pub use crate::write_through::{WriteThrough, WriteThroughFile, WriteThroughFileManager};
 pub use crate::{
-    config::Configuration,
-    entry::{ChunkRecord, EntryId, EntryWriter, LogPosition},
-    log_file::{Entry, EntryChunk, ReadChunkResult, RecoveredSegment, SegmentReader},
-    manager::{LogManager, LogVoid, Recovery},
+    archive::{ArchiveDirectory, Archived, Archiver, CheckpointedSegment},
+    codec::Compression,
+    config::{CheckpointRetry, Configuration, GroupCommitWindow},
+    encryption::{ChunkContext, Cipher},
+    entry::{
+        ChunkRecord, CommittedEntry, Durability, EntryId, EntryWriter, LogPosition, NEW_ENTRY,
+    },
+    error::Error,
+    log_file::{
+        Entry, EntryChunk, ReadChunkResult, RecoveredSegment, SegmentReader, FORMAT_VERSION,
+    },
+    manager::{LogManager, LogVoid, Recovery, SegmentCheckpoint},
+    recovery::{RecoveryMode, RecoveryReport, SegmentRecovery},
+    replication::{Follower, Replicator, SegmentRange},
+    staged::{StagedChunkWriter, StagedEntryWriter},
+    stats::{SegmentStats, Stats},
+    subscription::{SubscribedEntry, Subscription},
+};
+use crate::{
+    codec::DecodedChunk,
+    directory::DirectoryGuard,
+    encryption::ChunkEncryption,
+    entry::ENCODED_CHUNK,
+    log_file::{read_header, EntryIndex, LogFile, LogFileWriter},
+    replication::ReplicationQueue,
+    staged::StagedChunks,
+    stats::Metrics,
+    to_io_result::ToIoResult,
 };
-use crate::{log_file::LogFile, to_io_result::ToIoResult};
 pub use file_manager;
 
+mod archive;
+#[cfg(feature = "async")]
+mod asynchronous;
 mod buffered;
+mod codec;
 mod config;
+mod directory;
+mod encryption;
 mod entry;
+mod error;
+#[cfg(any(test, feature = "test-util"))]
+mod faulty;
 mod log_file;
 mod manager;
+mod recovery;
+mod replication;
+mod staged;
+mod stats;
+mod subscription;
 mod to_io_result;
+mod upgrade;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+#[cfg(unix)]
+mod write_through;
 
 /// A [Write-Ahead Log][wal] that provides atomic and durable writes.
 ///
@@ -58,6 +117,10 @@ mod to_io_result;
 /// allows good multi-threaded performance in spite of only using a single
 /// active file.
 ///
+/// Entries that take a long time to produce can be written using
+/// [`WriteAheadLog::begin_staged_entry()`], which only acquires the lock to
+/// the active log segment while the completed entry is copied into it.
+///
 /// [wal]: https://en.wikipedia.org/wiki/Write-ahead_logging
 #[derive(Debug, Clone)]
 pub struct WriteAheadLog<M = StdFileManager>
@@ -78,10 +141,17 @@ where
     dirfsync_sync: Condvar,
     config: Configuration<M>,
     checkpoint_sender: flume::Sender<CheckpointCommand<M::File>>,
-    checkpoint_thread: Mutex<Option<JoinHandle<io::Result<()>>>>,
+    checkpoint_receiver: flume::Receiver<CheckpointCommand<M::File>>,
+    checkpoint_thread: Mutex<Option<CheckpointThread>>,
     checkpoint_sync: Condvar,
+    flush_stop: flume::Sender<()>,
+    flush_thread: Mutex<Option<JoinHandle<()>>>,
     readers: Mutex<HashMap<u64, usize>>,
     readers_sync: Condvar,
+    metrics: Metrics,
+    #[cfg(feature = "async")]
+    async_worker: Mutex<Option<flume::Sender<asynchronous::Task<M>>>>,
+    _directory: DirectoryGuard,
 }
 
 impl WriteAheadLog<StdFileManager> {
@@ -101,76 +171,26 @@ where
     fn open<Manager: LogManager<M>>(
         config: Configuration<M>,
         mut manager: Manager,
-    ) -> io::Result<Self> {
+    ) -> io::Result<(Self, RecoveryReport)> {
         info!("Opening WAL with config: {:?}", config);
         if !config.file_manager.exists(&config.directory) {
             config.file_manager.create_dir_all(&config.directory)?;
         }
-
-        // Find all files that match this pattern: "wal-[0-9]+(-cp)?"
-        let mut discovered_files = Vec::new();
-        for file in config.file_manager.list(&config.directory)? {
-            if let Some(file_name) = file.file_name().and_then(OsStr::to_str) {
-                let mut parts = file_name.split('-');
-                let prefix = parts.next();
-                let entry_id = parts.next().and_then(|ts| ts.parse::<u64>().ok());
-                let suffix = parts.next();
-                match (prefix, entry_id, suffix) {
-                    (Some(prefix), Some(entry_id), suffix) if prefix == "wal" => {
-                        let has_checkpointed = suffix == Some("cp");
-                        discovered_files.push((entry_id, file, has_checkpointed));
-                    }
-                    _ => {}
-                }
-            }
-        }
-
-        // Sort by the timestamp
-        discovered_files.sort_by(|a, b| a.0.cmp(&b.0));
-
-        let mut files = Files::default();
-        let mut files_to_checkpoint = Vec::new();
-        for (entry_id, path, has_checkpointed) in discovered_files {
-            // We can safely assume that the entry id prior to the one that
-            // labels the file have been used.
-            files.last_entry_id = EntryId(entry_id - 1);
-            if has_checkpointed {
-                let file = LogFile::write(entry_id, path, 0, None, &config)?;
-                files.all.insert(entry_id, file.clone());
-                files.inactive.push_back(file);
-            } else {
-                let mut reader = SegmentReader::new(&path, entry_id, &config.file_manager)?;
-                match manager.should_recover_segment(&reader.header)? {
-                    Recovery::Recover => {
-                        while let Some(mut entry) = reader.read_entry()? {
-                            manager.recover(&mut entry)?;
-                            while let Some(chunk) = match entry.read_chunk()? {
-                                ReadChunkResult::Chunk(chunk) => Some(chunk),
-                                ReadChunkResult::EndOfEntry | ReadChunkResult::AbortedEntry => None,
-                            } {
-                                chunk.skip_remaining_bytes()?;
-                            }
-                            files.last_entry_id = entry.id();
-                        }
-
-                        let file = LogFile::write(
-                            entry_id,
-                            path,
-                            reader.valid_until,
-                            reader.last_entry_id,
-                            &config,
-                        )?;
-                        files.all.insert(entry_id, file.clone());
-                        files_to_checkpoint.push(file);
-                    }
-                    Recovery::Abandon => {
-                        let file = LogFile::write(entry_id, path, 0, None, &config)?;
-                        files.all.insert(entry_id, file.clone());
-                        files.inactive.push_back(file);
-                    }
-                }
-            }
-        }
+        let directory = DirectoryGuard::open(&config)?;
+        upgrade::remove_abandoned_files(&config)?;
+
+        let mut files = Files::<M::File> {
+            replication: config
+                .replicator
+                .clone()
+                .map(ReplicationQueue::spawn)
+                .transpose()?
+                .map(Arc::new),
+            ..Files::default()
+        };
+        let mut report = RecoveryReport::default();
+        let mut files_to_checkpoint =
+            Self::recover_segments(&config, &mut manager, &mut files, &mut report)?;
 
         // Trim inactive files to the size allowed in configuration.
         if files.inactive.len() > config.max_inactive_files as usize {
@@ -182,22 +202,37 @@ where
                 .collect();
 
             for file in range_to_delete {
-                config
-                    .file_manager
-                    .remove_file(&file.path())
-                    .unwrap_or_else(|_| warn!("Couldn't remove file: {:?}", file));
+                let path = file.path();
+                match config.file_manager.remove_file(&path) {
+                    Ok(()) => report.deleted_files.push(path),
+                    Err(_) => warn!("Couldn't remove file: {:?}", file),
+                }
             }
             files.inactive.truncate(config.max_inactive_files as usize);
         }
 
-        // If we recovered a file that wasn't checkpointed, activate it.
-        if let Some(latest_file) = files_to_checkpoint.pop() {
-            files.active = Some(latest_file);
-        } else {
-            files.activate_new_file(&config)?;
+        // If we recovered a file that wasn't checkpointed, activate it. A file
+        // written before a cipher was configured is left unencrypted, and a file
+        // written in a different format than the configured one is left in its
+        // format. In either case, a new file is activated instead, unless the
+        // file has no entries, in which case it's rewritten.
+        match files_to_checkpoint.pop() {
+            Some(latest_file) if latest_file.lock().is_written_as_configured(&config) => {
+                files.active = Some(latest_file);
+            }
+            // A new file would be given the same id as a file without entries.
+            Some(latest_file) if latest_file.lock().last_entry_id().is_none() => {
+                latest_file.lock().revert_to(0)?;
+                files.active = Some(latest_file);
+            }
+            latest_file => {
+                files_to_checkpoint.extend(latest_file);
+                files.activate_new_file(&config)?;
+            }
         }
 
         let (checkpoint_sender, checkpoint_receiver) = flume::unbounded();
+        let (flush_stop, flush_stop_receiver) = flume::bounded(1);
         let wal = Self {
             data: Arc::new(Data {
                 files: Mutex::new(files),
@@ -207,30 +242,223 @@ where
                 config,
                 checkpoint_sync: Condvar::new(),
                 checkpoint_sender,
+                checkpoint_receiver,
                 checkpoint_thread: Mutex::new(None),
+                flush_stop,
+                flush_thread: Mutex::new(None),
                 readers: Mutex::default(),
                 readers_sync: Condvar::new(),
+                metrics: Metrics::default(),
+                #[cfg(feature = "async")]
+                async_worker: Mutex::new(None),
+                _directory: directory,
             }),
         };
 
         for file_to_checkpoint in files_to_checkpoint {
+            file_to_checkpoint.seal();
             wal.data
                 .checkpoint_sender
                 .send(CheckpointCommand::Checkpoint(file_to_checkpoint))
                 .to_io()?;
         }
 
-        let weak_wal = Arc::downgrade(&wal.data);
         let mut checkpoint_thread = wal.data.checkpoint_thread.lock();
-        *checkpoint_thread = Some(
-            std::thread::Builder::new()
-                .name(String::from("okaywal-cp"))
-                .spawn(move || Self::checkpoint_thread(&weak_wal, &checkpoint_receiver))
-                .expect("failed to spawn checkpointer thread"),
-        );
+        *checkpoint_thread = Some(wal.spawn_checkpoint_thread(Vec::new()));
         drop(checkpoint_thread);
 
-        Ok(wal)
+        if let Some(interval) = wal.data.config.flush_interval {
+            *wal.data.flush_thread.lock() =
+                Some(wal.spawn_flush_thread(flush_stop_receiver, interval));
+        }
+
+        if !report.is_clean() {
+            warn!("Recovered WAL discarded data: {:?}", report);
+        }
+
+        Ok((wal, report))
+    }
+
+    /// Recovers the segments in the log's directory, adding them to `files`.
+    /// Returns the recovered segments that haven't been checkpointed, ordered
+    /// by their ids.
+    fn recover_segments<Manager: LogManager<M>>(
+        config: &Configuration<M>,
+        manager: &mut Manager,
+        files: &mut Files<M::File>,
+        report: &mut RecoveryReport,
+    ) -> io::Result<Vec<LogFile<M::File>>> {
+        let mut files_to_checkpoint = Vec::new();
+        for SegmentFile {
+            id: entry_id,
+            path,
+            checkpointed: has_checkpointed,
+        } in list_segments(&config.file_manager, &config.directory)?
+        {
+            // We can safely assume that the entry id prior to the one that
+            // labels the file have been used.
+            files.last_entry_id = EntryId(entry_id - 1);
+            if has_checkpointed {
+                let file =
+                    LogFile::write(entry_id, path, 0, None, files.replication.as_ref(), config)?;
+                file.mark_checkpointed();
+                files.all.insert(entry_id, file.clone());
+                files.inactive.push_back(file);
+                continue;
+            }
+
+            let reader = match SegmentReader::open(&path, entry_id, config) {
+                Ok(reader) => reader,
+                Err(_) if log_file::is_uninitialized(&path, &config.file_manager)? => {
+                    let file = LogFile::write(
+                        entry_id,
+                        path,
+                        0,
+                        None,
+                        files.replication.as_ref(),
+                        config,
+                    )?;
+                    file.mark_checkpointed();
+                    files.all.insert(entry_id, file.clone());
+                    // This is the newest segment, so it's reused first.
+                    // Otherwise, the next segment activated would be renamed
+                    // over it.
+                    files.inactive.push_front(file);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            match manager.should_recover_segment(&reader.header)? {
+                Recovery::Recover => {
+                    let file = Self::recover_segment(reader, path, config, manager, files, report)?;
+                    files_to_checkpoint.push(file);
+                }
+                Recovery::Abandon => {
+                    report.abandoned_segments.push(entry_id);
+                    let file = LogFile::write(
+                        entry_id,
+                        path,
+                        0,
+                        None,
+                        files.replication.as_ref(),
+                        config,
+                    )?;
+                    file.mark_checkpointed();
+                    files.all.insert(entry_id, file.clone());
+                    files.inactive.push_back(file);
+                }
+            }
+        }
+        Ok(files_to_checkpoint)
+    }
+
+    /// Passes the entries of the segment at `path` to `manager`, recording the
+    /// outcome in `report`. Returns the segment, which new entries are written
+    /// to after its last valid entry.
+    fn recover_segment<Manager: LogManager<M>>(
+        mut reader: SegmentReader<M::File>,
+        path: PathId,
+        config: &Configuration<M>,
+        manager: &mut Manager,
+        files: &mut Files<M::File>,
+        report: &mut RecoveryReport,
+    ) -> io::Result<LogFile<M::File>> {
+        let entry_id = reader.file_id;
+        let mut entry_index = EntryIndex::default();
+        let mut entries_read = 0;
+        let mut corrupted_bytes = 0;
+        let mut salvaged_entries = 0;
+        loop {
+            recover_entries(&mut reader, config.verify_recovered_chunks, |entry| {
+                entry_index.record(entry.id(), entry.reader.valid_until);
+                manager.recover(entry)?;
+                files.last_entry_id = entry.id();
+                entries_read += 1;
+                Ok(true)
+            })?;
+            if config.recovery_mode == RecoveryMode::Tolerant {
+                break;
+            }
+            match recovery::skip_corruption(&mut reader, &path, config)? {
+                Some(salvaged) => {
+                    corrupted_bytes += salvaged.corrupted_bytes;
+                    salvaged_entries += salvaged.entries;
+                }
+                None => break,
+            }
+        }
+        entry_index.truncate(reader.valid_until);
+        report.segments.push(SegmentRecovery {
+            id: entry_id,
+            path: path.clone(),
+            // Unverified entries are passed to the manager even if they are
+            // aborted.
+            entries_recovered: if config.verify_recovered_chunks {
+                entries_read
+            } else {
+                entries_read - reader.aborted_entries
+            },
+            aborted_entries: reader.aborted_entries,
+            truncated_bytes: reader.truncated_bytes,
+            crc_failures: reader.crc_failures,
+            corrupted_bytes,
+            salvaged_entries,
+        });
+
+        let file = LogFile::write(
+            entry_id,
+            path,
+            reader.valid_until,
+            reader.last_entry_id,
+            files.replication.as_ref(),
+            config,
+        )?;
+        file.lock().set_entry_index(entry_index);
+        files.all.insert(entry_id, file.clone());
+        Ok(file)
+    }
+
+    fn spawn_checkpoint_thread(&self, retry_files: Vec<LogFile<M::File>>) -> CheckpointThread {
+        let weak_wal = Arc::downgrade(&self.data);
+        let checkpoint_receiver = self.data.checkpoint_receiver.clone();
+        let finished = Arc::new(AtomicBool::new(false));
+        let exited = SetOnDrop(finished.clone());
+        let handle = std::thread::Builder::new()
+            .name(String::from("okaywal-cp"))
+            .spawn(move || {
+                let _exited = exited;
+                Self::checkpoint_thread(&weak_wal, &checkpoint_receiver, retry_files)
+            })
+            .expect("failed to spawn checkpointer thread");
+        CheckpointThread { handle, finished }
+    }
+
+    fn spawn_flush_thread(
+        &self,
+        stop_receiver: flume::Receiver<()>,
+        interval: Duration,
+    ) -> JoinHandle<()> {
+        let weak_wal = Arc::downgrade(&self.data);
+        std::thread::Builder::new()
+            .name(String::from("okaywal-flush"))
+            .spawn(move || Self::flush_thread(&weak_wal, &stop_receiver, interval))
+            .expect("failed to spawn flush thread")
+    }
+
+    /// Opens a follower of another log, storing its segments in the directory
+    /// specified by `config`.
+    ///
+    /// The follower receives segment data from the primary log through
+    /// [`Replicator::replicate()`], and each entry is passed to
+    /// [`LogManager::recover()`] once it has been fully received. Any segments
+    /// already present in the directory are recovered first. Use
+    /// [`Follower::promote()`] to turn the follower into a writable
+    /// [`WriteAheadLog`].
+    pub fn open_follower<Manager: LogManager<M>>(
+        config: Configuration<M>,
+        manager: Manager,
+    ) -> io::Result<Follower<M>> {
+        Follower::open(config, manager)
     }
 
     /// Begins writing an entry to this log.
@@ -242,21 +470,181 @@ where
     /// This call will acquire an exclusive lock to the active file or block
     /// until it can be acquired.
     pub fn begin_entry(&self) -> io::Result<EntryWriter<'_, M>> {
+        let (file, entry_id) = self.acquire_active_file(1)?;
+
+        EntryWriter::new(self, entry_id, file)
+    }
+
+    /// Appends a batch of entries to this log, returning the id and chunk
+    /// records of each entry in the order they were provided.
+    ///
+    /// Each item of `entries` is the list of chunks that make up one entry. A
+    /// contiguous range of entry ids is allocated for the batch, all entries are
+    /// written while holding the lock to the active file, and the batch is
+    /// synchronized to disk with a single call. Once this call returns, all
+    /// entries in the batch have been synchronized to disk.
+    ///
+    /// The batch is not atomic. Each entry is recovered on its own, so a crash
+    /// before this call returns may recover any prefix of the batch's entries.
+    /// If an error occurs while writing, the batch is rolled back and none of
+    /// its entries will be recovered.
+    pub fn append_batch<Entries, Chunks, Chunk>(
+        &self,
+        entries: Entries,
+    ) -> io::Result<Vec<CommittedEntry>>
+    where
+        Entries: IntoIterator<Item = Chunks>,
+        Chunks: IntoIterator<Item = Chunk>,
+        Chunk: AsRef<[u8]>,
+    {
+        let entries = entries
+            .into_iter()
+            .map(|chunks| chunks.into_iter().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        if entries.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let compression = self.data.config.compression;
+        self.append_entries(
+            u64::try_from(entries.len()).to_io()?,
+            false,
+            |writer, first_entry_id| {
+                entries
+                    .iter()
+                    .zip(first_entry_id.0..)
+                    .map(|(chunks, id)| {
+                        entry::write_entry(writer, EntryId(id), chunks, compression)
+                    })
+                    .collect()
+            },
+        )
+    }
+
+    /// Begins writing a staged entry to this log.
+    ///
+    /// Unlike [`WriteAheadLog::begin_entry()`], this function does not acquire
+    /// a lock to the active file. Chunks written to the returned writer are
+    /// staged in a buffer owned by the writer, and the active file is only
+    /// locked during [`StagedEntryWriter::commit()`] while the completed entry
+    /// is copied into it. This allows any number of staged entries to be
+    /// written concurrently, and prevents short entries from waiting on long
+    /// ones.
+    ///
+    /// Because the entry is not placed in the log until it is committed, its
+    /// entry id and the positions of its chunks are assigned during commit.
+    pub fn begin_staged_entry(&self) -> StagedEntryWriter<'_, M> {
+        StagedEntryWriter::new(self)
+    }
+
+    fn commit_staged(
+        &self,
+        chunks: &StagedChunks,
+        force_checkpoint: bool,
+    ) -> io::Result<CommittedEntry> {
+        self.append_entries(1, force_checkpoint, |writer, id| {
+            chunks.write_to(writer, id)
+        })
+    }
+
+    /// Writes `entry_count` complete entries to the active file using `write`,
+    /// and synchronizes them to disk.
+    ///
+    /// `write` is provided the first of the entry ids allocated for the
+    /// entries. If it returns an error, all bytes it wrote are reverted.
+    fn append_entries<T, W>(
+        &self,
+        entry_count: u64,
+        force_checkpoint: bool,
+        write: W,
+    ) -> io::Result<T>
+    where
+        W: FnOnce(&mut LogFileWriter<M::File>, EntryId) -> io::Result<T>,
+    {
+        self.try_append_entries(entry_count, force_checkpoint, write)?
+    }
+
+    /// Writes entries like [`Self::append_entries()`], returning `Ok(Err(_))`
+    /// if `write` failed and the bytes it wrote have been reverted. Any other
+    /// error is returned as `Err(_)`, and the entries may have been written.
+    fn try_append_entries<T, W>(
+        &self,
+        entry_count: u64,
+        force_checkpoint: bool,
+        write: W,
+    ) -> io::Result<io::Result<T>>
+    where
+        W: FnOnce(&mut LogFileWriter<M::File>, EntryId) -> io::Result<T>,
+    {
+        let (file, first_entry_id) = self.acquire_active_file(entry_count)?;
+        let started_at = Instant::now();
+        #[cfg(feature = "tracing")]
+        let _span =
+            tracing::debug_span!("commit", entry_id = first_entry_id.0, entry_count).entered();
+        let mut writer = file.lock();
+        let original_length = writer.position();
+        match write(&mut writer, first_entry_id) {
+            Ok(result) => {
+                let new_length = writer.position();
+                writer.record_commit(EntryId(first_entry_id.0 + entry_count - 1));
+                drop(writer);
+                self.reclaim(
+                    file,
+                    WriteResult::Entry {
+                        new_length,
+                        durability: Durability::Fsync,
+                    },
+                    force_checkpoint,
+                )?;
+                self.data
+                    .metrics
+                    .record_entries(entry_count, started_at.elapsed());
+                Ok(Ok(result))
+            }
+            Err(err) => {
+                writer.revert_to(original_length)?;
+                drop(writer);
+                self.reclaim(file, WriteResult::RolledBack, false)?;
+                Ok(Err(err))
+            }
+        }
+    }
+
+    /// Returns a subscription to all committed entries in this log, starting
+    /// with the first entry whose id is greater than or equal to `from`.
+    ///
+    /// Entries are returned in the order of their ids once they have been
+    /// committed and synchronized to disk. When all currently committed
+    /// entries have been returned, iterating the subscription blocks until more
+    /// entries are committed.
+    ///
+    /// Entries can only be returned until they are checkpointed. If `from` or
+    /// any entry the subscription has not yet returned has been checkpointed,
+    /// an error is returned. See [`Subscription`] for more information.
+    pub fn subscribe(&self, from: EntryId) -> io::Result<Subscription<'_, M>> {
+        Subscription::new(self, from)
+    }
+
+    /// Takes exclusive ownership of the active file, blocking until it is
+    /// available, and allocates `entry_count` sequential entry ids. The first
+    /// allocated id is returned.
+    fn acquire_active_file(&self, entry_count: u64) -> io::Result<(LogFile<M::File>, EntryId)> {
         // Check if we're below the available space limit, otherwise refuse to allow
         // to begin the entry. This doesn't mean we couldn't actually write the entry
         // since the WAL files are pre-allocated, but it could mean that the
         // checkpointing thread either failed or would fail for lack of space, so it's
         // better to refuse transactions to gitve an opportunity to delete files.
         if !is_enough_space_available(&self.data.config)? {
-            error!("Not enough space available to append to WAL! Available space (bytes): {:?}. Total space (bytes): {:?}",
-                   available_space_bytes(&self.data.config)?, total_space_bytes(&self.data.config)?);
-            return Err(io::Error::new(
-                io::ErrorKind::OutOfMemory,
-                "Storage is full",
-            ));
+            let available = available_space_bytes(&self.data.config)?;
+            let total = total_space_bytes(&self.data.config)?;
+            error!("Not enough space available to append to WAL! Available space (bytes): {available:?}. Total space (bytes): {total:?}");
+            return Err(Error::StorageFull { available, total }.into());
         }
 
         let mut files = self.data.files.lock();
+        if let Some(error) = &files.checkpointer_error {
+            return Err(error.clone().into());
+        }
         let file = loop {
             if let Some(file) = files.active.take() {
                 break file;
@@ -265,14 +653,17 @@ where
             // Wait for a free file
             self.data.active_sync.wait(&mut files);
         };
-        files.last_entry_id.0 += 1;
-        let entry_id = files.last_entry_id;
+        let first_entry_id = EntryId(files.last_entry_id.0 + 1);
+        files.last_entry_id.0 += entry_count;
         drop(files);
 
-        EntryWriter::new(self, entry_id, file)
+        Ok((file, first_entry_id))
     }
 
     /// Waits, until timeout, for `entry_id` to be checkpointed.
+    ///
+    /// If the checkpointing thread has stopped because of an error, the error
+    /// is returned wrapped in [`Error::CheckpointerFailed`].
     pub fn wait_checkpointed_for(&self, entry_id: &EntryId, timeout: Duration) -> io::Result<()> {
         let time_deadline = Instant::now()
             .checked_add(timeout)
@@ -287,16 +678,15 @@ where
                     >= entry_id.0
             {
                 return Ok(());
+            } else if let Some(error) = &files.checkpointer_error {
+                return Err(error.clone().into());
             }
             let result = self
                 .data
                 .checkpoint_sync
                 .wait_until(&mut files, time_deadline);
             if result.timed_out() {
-                return Err(Error::new(
-                    ErrorKind::TimedOut,
-                    "Entry not checkpointed during timeout.",
-                ));
+                return Err(Error::Timeout.into());
             }
         }
     }
@@ -311,7 +701,67 @@ where
     #[must_use]
     pub fn is_checkpoint_thread_running(&self) -> bool {
         let thread_handle = self.data.checkpoint_thread.lock();
-        thread_handle.is_some() && !thread_handle.as_ref().unwrap().is_finished()
+        thread_handle.is_some()
+            && !thread_handle.as_ref().unwrap().is_finished()
+            && self.data.files.lock().checkpointer_error.is_none()
+    }
+
+    /// Returns a snapshot of this log's activity since it was opened.
+    #[must_use]
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            pending_checkpoints: self.pending_checkpoints(),
+            ..Stats::default()
+        };
+        self.data.metrics.fill(&mut stats);
+
+        let files = self.data.files.lock();
+        let segment_files = files.all.values().cloned().collect::<Vec<_>>();
+        drop(files);
+
+        let readers = self.data.readers.lock().clone();
+        for file in segment_files {
+            file.sync_metrics().add_to(&mut stats);
+            let checkpointed = file.is_checkpointed();
+            let writer = file.lock();
+            stats.segments.push(SegmentStats {
+                id: writer.id(),
+                bytes: writer.position(),
+                readers: readers.get(&writer.id()).copied().unwrap_or(0),
+                checkpointed,
+            });
+        }
+        stats.segments.sort_by_key(|segment| segment.id);
+        stats
+    }
+
+    /// Restarts the checkpointing thread after it has stopped because of an
+    /// error.
+    ///
+    /// The segment whose checkpoint failed is checkpointed again before any
+    /// other pending segments. If the checkpointing thread is running, this
+    /// function does nothing.
+    pub fn restart_checkpointer(&self) -> io::Result<()> {
+        let mut checkpoint_thread = self.data.checkpoint_thread.lock();
+        let join_handle = checkpoint_thread.take().ok_or(Error::Closed)?;
+        let mut files = self.data.files.lock();
+        if files.checkpointer_error.is_none() && !join_handle.is_finished() {
+            *checkpoint_thread = Some(join_handle);
+            return Ok(());
+        }
+        drop(files);
+
+        // The thread has recorded its error, and is exiting or has exited.
+        let _result = join_handle.handle.join();
+
+        files = self.data.files.lock();
+        files.checkpointer_error = None;
+        let retry_files = std::mem::take(&mut files.failed_checkpoints);
+        drop(files);
+
+        info!("Restarting checkpointing thread.");
+        *checkpoint_thread = Some(self.spawn_checkpoint_thread(retry_files));
+        Ok(())
     }
 
     fn reclaim(
@@ -320,17 +770,29 @@ where
         result: WriteResult,
         force_checkpoint: bool,
     ) -> io::Result<()> {
-        if let WriteResult::Entry { new_length } = result {
+        if let WriteResult::Entry {
+            new_length,
+            durability,
+        } = result
+        {
             let last_directory_sync =
                 if self.data.config.checkpoint_after_bytes <= new_length || force_checkpoint {
                     // Checkpoint this file. This first means activating a new file.
                     let mut files = self.data.files.lock();
-                    files.activate_new_file(&self.data.config)?;
+                    if let Err(err) = files.activate_new_file(&self.data.config) {
+                        // Keep writing to this file until a new file can be
+                        // activated.
+                        files.active = Some(file);
+                        drop(files);
+                        self.data.active_sync.notify_one();
+                        return Err(err);
+                    }
                     let last_directory_sync = files.directory_synced_at;
                     drop(files);
                     self.data.active_sync.notify_one();
 
                     // Now, send the file to the checkpointer.
+                    file.seal();
                     self.data
                         .checkpoint_sender
                         .send(CheckpointCommand::Checkpoint(file.clone()))
@@ -349,23 +811,25 @@ where
                     last_directory_sync
                 };
 
+            match durability {
+                Durability::Fsync => {}
+                Durability::Flush => return file.flush(new_length),
+                Durability::Buffered => return Ok(()),
+            }
+
             // Before reporting success we need to synchronize the data to
             // disk. To enable as much throughput as possible, we only want
             // one thread at a time to synchronize this file.
-            file.synchronize(new_length)?;
+            if let Some(window) = &self.data.config.group_commit_window {
+                file.synchronize_grouped(new_length, window)?;
+            } else {
+                file.synchronize(new_length)?;
+            }
 
             // If this file was activated during this process, we need to
             // sync the directory to ensure the file's metadata is
             // up-to-date.
-            if let Some(created_at) = file.created_at() {
-                // We want to avoid acquiring the lock again if we don't
-                // need to. Verify that the file was created after the last
-                // directory sync.
-                if last_directory_sync.is_none() || last_directory_sync.unwrap() < created_at {
-                    let files = self.data.files.lock();
-                    drop(self.sync_directory(files, created_at)?);
-                }
-            }
+            self.sync_directory_for(&file, last_directory_sync)?;
         } else {
             // Nothing happened, return the file back to be written.
             let mut files = self.data.files.lock();
@@ -377,14 +841,75 @@ where
         Ok(())
     }
 
+    fn flush_thread(data: &Weak<Data<M>>, stop_receiver: &flume::Receiver<()>, interval: Duration) {
+        debug!("Flush thread started.");
+        while let Err(flume::RecvTimeoutError::Timeout) = stop_receiver.recv_timeout(interval) {
+            let wal = if let Some(data) = data.upgrade() {
+                WriteAheadLog { data }
+            } else {
+                break;
+            };
+
+            // A failure is reported to the next writer that synchronizes the
+            // file, so the flush is simply attempted again later.
+            if let Err(err) = wal.synchronize_committed() {
+                warn!("Error synchronizing committed entries: {err:?}");
+            }
+        }
+        debug!("Flush thread stopping.");
+    }
+
+    /// Synchronizes the entries committed to every segment to disk.
+    fn synchronize_committed(&self) -> io::Result<()> {
+        let files = self.data.files.lock();
+        let segment_files = files.all.values().cloned().collect::<Vec<_>>();
+        let last_directory_sync = files.directory_synced_at;
+        drop(files);
+
+        for file in segment_files {
+            if file.synchronize_committed()? {
+                self.sync_directory_for(&file, last_directory_sync)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Synchronizes the directory if `file` was created after
+    /// `last_directory_sync`.
+    fn sync_directory_for(
+        &self,
+        file: &LogFile<M::File>,
+        last_directory_sync: Option<Instant>,
+    ) -> io::Result<()> {
+        if let Some(created_at) = file.created_at() {
+            // We want to avoid acquiring the lock again if we don't
+            // need to. Verify that the file was created after the last
+            // directory sync.
+            if last_directory_sync.is_none() || last_directory_sync.unwrap() < created_at {
+                let files = self.data.files.lock();
+                drop(self.sync_directory(files, created_at)?);
+            }
+        }
+        Ok(())
+    }
+
     fn checkpoint_thread(
         data: &Weak<Data<M>>,
         checkpoint_receiver: &flume::Receiver<CheckpointCommand<M::File>>,
+        mut retry_files: Vec<LogFile<M::File>>,
     ) -> io::Result<()> {
         debug!("Checkpointing thread started.");
-        while let Ok(CheckpointCommand::Checkpoint(file_to_checkpoint)) = checkpoint_receiver.recv()
-        {
-            debug!("Received file to checkpoint: {:?}", file_to_checkpoint);
+        let mut shutting_down = false;
+        while !shutting_down {
+            let mut files_to_checkpoint = std::mem::take(&mut retry_files);
+            if files_to_checkpoint.is_empty() {
+                if let Ok(CheckpointCommand::Checkpoint(file)) = checkpoint_receiver.recv() {
+                    files_to_checkpoint.push(file);
+                } else {
+                    break;
+                }
+            }
+            debug!("Received files to checkpoint: {:?}", files_to_checkpoint);
             let wal = if let Some(data) = data.upgrade() {
                 WriteAheadLog { data }
             } else {
@@ -392,29 +917,205 @@ where
                 break;
             };
 
-            let mut writer = file_to_checkpoint.lock();
-            let file_id = writer.id();
-            while !writer.is_synchronized() {
-                let synchronize_target = writer.position();
-                writer = file_to_checkpoint.synchronize_locked(writer, synchronize_target)?;
-            }
-            let last_checkpointed_entry_id = if let Some(entry_id) = writer.last_entry_id() {
-                let mut reader =
-                    SegmentReader::new(writer.path(), file_id, &wal.data.config.file_manager)?;
-                drop(writer);
-                let mut manager = wal.data.manager.lock();
-                if let Err(error) = manager.checkpoint_to(entry_id, &mut reader, &wal) {
-                    let message = format!(
-                        "Error: Checkpointer failed with error: {error:?}. Cannot proceed."
-                    );
-                    error!("{}", message);
-                    return Err(error);
+            // Checkpoint any other segments that are already waiting along
+            // with the first one.
+            while files_to_checkpoint.len() < wal.data.config.checkpoint_batch_size {
+                match checkpoint_receiver.try_recv() {
+                    Ok(CheckpointCommand::Checkpoint(file)) => files_to_checkpoint.push(file),
+                    Ok(CheckpointCommand::Shutdown) => {
+                        shutting_down = true;
+                        break;
+                    }
+                    Err(_) => break,
+                }
+            }
+
+            let started_at = Instant::now();
+            #[cfg(feature = "tracing")]
+            let _span = tracing::info_span!(
+                "checkpoint",
+                segment = files_to_checkpoint[0].lock().id(),
+                segments = files_to_checkpoint.len()
+            )
+            .entered();
+
+            // Only the segments that were checkpointed before the first one
+            // that failed are recycled. The rest are kept so that they can be
+            // checkpointed again once the thread is restarted.
+            let (checkpointed, result) = wal.checkpoint_segments(&files_to_checkpoint);
+            let mut unrecycled = files_to_checkpoint.into_iter();
+            for last_checkpointed_entry_id in checkpointed {
+                let checkpointed_file = unrecycled.next().expect("checkpointed too many");
+                // Archiving is retried along with the checkpoint, because the
+                // segment hasn't been renamed yet.
+                let archived = if last_checkpointed_entry_id.is_some() {
+                    match wal.archive_segment(&checkpointed_file) {
+                        Ok(archived) => archived,
+                        Err(error) => {
+                            let failed_files = std::iter::once(checkpointed_file)
+                                .chain(unrecycled)
+                                .collect();
+                            return Err(wal.checkpointer_failed(error, failed_files));
+                        }
+                    }
+                } else {
+                    None
+                };
+                if let Err(error) = wal.recycle_segment(
+                    checkpointed_file,
+                    last_checkpointed_entry_id,
+                    archived,
+                    started_at,
+                ) {
+                    return Err(wal.checkpointer_failed(error, unrecycled.collect()));
+                }
+            }
+            if let Err(error) = result {
+                return Err(wal.checkpointer_failed(error, unrecycled.collect()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Synchronizes `files_to_checkpoint` and passes their entries to
+    /// [`LogManager::checkpoint_to_many()`], retrying according to
+    /// [`Configuration::checkpoint_retry`].
+    ///
+    /// Returns the id of the last entry of each leading segment that was
+    /// checkpointed, and the error that prevented the remaining segments from
+    /// being checkpointed.
+    fn checkpoint_segments(
+        &self,
+        files_to_checkpoint: &[LogFile<M::File>],
+    ) -> (Vec<Option<EntryId>>, io::Result<()>) {
+        let mut segments = Vec::with_capacity(files_to_checkpoint.len());
+        let mut result = Ok(());
+        for file_to_checkpoint in files_to_checkpoint {
+            match Self::synchronize_for_checkpoint(file_to_checkpoint) {
+                Ok(segment) => segments.push(segment),
+                Err(error) => {
+                    result = Err(error);
+                    break;
                 }
-                writer = file_to_checkpoint.lock();
-                Some(entry_id)
+            }
+        }
+
+        let retry = self.data.config.checkpoint_retry;
+        let mut failed_attempts = 0;
+        let mut checkpointed = Vec::with_capacity(segments.len());
+        while checkpointed.len() < segments.len() {
+            let remaining = &segments[checkpointed.len()..];
+            let batch = remaining
+                .iter()
+                .filter_map(|(file_id, path, entry_id)| {
+                    entry_id.map(|entry_id| {
+                        SegmentReader::open(path, *file_id, &self.data.config)
+                            .map(|reader| SegmentCheckpoint::new(entry_id, reader))
+                    })
+                })
+                .collect::<io::Result<Vec<_>>>();
+            let mut batch = match batch {
+                Ok(batch) => batch,
+                Err(error) => return (checkpointed, Err(error)),
+            };
+
+            let mut manager = self.data.manager.lock();
+            let checkpoint_result = if batch.is_empty() {
+                Ok(())
             } else {
-                None
+                manager.checkpoint_to_many(&mut batch, self)
             };
+            drop(manager);
+
+            // Segments without entries don't need to be checkpointed.
+            let mut batch = batch.into_iter();
+            for (_, _, entry_id) in remaining {
+                if entry_id.is_some() {
+                    let segment = batch.next().expect("one reader per entry id");
+                    if checkpoint_result.is_err() && !segment.is_checkpointed() {
+                        break;
+                    }
+                }
+                checkpointed.push(*entry_id);
+            }
+
+            match checkpoint_result {
+                Ok(()) => {}
+                Err(error) if failed_attempts < retry.max_retries => {
+                    failed_attempts += 1;
+                    let backoff = retry.backoff(failed_attempts);
+                    warn!("Checkpointer failed with error: {error:?}. Retrying in {backoff:?}.");
+                    std::thread::sleep(backoff);
+                }
+                Err(error) => {
+                    error!("Error: Checkpointer failed with error: {error:?}. Cannot proceed.");
+                    return (checkpointed, Err(error));
+                }
+            }
+        }
+
+        (checkpointed, result)
+    }
+
+    /// Synchronizes `file_to_checkpoint`, returning its id, path and the id
+    /// of its last entry.
+    fn synchronize_for_checkpoint(
+        file_to_checkpoint: &LogFile<M::File>,
+    ) -> io::Result<(u64, PathId, Option<EntryId>)> {
+        let mut writer = file_to_checkpoint.lock();
+        while !writer.is_synchronized() {
+            let synchronize_target = writer.position();
+            writer = file_to_checkpoint.synchronize_locked(writer, synchronize_target)?;
+        }
+        Ok((writer.id(), writer.path().clone(), writer.last_entry_id()))
+    }
+
+    /// Passes `file_to_checkpoint` to [`Configuration::archiver`], if one is
+    /// set.
+    fn archive_segment(
+        &self,
+        file_to_checkpoint: &LogFile<M::File>,
+    ) -> io::Result<Option<Archived>> {
+        let archiver = if let Some(archiver) = &self.data.config.archiver {
+            archiver
+        } else {
+            return Ok(None);
+        };
+
+        let writer = file_to_checkpoint.lock();
+        let id = writer.id();
+        let path = writer.path().clone();
+        let length = writer.position();
+        drop(writer);
+
+        archiver
+            .archive(&CheckpointedSegment {
+                id,
+                path: &path,
+                length,
+                file_manager: &self.data.config.file_manager,
+            })
+            .map(Some)
+    }
+
+    /// Marks `file_to_checkpoint` as checkpointed through
+    /// `last_checkpointed_entry_id`, and prepares it for reuse once all of its
+    /// readers are closed. `started_at` is when the checkpoint began.
+    ///
+    /// If the segment was moved by the archiver, it is forgotten instead of
+    /// being reused.
+    fn recycle_segment(
+        &self,
+        file_to_checkpoint: LogFile<M::File>,
+        last_checkpointed_entry_id: Option<EntryId>,
+        archived: Option<Archived>,
+        started_at: Instant,
+    ) -> io::Result<()> {
+        let moved = archived == Some(Archived::Moved);
+        let mut writer = file_to_checkpoint.lock();
+        let file_id = writer.id();
+        if !moved {
             // Rename the file to denote that it's been checkpointed.
             let new_name = format!(
                 "{}-cp",
@@ -426,53 +1127,88 @@ where
                     .expect("should be ascii")
             );
             writer.rename(&new_name)?;
-            drop(writer);
+        }
+        drop(writer);
+        file_to_checkpoint.mark_checkpointed();
 
-            // Now, read attemps will fail, but any current readers are still
-            // able to read the data. Before we truncate the file, we need to
-            // wait for all existing readers to close.
-            let mut readers = wal.data.readers.lock();
-            while readers.get(&file_id).copied().unwrap_or(0) > 0 {
-                wal.data.readers_sync.wait(&mut readers);
-            }
-            readers.remove(&file_id);
-            drop(readers);
+        // Now, read attemps will fail, but any current readers are still
+        // able to read the data. Before we truncate the file, we need to
+        // wait for all existing readers to close.
+        let mut readers = self.data.readers.lock();
+        while readers.get(&file_id).copied().unwrap_or(0) > 0 {
+            self.data.readers_sync.wait(&mut readers);
+        }
+        readers.remove(&file_id);
+        drop(readers);
 
-            // Now that there are no readers, we can safely prepare the file for
-            // reuse.
+        // Now that there are no readers, we can safely prepare the file for
+        // reuse once its last ranges have been replicated.
+        if !moved {
+            let replication = self.data.files.lock().replication.clone();
+            if let Some(replication) = replication {
+                replication.wait_for_segment(file_id);
+            }
             let mut writer = file_to_checkpoint.lock();
             writer.revert_to(0)?;
             drop(writer);
+        }
 
-            let sync_target = Instant::now();
-
-            let files = wal.data.files.lock();
-            let mut files = wal.sync_directory(files, sync_target)?;
-            if let Some(entry_id) = files.last_checkpointed_entry_id {
-                if let Some(last_checkpointed_entry_id) = last_checkpointed_entry_id {
-                    if last_checkpointed_entry_id.0 > entry_id.0 {
-                        debug!(
-                            "Checkpointing finished. Set the last checkpointed entry it to: {:?}",
-                            last_checkpointed_entry_id
-                        );
-                        files.last_checkpointed_entry_id = Some(last_checkpointed_entry_id);
-                        wal.data.checkpoint_sync.notify_all();
-                    }
+        let sync_target = Instant::now();
+
+        let files = self.data.files.lock();
+        let mut files = self.sync_directory(files, sync_target)?;
+        self.data.metrics.record_checkpoint(started_at.elapsed());
+        if let Some(entry_id) = files.last_checkpointed_entry_id {
+            if let Some(last_checkpointed_entry_id) = last_checkpointed_entry_id {
+                if last_checkpointed_entry_id.0 > entry_id.0 {
+                    debug!(
+                        "Checkpointing finished. Set the last checkpointed entry it to: {:?}",
+                        last_checkpointed_entry_id
+                    );
+                    files.set_last_checkpointed_entry_id(Some(last_checkpointed_entry_id));
+                    self.data.checkpoint_sync.notify_all();
                 }
-            } else {
-                debug!(
-                    "Checkpointing finished. Set the last checkpointed entry it to: {:?}",
-                    last_checkpointed_entry_id
-                );
-                files.last_checkpointed_entry_id = last_checkpointed_entry_id;
-                wal.data.checkpoint_sync.notify_all();
             }
+        } else {
+            debug!(
+                "Checkpointing finished. Set the last checkpointed entry it to: {:?}",
+                last_checkpointed_entry_id
+            );
+            files.set_last_checkpointed_entry_id(last_checkpointed_entry_id);
+            self.data.checkpoint_sync.notify_all();
+        }
+        if moved {
+            files.all.remove(&file_id);
+        } else {
             files.inactive.push_back(file_to_checkpoint);
         }
 
         Ok(())
     }
 
+    /// Records that the checkpointing thread is stopping because of `error`,
+    /// and wakes everything waiting for a checkpoint. `failed_files` are
+    /// checkpointed first when the thread is restarted.
+    fn checkpointer_failed(
+        &self,
+        error: io::Error,
+        failed_files: Vec<LogFile<M::File>>,
+    ) -> io::Error {
+        let error = Error::CheckpointerFailed(Arc::new(error));
+        let mut files = self.data.files.lock();
+        files.checkpointer_error = Some(error.clone());
+        files.failed_checkpoints = failed_files;
+        self.data.checkpoint_sync.notify_all();
+        #[cfg(feature = "async")]
+        for (_, waiter) in files.checkpoint_waiters.drain(..) {
+            // The waiter may have been dropped, which is fine.
+            let _ = waiter.send(Err(error.clone()));
+        }
+        drop(files);
+
+        error.into()
+    }
+
     fn sync_directory<'a>(
         &'a self,
         mut files: MutexGuard<'a, Files<M::File>>,
@@ -488,16 +1224,24 @@ where
             } else {
                 files.directory_is_syncing = true;
                 drop(files);
+                #[cfg(feature = "tracing")]
+                let span = tracing::debug_span!("sync_directory").entered();
                 let synced_at = Instant::now();
-                self.data
+                let result = self
+                    .data
                     .config
                     .file_manager
-                    .sync_all(&self.data.config.directory)?;
+                    .sync_all(&self.data.config.directory);
+                #[cfg(feature = "tracing")]
+                drop(span);
 
                 files = self.data.files.lock();
                 files.directory_is_syncing = false;
-                files.directory_synced_at = Some(synced_at);
+                // Waiting threads retry the sync if it failed.
                 self.data.dirfsync_sync.notify_all();
+                result?;
+                self.data.metrics.record_directory_sync();
+                files.directory_synced_at = Some(synced_at);
                 break;
             }
         }
@@ -514,19 +1258,18 @@ where
     /// - The file cannot be read.
     /// - The position refers to data that has been checkpointed.
     pub fn read_at(&self, position: LogPosition) -> io::Result<ChunkReader<'_, M>> {
+        let chunk = self.open_chunk(position)?;
+        Ok(self.chunk_reader(position.file_id, chunk))
+    }
+
+    /// Synchronizes the segment containing `position` through `position`, and
+    /// opens it to read the chunk stored there.
+    fn open_chunk(&self, position: LogPosition) -> io::Result<OpenedChunk<M::File>> {
         // Before opening the file to read, we need to check that this position
         // has been written to disk fully.
-        let files = self.data.files.lock();
-        let log_file = files
-            .all
-            .get(&position.file_id)
-            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "invalid log position"))?
-            .clone();
-        drop(files);
+        self.synchronize_through(position)?;
 
-        log_file.synchronize(position.offset)?;
-
-        let mut file = self.data.config.file_manager.open(
+        let mut file = match self.data.config.file_manager.open(
             &PathId::from(
                 self.data
                     .config
@@ -534,32 +1277,201 @@ where
                     .join(format!("wal-{}", position.file_id)),
             ),
             OpenOptions::new().read(true),
-        )?;
-        file.seek(SeekFrom::Start(position.offset))?;
+        ) {
+            Ok(file) => file,
+            // The segment was renamed after being checkpointed.
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Err(Error::PositionCheckpointed { position }.into())
+            }
+            Err(err) => return Err(err),
+        };
+        file.rewind()?;
         let mut reader = BufReader::new(file);
-        let mut header_bytes = [0; 5];
-        reader.read_exact(&mut header_bytes)?;
-        let length = u32::from_le_bytes(header_bytes[1..5].try_into().expect("u32 is 4 bytes"));
+        let header = read_header(&mut reader)?;
+        reader.seek(SeekFrom::Start(position.offset))?;
+        let mut marker = [0; 1];
+        reader.read_exact(&mut marker)?;
+        let (length, decoded) = if marker[0] == ENCODED_CHUNK {
+            let encryption = ChunkEncryption::for_chunk(
+                self.data.config.cipher.as_ref(),
+                header.key_id,
+                position.file_id,
+                position.offset,
+            );
+            let decoded = DecodedChunk::read_from(&mut reader, encryption.as_ref())?;
+            (
+                u32::try_from(decoded.bytes_remaining()).to_io()?,
+                Some(decoded),
+            )
+        } else {
+            let mut length = [0; 4];
+            reader.read_exact(&mut length)?;
+            (u32::from_le_bytes(length), None)
+        };
 
-        let mut readers = self.data.readers.lock();
-        let file_readers = readers.entry(position.file_id).or_default();
-        *file_readers += 1;
-        drop(readers);
+        Ok(OpenedChunk {
+            reader,
+            length,
+            decoded,
+        })
+    }
+
+    /// Returns a reader for `chunk`, which was opened from the segment with
+    /// `file_id`. The segment is not reused until the reader is dropped.
+    fn chunk_reader(&self, file_id: u64, chunk: OpenedChunk<M::File>) -> ChunkReader<'_, M> {
+        self.register_reader(file_id);
 
-        Ok(ChunkReader {
+        let OpenedChunk {
+            reader,
+            length,
+            decoded,
+        } = chunk;
+        ChunkReader {
             wal: self,
-            file_id: position.file_id,
+            file_id,
             reader,
-            stored_crc32: None,
+            stored_crc32: decoded.as_ref().map(|decoded| decoded.stored_crc32),
             length,
             bytes_remaining: length,
-            read_crc32: 0,
-        })
+            read_crc32: decoded
+                .as_ref()
+                .map_or(0, |decoded| decoded.calculated_crc32),
+            decoded,
+        }
+    }
+
+    /// Opens the entry with `entry_id` to read its chunks.
+    ///
+    /// The segment containing the entry is found using its id, and the entry
+    /// is found within the segment using an index of entry offsets that is
+    /// kept in memory. Any committed entries that haven't been synchronized to
+    /// disk are synchronized before the entry is read.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error wrapping:
+    ///
+    /// - [`Error::EntryCheckpointed`] if the entry has been checkpointed.
+    /// - [`Error::EntryNotFound`] if the entry has not been committed.
+    ///
+    /// May also error if the segment cannot be read.
+    pub fn read_entry(&self, entry_id: EntryId) -> io::Result<EntryReader<'_, M>> {
+        let files = self.data.files.lock();
+        if files
+            .last_checkpointed_entry_id
+            .map_or(false, |checkpointed| entry_id <= checkpointed)
+        {
+            return Err(Error::EntryCheckpointed { entry_id }.into());
+        }
+        // Each segment is named after the first entry id it can contain, which
+        // means the entry can only be in the last segment whose id is less
+        // than or equal to it.
+        let (segment_id, segment) = files
+            .all
+            .iter()
+            .filter(|(id, _)| **id <= entry_id.0)
+            .max_by_key(|(id, _)| **id)
+            .map(|(id, file)| (*id, file.clone()))
+            .ok_or(Error::EntryCheckpointed { entry_id })?;
+        drop(files);
+
+        // Registering the reader before checking the segment ensures it can't
+        // be recycled until the reader is dropped.
+        self.register_reader(segment_id);
+        match self.find_entry(segment_id, &segment, entry_id) {
+            Ok(reader) => Ok(EntryReader {
+                wal: self,
+                segment_id,
+                id: entry_id,
+                reader,
+            }),
+            Err(err) => {
+                self.unregister_reader(segment_id);
+                Err(err)
+            }
+        }
+    }
+
+    /// Returns a reader of the segment `segment_id` positioned at the first
+    /// chunk of the entry `entry_id`.
+    fn find_entry(
+        &self,
+        segment_id: u64,
+        segment: &LogFile<M::File>,
+        entry_id: EntryId,
+    ) -> io::Result<SegmentReader<M::File>> {
+        let offset = segment.locate_entry(segment_id, entry_id)?;
+
+        let config = &self.data.config;
+        let path = PathId::from(config.directory.join(format!("wal-{segment_id}")));
+        let mut reader = match SegmentReader::open(&path, segment_id, config) {
+            Ok(reader) => reader,
+            // The segment was renamed after being checkpointed.
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Err(Error::EntryCheckpointed { entry_id }.into())
+            }
+            Err(err) => return Err(err),
+        };
+        if let Some(offset) = offset {
+            reader.file.seek(SeekFrom::Start(offset))?;
+        }
+
+        loop {
+            match reader.read_entry()? {
+                Some(entry) if entry.id() == entry_id => return Ok(reader),
+                Some(entry) if entry.id() < entry_id => {}
+                // The ids of entries that were rolled back are never used.
+                _ => return Err(Error::EntryNotFound { entry_id }.into()),
+            }
+        }
+    }
+
+    /// Prevents the file with `file_id` from being reused until
+    /// [`Self::unregister_reader()`] is called.
+    fn register_reader(&self, file_id: u64) {
+        let mut readers = self.data.readers.lock();
+        let file_readers = readers.entry(file_id).or_default();
+        *file_readers += 1;
+    }
+
+    fn unregister_reader(&self, file_id: u64) {
+        let mut readers = self.data.readers.lock();
+        let file_readers = readers.get_mut(&file_id).expect("reader entry not present");
+        *file_readers -= 1;
+        drop(readers);
+        self.data.readers_sync.notify_one();
+    }
+
+    /// Blocks until all data in the segment containing `position` has been
+    /// synchronized to disk through `position`.
+    fn synchronize_through(&self, position: LogPosition) -> io::Result<()> {
+        let files = self.data.files.lock();
+        let log_file = files
+            .all
+            .get(&position.file_id)
+            .ok_or(Error::PositionCheckpointed { position })?
+            .clone();
+        drop(files);
+
+        // Checkpointed segments are still tracked until they are reused.
+        if log_file.is_checkpointed() {
+            return Err(Error::PositionCheckpointed { position }.into());
+        }
+        log_file.synchronize(position.offset)
     }
 
     /// Waits for all other instances of [`WriteAheadLog`] to be dropped and for
     /// the checkpointing thread to complete.
     ///
+    /// If the checkpointing thread stopped because of an error, the error is
+    /// returned wrapped in [`Error::CheckpointerFailed`].
+    ///
+    /// Entries committed with [`Durability::Flush`] or [`Durability::Buffered`]
+    /// are synchronized to disk before the checkpointing thread is stopped.
+    /// Once the checkpointing thread has stopped, this call waits for the
+    /// synchronized ranges to be replicated. If replication failed, the error
+    /// is returned wrapped in [`Error::ReplicationFailed`].
+    ///
     /// This call will not interrupt any writers, and will block indefinitely if
     /// another instance of this [`WriteAheadLog`] exists and is not eventually
     /// dropped. This was the safest to implement, and because a WAL is
@@ -570,15 +1482,215 @@ where
         let join_handle = checkpoint_thread.take().expect("shutdown already invoked");
         drop(checkpoint_thread);
 
+        let flush_thread = self.data.flush_thread.lock().take();
+        if let Some(flush_thread) = flush_thread {
+            let _ = self.data.flush_stop.send(());
+            flush_thread
+                .join()
+                .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))?;
+        }
+        // Entries committed without `Durability::Fsync` may not have been
+        // synchronized yet.
+        let synchronized = self.synchronize_committed();
+
         self.data
             .checkpoint_sender
             .send(CheckpointCommand::Shutdown)
-            .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))?;
+            .to_io()?;
 
         // Wait for the checkpoint thread to terminate.
-        join_handle
+        let checkpointed = join_handle
+            .handle
             .join()
-            .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))?
+            .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))?;
+        let replicated = self.wait_for_replication();
+        synchronized.and(checkpointed).and(replicated)
+    }
+
+    /// Blocks until every range synchronized to the log's segment files
+    /// before this call has been passed to [`Configuration::replicator`].
+    ///
+    /// Returns immediately if no replicator is configured.
+    ///
+    /// # Errors
+    ///
+    /// If replication has stopped because of an error, the error is returned
+    /// wrapped in [`Error::ReplicationFailed`].
+    pub fn wait_for_replication(&self) -> io::Result<()> {
+        let replication = self.data.files.lock().replication.clone();
+        match replication {
+            Some(replication) => replication.wait(),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the error that stopped replication, wrapped in
+    /// [`Error::ReplicationFailed`], if replication has failed.
+    ///
+    /// A replication failure does not prevent entries from being committed.
+    /// No further ranges are replicated until the log is reopened.
+    #[must_use]
+    pub fn replication_error(&self) -> Option<Error> {
+        self.data
+            .files
+            .lock()
+            .replication
+            .as_ref()
+            .and_then(|replication| replication.error())
+    }
+}
+
+/// A segment file found in a log's directory.
+#[derive(Debug, Clone)]
+pub struct SegmentFile {
+    /// The id of the segment. No entry in the segment has an id lower than
+    /// this value.
+    pub id: u64,
+    /// The path to the segment file.
+    pub path: PathId,
+    /// True if the file is named `wal-<id>-cp`, which means the entries it
+    /// contains have been checkpointed.
+    pub checkpointed: bool,
+}
+
+/// Returns the segment files in `directory`, ordered by their ids.
+///
+/// Segment files are named `wal-<id>`, or `wal-<id>-cp` once checkpointed.
+/// All other files are ignored.
+pub fn list_segments<M: FileManager>(
+    file_manager: &M,
+    directory: &PathId,
+) -> io::Result<Vec<SegmentFile>> {
+    let mut segments = Vec::new();
+    for path in file_manager.list(directory)? {
+        if let Some((id, checkpointed)) = path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .and_then(parse_segment_file_name)
+        {
+            segments.push(SegmentFile {
+                id,
+                path,
+                checkpointed,
+            });
+        }
+    }
+    segments.sort_by_key(|segment| segment.id);
+    Ok(segments)
+}
+
+/// Passes each entry in the segments of `config.directory` whose id is between
+/// `from` and `to`, inclusive, to [`LogManager::recover()`]. Returns the id of
+/// the last entry passed to `manager`.
+///
+/// Segments are read in order, using the same process as recovering a
+/// [`WriteAheadLog`] when it is opened, including calling
+/// [`LogManager::should_recover_segment()`] for each segment read. The
+/// directory can be a log's directory or one that segments have been archived
+/// to using an [`Archiver`]. Segments that have been checkpointed, named
+/// `wal-<id>-cp`, are skipped, as they are waiting to be reused.
+///
+/// The entries of an open log are only replayed once they have been
+/// synchronized to disk. Entries can be written to the log while it is being
+/// replayed, but a segment may be recycled while it is being read. Archiving
+/// segments avoids this.
+pub fn replay<M, Manager>(
+    config: &Configuration<M>,
+    from: EntryId,
+    to: EntryId,
+    manager: &mut Manager,
+) -> io::Result<Option<EntryId>>
+where
+    M: FileManager,
+    Manager: LogManager<M>,
+{
+    let segments = list_segments(&config.file_manager, &config.directory)?
+        .into_iter()
+        .filter(|segment| !segment.checkpointed)
+        .collect::<Vec<_>>();
+    let mut last_replayed = None;
+    for (index, segment) in segments.iter().enumerate() {
+        if segment.id > to.0 {
+            break;
+        }
+        // Each segment only contains entries before the next segment's id.
+        if segments
+            .get(index + 1)
+            .map_or(false, |next| next.id <= from.0)
+        {
+            continue;
+        }
+
+        let mut reader = match SegmentReader::open(&segment.path, segment.id, config) {
+            Ok(reader) => reader,
+            Err(_) if log_file::is_uninitialized(&segment.path, &config.file_manager)? => continue,
+            Err(err) => return Err(err),
+        };
+        if let Recovery::Abandon = manager.should_recover_segment(&reader.header)? {
+            continue;
+        }
+        recover_entries(&mut reader, config.verify_recovered_chunks, |entry| {
+            if entry.id() > to {
+                Ok(false)
+            } else {
+                if entry.id() >= from {
+                    manager.recover(entry)?;
+                    last_replayed = Some(entry.id());
+                }
+                Ok(true)
+            }
+        })?;
+    }
+
+    Ok(last_replayed)
+}
+
+/// Passes each entry in `reader` to `recover`, until `recover` returns false
+/// or the end of the segment's valid data is reached.
+///
+/// If `verify_chunks` is true, each entry is only passed to `recover` once it
+/// has been read completely and its chunks' CRCs have been verified. The
+/// segment's valid data ends at the first entry that fails verification.
+fn recover_entries<F, R>(
+    reader: &mut SegmentReader<F>,
+    verify_chunks: bool,
+    mut recover: R,
+) -> io::Result<()>
+where
+    F: file_manager::File,
+    R: FnMut(&mut Entry<'_, F>) -> io::Result<bool>,
+{
+    // Reading the next entry skips any chunks `recover` didn't read. Running
+    // out of bytes while doing so means the entry was torn by a crash, and the
+    // segment's valid data ends where it begins.
+    loop {
+        if verify_chunks && !reader.verify_next_entry()? {
+            return Ok(());
+        }
+        let mut entry = match reader.read_entry() {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return Ok(()),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        if !recover(&mut entry)? {
+            return Ok(());
+        }
+    }
+}
+
+/// Parses a segment file name in the form `wal-<id>` or `wal-<id>-cp`,
+/// returning the id and whether the segment has been checkpointed.
+fn parse_segment_file_name(file_name: &str) -> Option<(u64, bool)> {
+    let mut parts = file_name.split('-');
+    let prefix = parts.next();
+    let entry_id = parts.next().and_then(|ts| ts.parse::<u64>().ok());
+    let suffix = parts.next();
+    match (prefix, entry_id, suffix) {
+        (Some(prefix), Some(entry_id), suffix) if prefix == "wal" => {
+            Some((entry_id, suffix == Some("cp")))
+        }
+        _ => None,
     }
 }
 
@@ -618,6 +1730,11 @@ where
     directory_synced_at: Option<Instant>,
     directory_is_syncing: bool,
     all: HashMap<u64, LogFile<F>>,
+    #[cfg(feature = "async")]
+    checkpoint_waiters: Vec<(EntryId, flume::Sender<Result<(), Error>>)>,
+    checkpointer_error: Option<Error>,
+    failed_checkpoints: Vec<LogFile<F>>,
+    replication: Option<Arc<ReplicationQueue<F>>>,
 }
 
 impl<F> Files<F>
@@ -635,18 +1752,17 @@ where
             file
         } else {
             if !is_enough_space_available(config)? {
-                error!("Not enough space available activate new file! Available space (bytes): {:?}. Total space (bytes): {:?}",
-                   available_space_bytes(config)?, total_space_bytes(config)?);
-                return Err(io::Error::new(
-                    io::ErrorKind::OutOfMemory,
-                    "Storage is full",
-                ));
+                let available = available_space_bytes(config)?;
+                let total = total_space_bytes(config)?;
+                error!("Not enough space available activate new file! Available space (bytes): {available:?}. Total space (bytes): {total:?}");
+                return Err(Error::StorageFull { available, total }.into());
             }
             let file = LogFile::write(
                 next_id,
                 config.directory.join(file_name).into(),
                 0,
                 None,
+                self.replication.as_ref(),
                 config,
             )?;
             self.all.insert(next_id, file.clone());
@@ -657,6 +1773,24 @@ where
 
         Ok(())
     }
+
+    /// Records that all entries through `entry_id` have been checkpointed, and
+    /// wakes the tasks waiting for them.
+    fn set_last_checkpointed_entry_id(&mut self, entry_id: Option<EntryId>) {
+        self.last_checkpointed_entry_id = entry_id;
+        #[cfg(feature = "async")]
+        if let Some(last_checkpointed_entry_id) = entry_id {
+            self.checkpoint_waiters.retain(|(entry_id, waiter)| {
+                if *entry_id <= last_checkpointed_entry_id {
+                    // The waiter may have been dropped, which is fine.
+                    let _ = waiter.send(Ok(()));
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+    }
 }
 
 impl<F> Default for Files<F>
@@ -672,14 +1806,54 @@ where
             directory_synced_at: None,
             directory_is_syncing: false,
             all: HashMap::new(),
+            #[cfg(feature = "async")]
+            checkpoint_waiters: Vec::new(),
+            checkpointer_error: None,
+            failed_checkpoints: Vec::new(),
+            replication: None,
         }
     }
 }
 
+/// The thread that checkpoints a log's segments.
+#[derive(Debug)]
+struct CheckpointThread {
+    handle: JoinHandle<io::Result<()>>,
+    /// Set once the thread exits, including when it panics.
+    finished: Arc<AtomicBool>,
+}
+
+impl CheckpointThread {
+    fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Acquire)
+    }
+}
+
+/// Sets its flag when dropped.
+struct SetOnDrop(Arc<AtomicBool>);
+
+impl Drop for SetOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
 #[derive(Clone, Copy)]
 enum WriteResult {
     RolledBack,
-    Entry { new_length: u64 },
+    Entry {
+        new_length: u64,
+        durability: Durability,
+    },
+}
+
+/// A chunk that has been opened for reading, but whose reader hasn't been
+/// registered with the log yet.
+#[derive(Debug)]
+struct OpenedChunk<F> {
+    reader: BufReader<F>,
+    length: u32,
+    decoded: Option<DecodedChunk>,
 }
 
 /// A buffered reader for a previously written data chunk.
@@ -698,13 +1872,15 @@ where
     length: u32,
     stored_crc32: Option<u32>,
     read_crc32: u32,
+    decoded: Option<DecodedChunk>,
 }
 
 impl<'a, M> ChunkReader<'a, M>
 where
     M: FileManager,
 {
-    /// Returns the length of the data stored.
+    /// Returns the length of the data stored. If the chunk was compressed,
+    /// this is the length of the decompressed data.
     ///
     /// This value will not change as data is read.
     #[must_use]
@@ -754,8 +1930,13 @@ where
         if buf.len() > bytes_remaining {
             buf = &mut buf[..bytes_remaining];
         }
-        let bytes_read = self.reader.read(buf)?;
-        self.read_crc32 = crc32c::crc32c_append(self.read_crc32, &buf[..bytes_read]);
+        let bytes_read = if let Some(decoded) = &mut self.decoded {
+            decoded.read(buf)?
+        } else {
+            let bytes_read = self.reader.read(buf)?;
+            self.read_crc32 = crc32c::crc32c_append(self.read_crc32, &buf[..bytes_read]);
+            bytes_read
+        };
         self.bytes_remaining -= u32::try_from(bytes_read).expect("can't be larger than buf.len()");
         Ok(bytes_read)
     }
@@ -766,13 +1947,56 @@ where
     M: FileManager,
 {
     fn drop(&mut self) {
-        let mut readers = self.wal.data.readers.lock();
-        let file_readers = readers
-            .get_mut(&self.file_id)
-            .expect("reader entry not present");
-        *file_readers -= 1;
-        drop(readers);
-        self.wal.data.readers_sync.notify_one();
+        self.wal.unregister_reader(self.file_id);
+    }
+}
+
+/// A reader for a previously committed entry, returned from
+/// [`WriteAheadLog::read_entry()`].
+///
+/// The segment containing the entry will not be reused until this reader is
+/// dropped.
+#[derive(Debug)]
+pub struct EntryReader<'a, M>
+where
+    M: FileManager,
+{
+    wal: &'a WriteAheadLog<M>,
+    segment_id: u64,
+    id: EntryId,
+    reader: SegmentReader<M::File>,
+}
+
+impl<M> EntryReader<'_, M>
+where
+    M: FileManager,
+{
+    /// The unique id of this entry.
+    #[must_use]
+    pub const fn id(&self) -> EntryId {
+        self.id
+    }
+
+    /// Returns the entry, which can be used to read its chunks.
+    pub fn entry(&mut self) -> Entry<'_, M::File> {
+        Entry {
+            id: self.id,
+            reader: &mut self.reader,
+        }
+    }
+
+    /// Reads all chunks of this entry. See [`Entry::read_all_chunks()`].
+    pub fn read_all_chunks(&mut self) -> io::Result<Option<Vec<Vec<u8>>>> {
+        self.entry().read_all_chunks()
+    }
+}
+
+impl<M> Drop for EntryReader<'_, M>
+where
+    M: FileManager,
+{
+    fn drop(&mut self) {
+        self.wal.unregister_reader(self.segment_id);
     }
 }
 
@@ -9,11 +9,36 @@ use parking_lot::{Condvar, Mutex, MutexGuard};
 
 use crate::{
     buffered::Buffered,
-    entry::{EntryId, CHUNK, END_OF_ENTRY, NEW_ENTRY},
+    codec::DecodedChunk,
+    encryption::ChunkEncryption,
+    entry::{EntryId, CHUNK, ENCODED_CHUNK, END_OF_ENTRY, KEY_ID, NEW_ENTRY},
+    replication::{QueuedRange, ReplicationQueue},
+    stats::SyncMetrics,
     to_io_result::ToIoResult,
-    Configuration, LogPosition,
+    Cipher, Configuration, Error, GroupCommitWindow, LogPosition,
 };
 
+/// The most bytes [`EntryChunk::read_all()`] allocates before reading a chunk.
+const MAX_PREALLOCATED_CHUNK_BYTES: usize = 1024 * 1024;
+
+/// The minimum number of bytes between the entries recorded in an
+/// [`EntryIndex`].
+const ENTRY_INDEX_INTERVAL: u64 = 16 * 1024;
+
+/// The newest version of the segment format, which is written to new segments
+/// by default. Segments written in any version up to this one can be read.
+///
+/// - Version 0: Entries end with an end-of-entry marker.
+/// - Version 1: The end-of-entry marker is followed by the entry's length and
+///   a CRC32C of its bytes, from its header through its end-of-entry marker.
+///
+/// See [`Configuration::format_version`].
+pub const FORMAT_VERSION: u8 = 1;
+
+/// The number of bytes following [`END_OF_ENTRY`] in version 1 segments: an
+/// 8 byte length and a 4 byte CRC32C.
+const ENTRY_TRAILER_LENGTH: u64 = 12;
+
 #[derive(Debug)]
 pub struct LogFile<F>
 where
@@ -31,9 +56,17 @@ where
         path: PathId,
         validated_length: u64,
         last_entry_id: Option<EntryId>,
+        replication: Option<&Arc<ReplicationQueue<F>>>,
         config: &Configuration<F::Manager>,
     ) -> io::Result<Self> {
-        let writer = LogFileWriter::new(id, path, validated_length, last_entry_id, config)?;
+        let writer = LogFileWriter::new(
+            id,
+            path,
+            validated_length,
+            last_entry_id,
+            replication,
+            config,
+        )?;
         let created_at = if last_entry_id.is_some() {
             None
         } else {
@@ -43,7 +76,9 @@ where
             data: Arc::new(LogFileData {
                 writer: Mutex::new(writer),
                 sync: Condvar::new(),
+                group_commit: Condvar::new(),
                 created_at,
+                sync_metrics: SyncMetrics::default(),
             }),
         })
     }
@@ -60,6 +95,10 @@ where
         self.data.created_at
     }
 
+    pub fn sync_metrics(&self) -> &SyncMetrics {
+        &self.data.sync_metrics
+    }
+
     pub fn lock(&self) -> MutexGuard<'_, LogFileWriter<F>> {
         self.data.writer.lock()
     }
@@ -67,9 +106,129 @@ where
     pub fn rename(&self, new_id: u64, new_name: &str) -> io::Result<()> {
         let mut writer = self.data.writer.lock();
         writer.id = new_id;
+        writer.state = SegmentState::Active;
         writer.rename(new_name)
     }
 
+    /// Marks this file as no longer accepting new entries.
+    ///
+    /// If all committed entries have already been synchronized, the
+    /// replicator is notified that the segment is complete. Otherwise, the
+    /// notification is sent with the final synchronized range.
+    pub fn seal(&self) {
+        let mut writer = self.data.writer.lock();
+        writer.state = SegmentState::Sealed;
+        if !writer.is_syncing && writer.synchronized_through >= writer.committed_through {
+            let synchronized_through = writer.synchronized_through;
+            writer.replicate(synchronized_through, synchronized_through);
+        }
+        drop(writer);
+        self.data.sync.notify_all();
+    }
+
+    /// Marks this file as checkpointed. Its entries can no longer be read.
+    pub fn mark_checkpointed(&self) {
+        self.set_state(SegmentState::Checkpointed);
+    }
+
+    fn set_state(&self, state: SegmentState) {
+        let mut writer = self.data.writer.lock();
+        writer.state = state;
+        drop(writer);
+        self.data.sync.notify_all();
+    }
+
+    pub fn is_checkpointed(&self) -> bool {
+        self.data.writer.lock().state == SegmentState::Checkpointed
+    }
+
+    /// Synchronizes the committed entries of this file to disk, and returns
+    /// the offset of the closest indexed entry at or before `entry_id`, if
+    /// any.
+    ///
+    /// `id` is the id this file is expected to have. If the file has been
+    /// recycled or checkpointed, [`Error::EntryCheckpointed`] is returned. If
+    /// `entry_id` hasn't been committed to this file,
+    /// [`Error::EntryNotFound`] is returned.
+    pub fn locate_entry(&self, id: u64, entry_id: EntryId) -> io::Result<Option<u64>> {
+        let mut writer = self.lock();
+        if writer.synchronized_through < writer.committed_through {
+            let committed_through = writer.committed_through;
+            writer = self.synchronize_locked(writer, committed_through)?;
+        }
+
+        if writer.id != id || writer.state == SegmentState::Checkpointed {
+            Err(Error::EntryCheckpointed { entry_id }.into())
+        } else if writer.last_entry_id.map_or(true, |last| entry_id > last) {
+            Err(Error::EntryNotFound { entry_id }.into())
+        } else {
+            Ok(writer.entry_index.search_from(entry_id))
+        }
+    }
+
+    /// Waits until entries committed after `offset` have been synchronized to
+    /// disk, the file is sealed, or `deadline` is reached.
+    ///
+    /// `id` is the id this file is expected to have. If the file has been
+    /// recycled or checkpointed, [`SegmentProgress::Checkpointed`] is
+    /// returned.
+    pub fn wait_for_entries(
+        &self,
+        id: u64,
+        offset: u64,
+        deadline: Option<Instant>,
+    ) -> SegmentProgress {
+        let mut writer = self.data.writer.lock();
+        loop {
+            if writer.id != id || writer.state == SegmentState::Checkpointed {
+                return SegmentProgress::Checkpointed;
+            }
+
+            let through = writer.synchronized_through.min(writer.committed_through);
+            if through > offset {
+                return SegmentProgress::Readable {
+                    through,
+                    at_entry_boundary: writer.committed_through <= writer.synchronized_through,
+                };
+            } else if writer.state == SegmentState::Sealed && writer.committed_through <= offset {
+                return SegmentProgress::Sealed;
+            }
+
+            if let Some(deadline) = deadline {
+                if self.data.sync.wait_until(&mut writer, deadline).timed_out() {
+                    return SegmentProgress::TimedOut;
+                }
+            } else {
+                self.data.sync.wait(&mut writer);
+            }
+        }
+    }
+
+    /// Writes any buffered bytes before `target_flushed_bytes` to the file,
+    /// without synchronizing them to disk.
+    pub fn flush(&self, target_flushed_bytes: u64) -> io::Result<()> {
+        let mut data = self.lock();
+        if data.buffer_position() < target_flushed_bytes {
+            data.file.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Synchronizes all committed entries to disk. Returns true if any bytes
+    /// needed to be synchronized.
+    pub fn synchronize_committed(&self) -> io::Result<bool> {
+        let data = self.lock();
+        if data.synchronized_through < data.committed_through
+            && data.state != SegmentState::Checkpointed
+        {
+            let committed_through = data.committed_through;
+            drop(self.synchronize_locked(data, committed_through)?);
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
     pub fn synchronize(&self, target_synced_bytes: u64) -> io::Result<()> {
         // Flush the buffer to disk.
         let data = self.lock();
@@ -77,11 +236,41 @@ where
             .map(|_| ())
     }
 
+    /// Synchronizes this file through `target_synced_bytes` like
+    /// [`Self::synchronize()`], but if no other thread is synchronizing the
+    /// file, first waits for up to `window.max_delay` for more commits so that
+    /// they are synchronized by the same `fsync`.
+    pub fn synchronize_grouped(
+        &self,
+        target_synced_bytes: u64,
+        window: &GroupCommitWindow,
+    ) -> io::Result<()> {
+        let mut data = self.lock();
+        if !data.is_syncing && data.synchronized_through < target_synced_bytes {
+            // Become the sync thread while waiting, which causes other
+            // committers to wait for this thread to synchronize their entries.
+            data.is_syncing = true;
+            let deadline = Instant::now() + window.max_delay;
+            while !window.is_full(data.committed_through - data.synchronized_through)
+                && !self
+                    .data
+                    .group_commit
+                    .wait_until(&mut data, deadline)
+                    .timed_out()
+            {}
+            data.is_syncing = false;
+        }
+
+        self.synchronize_locked(data, target_synced_bytes)
+            .map(|_| ())
+    }
+
     pub fn synchronize_locked<'a>(
         &'a self,
         mut data: MutexGuard<'a, LogFileWriter<F>>,
         target_synced_bytes: u64,
     ) -> io::Result<MutexGuard<'a, LogFileWriter<F>>> {
+        self.data.sync_metrics.record_request();
         loop {
             if data.synchronized_through >= target_synced_bytes {
                 // While we were waiting for the lock, another thread synced our
@@ -89,30 +278,62 @@ where
                 break;
             } else if data.is_syncing {
                 // Another thread is currently synchronizing this file.
+                self.data.group_commit.notify_one();
                 self.data.sync.wait(&mut data);
             } else {
-                // Become the sync thread for this file.
-                data.is_syncing = true;
+                let synchronized_from = data.synchronized_through;
 
                 // Check if we need to flush the buffer before calling fsync.
                 // It's possible that the currently buffered data doesn't need
-                // to be flushed.
-                if data.buffer_position() < target_synced_bytes {
+                // to be flushed. Any other committed entries are flushed too,
+                // so that they are covered by this fsync.
+                if data.buffer_position() < target_synced_bytes.max(data.committed_through) {
                     data.file.flush()?;
                 }
                 let synchronized_length = data.buffer_position();
+                let synchronized_commits = std::mem::take(&mut data.unsynchronized_commits);
 
                 // Get a duplicate handle we can use to call sync_data with while the
                 // mutex isn't locked.
                 let file_to_sync = data.file.inner().try_clone()?;
+
+                // Become the sync thread for this file.
+                data.is_syncing = true;
+                #[cfg(feature = "tracing")]
+                let span = tracing::debug_span!(
+                    "fsync",
+                    segment = data.id,
+                    bytes = synchronized_length - synchronized_from
+                )
+                .entered();
                 drop(data);
 
-                file_to_sync.sync_data()?;
+                let sync_started_at = Instant::now();
+                let synced = file_to_sync.sync_data();
+                self.data
+                    .sync_metrics
+                    .record_fsync(sync_started_at.elapsed());
+                #[cfg(feature = "tracing")]
+                drop(span);
 
                 data = self.lock();
                 data.is_syncing = false;
-                data.synchronized_through = synchronized_length;
+                if synced.is_ok() {
+                    data.synchronized_through = synchronized_length;
+                    self.data
+                        .sync_metrics
+                        .record_synchronized_commits(synchronized_commits);
+                    // The replicator must receive each segment's ranges in
+                    // order, so the range is queued before another thread can
+                    // begin syncing.
+                    data.replicate(synchronized_from, synchronized_length);
+                } else {
+                    data.unsynchronized_commits += synchronized_commits;
+                }
+                // Waiting threads must be woken even if syncing failed, or
+                // they would wait for a sync that will never finish.
                 self.data.sync.notify_all();
+                synced?;
                 break;
             }
         }
@@ -131,6 +352,71 @@ where
     }
 }
 
+/// A sparse index of the offsets of the entries in a segment, which allows an
+/// entry to be found without reading the segment from its start.
+#[derive(Debug, Default)]
+pub struct EntryIndex {
+    entries: Vec<(EntryId, u64)>,
+}
+
+impl EntryIndex {
+    /// Records that the entry `id` begins at `offset`, if it is far enough
+    /// from the last recorded entry.
+    pub fn record(&mut self, id: EntryId, offset: u64) {
+        if self
+            .entries
+            .last()
+            .map_or(true, |(_, last)| offset >= last + ENTRY_INDEX_INTERVAL)
+        {
+            self.entries.push((id, offset));
+        }
+    }
+
+    /// Removes the entries that begin at or after `length`.
+    pub fn truncate(&mut self, length: u64) {
+        let retained = self.entries.partition_point(|(_, offset)| *offset < length);
+        self.entries.truncate(retained);
+    }
+
+    /// Returns the offset of the last recorded entry at or before `id`.
+    pub fn search_from(&self, id: EntryId) -> Option<u64> {
+        let index = self
+            .entries
+            .partition_point(|(entry_id, _)| *entry_id <= id);
+        index.checked_sub(1).map(|index| self.entries[index].1)
+    }
+}
+
+/// The result of [`LogFile::wait_for_entries()`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SegmentProgress {
+    /// Committed entries have been synchronized through `through`. If
+    /// `at_entry_boundary` is false, `through` may be in the middle of an
+    /// entry that has not been fully synchronized yet.
+    Readable {
+        through: u64,
+        at_entry_boundary: bool,
+    },
+    /// All entries in the file have been read, and no more entries will be
+    /// written to it.
+    Sealed,
+    /// The file has been checkpointed or recycled.
+    Checkpointed,
+    /// The deadline was reached.
+    TimedOut,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum SegmentState {
+    /// The file may receive new entries.
+    Active,
+    /// The file will not receive new entries, but has not been checkpointed.
+    Sealed,
+    /// The file has been checkpointed, and its entries are no longer
+    /// accessible.
+    Checkpointed,
+}
+
 #[derive(Debug)]
 struct LogFileData<F>
 where
@@ -139,6 +425,11 @@ where
     writer: Mutex<LogFileWriter<F>>,
     created_at: Option<Instant>,
     sync: Condvar,
+    /// Notified when a thread begins waiting for another thread to
+    /// synchronize the file, which may allow a thread waiting for a group
+    /// commit to stop waiting early.
+    group_commit: Condvar,
+    sync_metrics: SyncMetrics,
 }
 
 #[derive(Debug)]
@@ -152,8 +443,32 @@ where
     last_entry_id: Option<EntryId>,
     version_info: Arc<Vec<u8>>,
     synchronized_through: u64,
+    committed_through: u64,
+    unsynchronized_commits: u64,
     is_syncing: bool,
+    state: SegmentState,
     manager: F::Manager,
+    replication: Option<Arc<ReplicationQueue<F>>>,
+    /// The handle synchronized ranges are read through to replicate them,
+    /// which is opened the first time a range is replicated.
+    replication_reader: Option<Arc<Mutex<F>>>,
+    cipher: Option<Arc<dyn Cipher>>,
+    key_id: Option<u32>,
+    /// The version of the format this segment is written in.
+    format_version: u8,
+    /// The version of the format this segment is written in once it is
+    /// recycled.
+    configured_format_version: u8,
+    /// The checksum of the entry being written, if any.
+    entry_checksum: Option<EntryChecksum>,
+    entry_index: EntryIndex,
+}
+
+/// The running checksum of an entry being written.
+#[derive(Debug, Clone, Copy)]
+struct EntryChecksum {
+    start: u64,
+    crc: u32,
 }
 
 static ZEROES: [u8; 8196] = [0; 8196];
@@ -167,6 +482,7 @@ where
         path: PathId,
         validated_length: u64,
         last_entry_id: Option<EntryId>,
+        replication: Option<&Arc<ReplicationQueue<F>>>,
         config: &Configuration<F::Manager>,
     ) -> io::Result<Self> {
         let mut file = config.file_manager.open(
@@ -195,12 +511,25 @@ where
             }
         }
 
+        // Existing segments continue to be encrypted with the key recorded in
+        // their header, and written in the format they were created with.
+        let (key_id, format_version) = if validated_length > 0 {
+            file.rewind()?;
+            let header = read_header(&mut BufReader::new(&mut file))?;
+            (header.key_id, header.format_version)
+        } else {
+            (
+                config.cipher.as_ref().map(|cipher| cipher.current_key_id()),
+                config.format_version,
+            )
+        };
+
         // Position the writer to write after the last validated byte.
         file.seek(SeekFrom::Start(validated_length))?;
         let mut file = Buffered::with_capacity(file, config.buffer_bytes)?;
 
         if validated_length == 0 {
-            Self::write_header(&mut file, &config.version_info)?;
+            Self::write_header(&mut file, format_version, &config.version_info, key_id)?;
             file.flush()?;
         }
 
@@ -211,16 +540,37 @@ where
             last_entry_id,
             version_info: config.version_info.clone(),
             synchronized_through: validated_length,
+            committed_through: validated_length,
+            unsynchronized_commits: 0,
             is_syncing: false,
+            state: SegmentState::Active,
             manager: config.file_manager.clone(),
+            replication: replication.cloned(),
+            replication_reader: None,
+            cipher: config.cipher.clone(),
+            key_id,
+            format_version,
+            configured_format_version: config.format_version,
+            entry_checksum: None,
+            entry_index: EntryIndex::default(),
         })
     }
 
-    fn write_header(file: &mut Buffered<F>, version_info: &[u8]) -> io::Result<()> {
-        file.write_all(b"okw\0")?;
+    fn write_header(
+        file: &mut Buffered<F>,
+        format_version: u8,
+        version_info: &[u8],
+        key_id: Option<u32>,
+    ) -> io::Result<()> {
+        file.write_all(b"okw")?;
+        file.write_all(&[format_version])?;
         let version_size = u8::try_from(version_info.len()).to_io()?;
         file.write_all(&[version_size])?;
         file.write_all(version_info)?;
+        if let Some(key_id) = key_id {
+            file.write_all(&[KEY_ID])?;
+            file.write_all(&key_id.to_le_bytes())?;
+        }
         Ok(())
     }
 
@@ -252,9 +602,24 @@ where
         if self.synchronized_through > length {
             self.synchronized_through = length;
         }
-        if self.synchronized_through == 0 {
-            Self::write_header(&mut self.file, &self.version_info)?;
+        if self.committed_through > length {
+            self.committed_through = length;
+        }
+        self.entry_index.truncate(length);
+        self.entry_checksum = None;
+        if length == 0 {
+            // Recycled segments are encrypted with the current key, and
+            // written in the configured format.
+            self.key_id = self.cipher.as_ref().map(|cipher| cipher.current_key_id());
+            self.format_version = self.configured_format_version;
+            Self::write_header(
+                &mut self.file,
+                self.format_version,
+                &self.version_info,
+                self.key_id,
+            )?;
             self.last_entry_id = None;
+            self.unsynchronized_commits = 0;
         }
 
         Ok(())
@@ -273,12 +638,99 @@ where
         Ok(())
     }
 
+    /// Queues the bytes from `from` to `to`, which must already be
+    /// synchronized to disk, to be sent to the configured replicator.
+    fn replicate(&mut self, from: u64, to: u64) {
+        let replication = match &self.replication {
+            Some(replication) => replication,
+            None => return,
+        };
+        let sealed = self.state != SegmentState::Active;
+        if from >= to && !sealed {
+            return;
+        }
+
+        // The segment's ranges are read using a handle of their own, opened
+        // once per segment, which remains valid if the segment is renamed
+        // before a range is replicated.
+        let file = if from < to {
+            if self.replication_reader.is_none() {
+                match self.manager.open(&self.path, OpenOptions::new().read(true)) {
+                    Ok(file) => self.replication_reader = Some(Arc::new(Mutex::new(file))),
+                    Err(err) => {
+                        replication.fail(err);
+                        return;
+                    }
+                }
+            }
+            self.replication_reader.clone()
+        } else {
+            None
+        };
+        replication.enqueue(QueuedRange {
+            file,
+            segment_id: self.id,
+            offset: from,
+            length: to.saturating_sub(from),
+            committed_through: self.committed_through,
+            sealed,
+        });
+    }
+
+    /// Returns true if this segment is encrypted if `config` has a cipher,
+    /// and is written in the format `config` writes new segments in.
+    pub fn is_written_as_configured(&self, config: &Configuration<F::Manager>) -> bool {
+        (config.cipher.is_none() || self.key_id.is_some())
+            && self.format_version == config.format_version
+    }
+
+    /// Returns the encryption to apply to a chunk written at the current
+    /// position.
+    pub fn chunk_encryption(&self) -> Option<ChunkEncryption> {
+        ChunkEncryption::for_chunk(self.cipher.as_ref(), self.key_id, self.id, self.position())
+    }
+
     pub fn last_entry_id(&self) -> Option<EntryId> {
         self.last_entry_id
     }
 
-    pub fn set_last_entry_id(&mut self, last_entry_id: Option<EntryId>) {
-        self.last_entry_id = last_entry_id;
+    /// Records that the header of the entry `id` is about to be written at
+    /// the current position.
+    pub fn begin_entry(&mut self, id: EntryId) {
+        let position = self.position();
+        self.entry_index.record(id, position);
+        if self.format_version >= 1 {
+            self.entry_checksum = Some(EntryChecksum {
+                start: position,
+                crc: 0,
+            });
+        }
+    }
+
+    /// Writes the end of the entry being written, followed by its length and
+    /// checksum if this segment's format includes them.
+    pub fn end_entry(&mut self) -> io::Result<()> {
+        self.write_all(&[END_OF_ENTRY])?;
+        if let Some(checksum) = self.entry_checksum.take() {
+            let length = self.position() - checksum.start;
+            self.write_all(&length.to_le_bytes())?;
+            self.write_all(&checksum.crc.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the index of this file's entries with one built while
+    /// recovering the file.
+    pub fn set_entry_index(&mut self, entry_index: EntryIndex) {
+        self.entry_index = entry_index;
+    }
+
+    /// Records that all entries written through the current position,
+    /// ending with `last_entry_id`, have been committed.
+    pub fn record_commit(&mut self, last_entry_id: EntryId) {
+        self.last_entry_id = Some(last_entry_id);
+        self.committed_through = self.position();
+        self.unsynchronized_commits += 1;
     }
 }
 
@@ -288,6 +740,9 @@ where
 {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         let bytes_written = self.file.write(buf)?;
+        if let Some(checksum) = &mut self.entry_checksum {
+            checksum.crc = crc32c::crc32c_append(checksum.crc, &buf[..bytes_written]);
+        }
 
         Ok(bytes_written)
     }
@@ -310,6 +765,23 @@ where
     pub(crate) first_entry_id: Option<EntryId>,
     pub(crate) last_entry_id: Option<EntryId>,
     pub(crate) valid_until: u64,
+    /// The number of entries read that were never completely written.
+    pub(crate) aborted_entries: u64,
+    /// The number of bytes following `valid_until` that were discarded because
+    /// the file ended partway through an entry.
+    pub(crate) truncated_bytes: u64,
+    /// The number of chunks read whose CRC didn't match.
+    pub(crate) crc_failures: u64,
+    /// The position of the first chunk or entry read whose CRC didn't match.
+    pub(crate) first_crc_failure: Option<LogPosition>,
+    /// The CRC stored after the most recently read entry, in version 1
+    /// segments.
+    entry_crc: Option<u32>,
+    /// The CRC of the bytes read so far from the entry being verified by
+    /// [`Entry::verify_chunks()`], which is `None` for entries that aren't
+    /// being verified.
+    calculated_entry_crc: Option<u32>,
+    cipher: Option<Arc<dyn Cipher>>,
 }
 
 impl<F> SegmentReader<F>
@@ -324,31 +796,8 @@ where
         let mut file = manager.open(path, OpenOptions::new().read(true))?;
         file.rewind()?;
         let mut file = BufReader::new(file);
-        let mut buffer = Vec::with_capacity(256 + 5);
-        buffer.resize(5, 0);
-        file.read_exact(&mut buffer)?;
-
-        if &buffer[0..3] != b"okw" {
-            return Err(io::Error::new(
-                ErrorKind::InvalidData,
-                "segment file did not contain magic code",
-            ));
-        }
-
-        if buffer[3] != 0 {
-            return Err(io::Error::new(
-                ErrorKind::InvalidData,
-                "segment file was written with a newer version",
-            ));
-        }
-
-        let version_info_length = buffer[4];
-        buffer.resize(usize::from(version_info_length), 0);
-        file.read_exact(&mut buffer)?;
-
-        let header = RecoveredSegment {
-            version_info: buffer,
-        };
+        let header = read_header(&mut file)?;
+        let valid_until = file.stream_position()?;
 
         Ok(Self {
             file_id,
@@ -357,16 +806,95 @@ where
             current_entry_id: None,
             first_entry_id: None,
             last_entry_id: None,
-            valid_until: u64::from(version_info_length) + 5,
+            valid_until,
+            aborted_entries: 0,
+            truncated_bytes: 0,
+            crc_failures: 0,
+            first_crc_failure: None,
+            entry_crc: None,
+            calculated_entry_crc: None,
+            cipher: None,
         })
     }
 
+    /// Replaces the file being read with the file now at `path`, such as after
+    /// it has been replaced by a rename. The file must have the same header.
+    pub(crate) fn reopen<M>(&mut self, path: &PathId, manager: &M) -> io::Result<()>
+    where
+        M: FileManager<File = F>,
+    {
+        self.file = BufReader::new(manager.open(path, OpenOptions::new().read(true))?);
+        Ok(())
+    }
+
+    /// Opens the segment at `path` for reading, decrypting its chunks using
+    /// the cipher in `config`.
+    pub(crate) fn open<M>(
+        path: &PathId,
+        file_id: u64,
+        config: &Configuration<M>,
+    ) -> io::Result<Self>
+    where
+        M: FileManager<File = F>,
+    {
+        let mut reader = Self::new(path, file_id, &config.file_manager)?;
+        reader.cipher.clone_from(&config.cipher);
+        Ok(reader)
+    }
+
+    /// Sets the cipher used to decrypt this segment's chunks. Returns `self`.
+    ///
+    /// Reading an encrypted chunk without a cipher returns an error.
+    #[must_use]
+    pub fn decrypt_with(mut self, cipher: Arc<dyn Cipher>) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    /// Returns the encryption used for the chunk at `offset`.
+    fn chunk_encryption(&self, offset: u64) -> Option<ChunkEncryption> {
+        ChunkEncryption::for_chunk(
+            self.cipher.as_ref(),
+            self.header.key_id,
+            self.file_id,
+            offset,
+        )
+    }
+
+    /// Returns the header of this segment.
+    #[must_use]
+    pub const fn header(&self) -> &RecoveredSegment {
+        &self.header
+    }
+
+    /// Returns the offset of the most recently read entry header. Once
+    /// [`SegmentReader::read_entry()`] returns `None`, this is the offset where
+    /// the segment's valid data ends, and recovery treats everything after it
+    /// as free space.
+    #[must_use]
+    pub const fn valid_until(&self) -> u64 {
+        self.valid_until
+    }
+
     fn read_next_entry(&mut self) -> io::Result<bool> {
-        self.valid_until = self.file.stream_position()?;
+        let position = self.file.stream_position()?;
+        if position > self.file.get_ref().len()? {
+            // Skipping a torn chunk moved past the end of the file, which
+            // means the previous entry is where the valid data ends.
+            self.current_entry_id = None;
+            self.record_truncation()?;
+            return Ok(false);
+        }
+        self.valid_until = position;
+        self.entry_crc = None;
+        self.calculated_entry_crc = None;
         let mut header_bytes = [0; 9];
         match self.file.read_exact(&mut header_bytes) {
             Ok(()) => {}
-            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                self.record_truncation()?;
+                return Ok(false);
+            }
             Err(err) => return Err(err),
         }
 
@@ -387,18 +915,122 @@ where
         Ok(false)
     }
 
+    /// Moves to `offset`, where the next entry is read from. The buffered
+    /// bytes are kept if `offset` is within them.
+    pub(crate) fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        let position = self.file.stream_position()?;
+        self.file
+            .seek_relative(i64::try_from(offset).to_io()? - i64::try_from(position).to_io()?)?;
+        self.current_entry_id = None;
+        self.valid_until = offset;
+        Ok(())
+    }
+
+    /// Reads the chunks of the next entry to verify that it is complete and
+    /// that their CRCs match, and then moves back to the start of the entry so
+    /// that it can be read using [`SegmentReader::read_entry()`].
+    ///
+    /// Returns false if there are no more entries, or if the next entry is
+    /// incomplete or has a chunk whose CRC doesn't match. In the latter case,
+    /// the entry is counted as aborted or as a CRC failure, and the segment's
+    /// valid data ends where the entry begins.
+    pub(crate) fn verify_next_entry(&mut self) -> io::Result<bool> {
+        let verification = match self.read_entry() {
+            Ok(Some(mut entry)) => entry.verify_chunks(),
+            Ok(None) => return Ok(false),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        let entry_start = self.valid_until;
+        let is_complete = match verification {
+            Ok(EntryVerification::Complete) => true,
+            Ok(EntryVerification::CrcMismatch) => false,
+            Ok(EntryVerification::Aborted) => {
+                self.aborted_entries += 1;
+                false
+            }
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                self.aborted_entries += 1;
+                self.record_truncation()?;
+                false
+            }
+            Err(err) => return Err(err),
+        };
+        self.seek_to(entry_start)?;
+        Ok(is_complete)
+    }
+
+    /// Reads the length and CRC following the end of the entry starting at
+    /// `valid_until`. Returns false if the length doesn't match the entry.
+    fn read_entry_trailer(&mut self) -> io::Result<bool> {
+        let mut trailer = [0; 12];
+        self.file.read_exact(&mut trailer)?;
+        let length = u64::from_le_bytes(trailer[..8].try_into().expect("u64 is 8 bytes"));
+        let entry_end = self.file.stream_position()? - ENTRY_TRAILER_LENGTH;
+        if length == entry_end - self.valid_until {
+            self.entry_crc = Some(u32::from_le_bytes(
+                trailer[8..].try_into().expect("u32 is 4 bytes"),
+            ));
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Compares the CRC stored after the entry that was just verified against
+    /// the CRC of the entry's bytes, from its header through its end marker,
+    /// which was calculated while its chunks were read. Entries in version 0
+    /// segments have no CRC, and are always complete.
+    fn verify_entry_crc(&mut self) -> EntryVerification {
+        let calculated_crc = self.calculated_entry_crc.take();
+        let stored_crc = match self.entry_crc.take() {
+            Some(crc) => crc,
+            None => return EntryVerification::Complete,
+        };
+
+        if calculated_crc == Some(stored_crc) {
+            EntryVerification::Complete
+        } else {
+            self.crc_failures += 1;
+            self.first_crc_failure.get_or_insert(LogPosition {
+                file_id: self.file_id,
+                offset: self.valid_until,
+            });
+            EntryVerification::CrcMismatch
+        }
+    }
+
+    /// Records that the file ended partway through the entry starting at
+    /// `valid_until`.
+    fn record_truncation(&mut self) -> io::Result<()> {
+        self.truncated_bytes = self.file.get_ref().len()?.saturating_sub(self.valid_until);
+        Ok(())
+    }
+
     /// Reads an entry from the log. If no more entries are found, None is
     /// returned.
     pub fn read_entry(&mut self) -> io::Result<Option<Entry<'_, F>>> {
-        if let Some(id) = self.current_entry_id.take() {
+        if let Some(id) = self.current_entry_id {
             // Skip the remainder of the current entry.
             let mut entry = Entry { id, reader: self };
-            while let Some(chunk) = match entry.read_chunk()? {
-                ReadChunkResult::Chunk(chunk) => Some(chunk),
-                _ => None,
-            } {
-                // skip chunk
-                chunk.skip_remaining_bytes()?;
+            let aborted = loop {
+                match entry.read_chunk() {
+                    Ok(ReadChunkResult::Chunk(chunk)) => chunk.skip_remaining_bytes()?,
+                    Ok(ReadChunkResult::EndOfEntry) => break Ok(false),
+                    Ok(ReadChunkResult::AbortedEntry) => break Ok(true),
+                    Err(err) => break Err(err),
+                }
+            };
+            match aborted {
+                Ok(false) => {}
+                Ok(true) => self.aborted_entries += 1,
+                Err(err) => {
+                    if err.kind() == ErrorKind::UnexpectedEof {
+                        self.aborted_entries += 1;
+                        self.record_truncation()?;
+                    }
+                    return Err(err);
+                }
             }
         }
 
@@ -463,6 +1095,13 @@ where
     /// being written. This allows for very large log entries to be written
     /// without requiring memory for the entire entry.
     pub fn read_chunk<'chunk>(&'chunk mut self) -> io::Result<ReadChunkResult<'chunk, 'entry, F>> {
+        // Once the end of the entry has been read, the bytes that follow
+        // belong to the next entry, or are left over from before the segment
+        // was recycled.
+        if self.reader.current_entry_id != Some(self.id) {
+            return Ok(ReadChunkResult::EndOfEntry);
+        }
+
         if self.reader.file.buffer().is_empty() {
             self.reader.file.fill_buf()?;
         }
@@ -472,6 +1111,7 @@ where
                 let mut header_bytes = [0; 5];
                 let offset = self.reader.file.stream_position()?;
                 self.reader.file.read_exact(&mut header_bytes)?;
+                append_entry_crc(&mut self.reader.calculated_entry_crc, &header_bytes);
                 Ok(ReadChunkResult::Chunk(EntryChunk {
                     position: LogPosition {
                         file_id: self.reader.file_id,
@@ -480,39 +1120,141 @@ where
                     entry: self,
                     calculated_crc: 0,
                     stored_crc32: None,
+                    crc_failure_recorded: false,
                     bytes_remaining: u32::from_le_bytes(
                         header_bytes[1..5].try_into().expect("u32 is 4 bytes"),
                     ),
+                    decoded: None,
+                }))
+            }
+            Some(ENCODED_CHUNK) => {
+                let offset = self.reader.file.stream_position()?;
+                self.reader.file.consume(1);
+                append_entry_crc(&mut self.reader.calculated_entry_crc, &[ENCODED_CHUNK]);
+                let encryption = self.reader.chunk_encryption(offset);
+                let decoded = DecodedChunk::read_from(
+                    EntryCrcReader {
+                        reader: &mut self.reader.file,
+                        crc: &mut self.reader.calculated_entry_crc,
+                    },
+                    encryption.as_ref(),
+                )?;
+                Ok(ReadChunkResult::Chunk(EntryChunk {
+                    position: LogPosition {
+                        file_id: self.reader.file_id,
+                        offset,
+                    },
+                    entry: self,
+                    calculated_crc: decoded.calculated_crc32,
+                    stored_crc32: Some(decoded.stored_crc32),
+                    crc_failure_recorded: false,
+                    bytes_remaining: u32::try_from(decoded.bytes_remaining()).to_io()?,
+                    decoded: Some(decoded),
                 }))
             }
             Some(END_OF_ENTRY) => {
                 self.reader.file.consume(1);
+                append_entry_crc(&mut self.reader.calculated_entry_crc, &[END_OF_ENTRY]);
+                self.reader.current_entry_id = None;
+                // A length that doesn't match means the entry's chunks weren't
+                // framed the way they were written.
+                if self.reader.header.format_version >= 1 && !self.reader.read_entry_trailer()? {
+                    return Ok(ReadChunkResult::AbortedEntry);
+                }
                 Ok(ReadChunkResult::EndOfEntry)
             }
             _ => Ok(ReadChunkResult::AbortedEntry),
         }
     }
 
+    /// Reads the remaining chunks of this entry, verifying their CRCs and the
+    /// entry's CRC. The entry must not have had any chunks read yet.
+    pub(crate) fn verify_chunks(&mut self) -> io::Result<EntryVerification> {
+        let mut header = [NEW_ENTRY; 9];
+        header[1..].copy_from_slice(&self.id.0.to_le_bytes());
+        self.reader.calculated_entry_crc = Some(crc32c::crc32c(&header));
+        loop {
+            let mut chunk = match self.read_chunk()? {
+                ReadChunkResult::Chunk(chunk) => chunk,
+                ReadChunkResult::EndOfEntry => break,
+                ReadChunkResult::AbortedEntry => return Ok(EntryVerification::Aborted),
+            };
+            io::copy(&mut chunk, &mut io::sink())?;
+            if chunk.bytes_remaining() > 0 {
+                return Ok(EntryVerification::Aborted);
+            } else if !chunk.check_crc()? {
+                return Ok(EntryVerification::CrcMismatch);
+            }
+        }
+        Ok(self.reader.verify_entry_crc())
+    }
+
     /// Reads all chunks for this entry. If the entry was completely written,
     /// the list of chunks of data is returned. If the entry wasn't completely
     /// written, `None` will be returned.
     pub fn read_all_chunks(&mut self) -> io::Result<Option<Vec<Vec<u8>>>> {
         let mut chunks = Vec::new();
         loop {
-            let mut chunk = match self.read_chunk()? {
-                ReadChunkResult::Chunk(chunk) => chunk,
-                ReadChunkResult::EndOfEntry => break,
-                ReadChunkResult::AbortedEntry => return Ok(None),
+            let mut chunk = match self.read_chunk() {
+                Ok(ReadChunkResult::Chunk(chunk)) => chunk,
+                Ok(ReadChunkResult::EndOfEntry) => break,
+                Ok(ReadChunkResult::AbortedEntry) => return Ok(None),
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(err) => return Err(err),
             };
             chunks.push(chunk.read_all()?);
-            if !chunk.check_crc()? {
-                return Err(io::Error::new(ErrorKind::InvalidData, "crc check failed"));
+            if chunk.bytes_remaining() > 0 {
+                return Ok(None);
+            }
+            match chunk.check_crc() {
+                Ok(true) => {}
+                Ok(false) => {
+                    return Err(Error::CrcMismatch {
+                        position: chunk.log_position(),
+                    }
+                    .into())
+                }
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(err) => return Err(err),
             }
         }
         Ok(Some(chunks))
     }
 }
 
+/// The result of [`Entry::verify_chunks()`].
+pub(crate) enum EntryVerification {
+    /// Every chunk was read and their CRCs matched, as did the entry's CRC.
+    Complete,
+    /// The entry ended before it was completely written, or its length
+    /// didn't match the length stored after it.
+    Aborted,
+    /// A chunk's or the entry's CRC didn't match.
+    CrcMismatch,
+}
+
+/// Appends `bytes` to the CRC of the entry being verified, if any.
+fn append_entry_crc(crc: &mut Option<u32>, bytes: &[u8]) {
+    if let Some(crc) = crc {
+        *crc = crc32c::crc32c_append(*crc, bytes);
+    }
+}
+
+/// Reads from `reader`, appending the bytes read to the CRC of the entry
+/// being verified.
+struct EntryCrcReader<'a, R> {
+    reader: R,
+    crc: &'a mut Option<u32>,
+}
+
+impl<R: Read> Read for EntryCrcReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.reader.read(buf)?;
+        append_entry_crc(self.crc, &buf[..bytes_read]);
+        Ok(bytes_read)
+    }
+}
+
 /// The result of reading a chunk from a log segment.
 #[derive(Debug)]
 pub enum ReadChunkResult<'chunk, 'entry, F>
@@ -556,6 +1298,12 @@ where
     bytes_remaining: u32,
     calculated_crc: u32,
     stored_crc32: Option<u32>,
+    /// Whether a CRC mismatch has been counted in
+    /// [`SegmentReader::crc_failures`].
+    crc_failure_recorded: bool,
+    /// The contents of a compressed chunk, which is read and decompressed in
+    /// its entirety when the chunk is read.
+    decoded: Option<DecodedChunk>,
 }
 
 impl<'chunk, 'entry, F> EntryChunk<'chunk, 'entry, F>
@@ -584,10 +1332,20 @@ where
                 // Bypass our internal read, otherwise our CRC would include the
                 // CRC read itself.
                 self.entry.reader.file.read_exact(&mut stored_crc32)?;
+                append_entry_crc(&mut self.entry.reader.calculated_entry_crc, &stored_crc32);
                 self.stored_crc32 = Some(u32::from_le_bytes(stored_crc32));
             }
 
-            Ok(self.stored_crc32.expect("already initialized") == self.calculated_crc)
+            let is_valid = self.stored_crc32.expect("already initialized") == self.calculated_crc;
+            if !is_valid && !self.crc_failure_recorded {
+                self.crc_failure_recorded = true;
+                self.entry.reader.crc_failures += 1;
+                self.entry
+                    .reader
+                    .first_crc_failure
+                    .get_or_insert(self.position);
+            }
+            Ok(is_valid)
         } else {
             Err(io::Error::new(
                 io::ErrorKind::Other,
@@ -598,7 +1356,13 @@ where
 
     /// Reads all of the remaining data from this chunk.
     pub fn read_all(&mut self) -> io::Result<Vec<u8>> {
-        let mut data = Vec::with_capacity(usize::try_from(self.bytes_remaining).to_io()?);
+        // The length hasn't been verified by the crc yet, so a corrupted
+        // length must not cause a huge allocation.
+        let mut data = Vec::with_capacity(
+            usize::try_from(self.bytes_remaining)
+                .to_io()?
+                .min(MAX_PREALLOCATED_CHUNK_BYTES),
+        );
         self.read_to_end(&mut data)?;
         Ok(data)
     }
@@ -610,13 +1374,19 @@ where
 
     /// Advances past the end of this chunk without reading the remaining bytes.
     fn skip_remaining_bytes_internal(&mut self) -> io::Result<()> {
-        if self.bytes_remaining > 0 || self.stored_crc32.is_none() {
+        if self.decoded.is_some() {
+            // Compressed chunks are read from the file in their entirety.
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining > 0 || self.stored_crc32.is_none() {
             // Skip past the remaining bytes plus the crc.
             self.entry
                 .reader
                 .file
-                .seek(SeekFrom::Current(i64::from(self.bytes_remaining + 4)))?;
+                .seek(SeekFrom::Current(i64::from(self.bytes_remaining) + 4))?;
             self.bytes_remaining = 0;
+            // The crc has been skipped, so it must not be skipped again when
+            // this chunk is dropped.
+            self.stored_crc32 = Some(self.calculated_crc);
         }
         Ok(())
     }
@@ -630,10 +1400,18 @@ where
         let bytes_remaining = usize::try_from(self.bytes_remaining).to_io()?;
         let bytes_to_read = bytes_remaining.min(buf.len());
 
-        if bytes_to_read > 0 {
+        if let Some(decoded) = &mut self.decoded {
+            let bytes_read = decoded.read(&mut buf[..bytes_to_read])?;
+            self.bytes_remaining -= u32::try_from(bytes_read).to_io()?;
+            Ok(bytes_read)
+        } else if bytes_to_read > 0 {
             let bytes_read = self.entry.reader.file.read(&mut buf[..bytes_to_read])?;
             self.bytes_remaining -= u32::try_from(bytes_read).to_io()?;
             self.calculated_crc = crc32c::crc32c_append(self.calculated_crc, &buf[..bytes_read]);
+            append_entry_crc(
+                &mut self.entry.reader.calculated_entry_crc,
+                &buf[..bytes_read],
+            );
             Ok(bytes_read)
         } else {
             Ok(0)
@@ -658,4 +1436,68 @@ pub struct RecoveredSegment {
     /// The value of [`Configuration::version_info`] at the time this segment
     /// was created.
     pub version_info: Vec<u8>,
+    /// The id of the key this segment's chunks are encrypted with, if the
+    /// segment was written with a [`Cipher`].
+    pub key_id: Option<u32>,
+    /// The version of the format the segment was written in.
+    pub format_version: u8,
+}
+
+/// Reads a segment header from the start of `file`, leaving `file` positioned
+/// after it.
+pub(crate) fn read_header<R: Read>(file: &mut BufReader<R>) -> io::Result<RecoveredSegment> {
+    let mut buffer = Vec::with_capacity(256 + 5);
+    buffer.resize(5, 0);
+    file.read_exact(&mut buffer)?;
+
+    if &buffer[0..3] != b"okw" {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "segment file did not contain magic code",
+        ));
+    }
+
+    let format_version = buffer[3];
+    if format_version > FORMAT_VERSION {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "segment file was written with a newer version",
+        ));
+    }
+
+    let version_info_length = buffer[4];
+    buffer.resize(usize::from(version_info_length), 0);
+    file.read_exact(&mut buffer)?;
+
+    let key_id = if file.fill_buf()?.first() == Some(&KEY_ID) {
+        let mut key_id = [0; 5];
+        file.read_exact(&mut key_id)?;
+        Some(u32::from_le_bytes(
+            key_id[1..].try_into().expect("u32 is 4 bytes"),
+        ))
+    } else {
+        None
+    };
+
+    Ok(RecoveredSegment {
+        version_info: buffer,
+        key_id,
+        format_version,
+    })
+}
+
+/// Returns true if the segment at `path` starts with a partially written
+/// magic code followed by zeroes, which is left behind when a crash occurs
+/// before a new segment's header is synchronized. Nothing can have been
+/// committed to such a segment.
+pub(crate) fn is_uninitialized<M: FileManager>(path: &PathId, manager: &M) -> io::Result<bool> {
+    let file = manager.open(path, OpenOptions::new().read(true))?;
+    let mut start = Vec::with_capacity(4);
+    file.take(4).read_to_end(&mut start)?;
+    let written = start
+        .iter()
+        .zip(b"okw\0")
+        .take_while(|(byte, expected)| byte == expected)
+        .count();
+    Ok(start[written..].iter().all(|byte| *byte == 0))
 }
@@ -1,6 +1,9 @@
 use std::{fmt::Debug, io};
 
-use file_manager::{fs::StdFileManager, FileManager};
+use file_manager::{
+    fs::{StdFile, StdFileManager},
+    FileManager,
+};
 
 use crate::{
     entry::EntryId,
@@ -51,6 +54,73 @@ where
         checkpointed_entries: &mut SegmentReader<M::File>,
         wal: &WriteAheadLog<M>,
     ) -> io::Result<()>;
+
+    /// Invoked each time one or more segments are ready to be checkpointed.
+    /// `segments` are ordered by the ids of the entries they contain.
+    ///
+    /// When multiple segments are waiting to be checkpointed, up to
+    /// [`Configuration::checkpoint_batch_size`](crate::Configuration::checkpoint_batch_size)
+    /// segments are passed at once, which allows them to be checkpointed
+    /// concurrently.
+    ///
+    /// If this function returns an error, the leading segments that were
+    /// marked using [`SegmentCheckpoint::mark_checkpointed()`] are treated as
+    /// checkpointed, and the remaining segments are checkpointed again as
+    /// described in [`CheckpointRetry`](crate::CheckpointRetry). If it
+    /// succeeds, every segment is treated as checkpointed.
+    ///
+    /// The default implementation passes each segment to
+    /// [`LogManager::checkpoint_to()`] in order, marking each segment as
+    /// checkpointed once it returns.
+    fn checkpoint_to_many(
+        &mut self,
+        segments: &mut [SegmentCheckpoint<M::File>],
+        wal: &WriteAheadLog<M>,
+    ) -> io::Result<()> {
+        for segment in segments {
+            self.checkpoint_to(segment.last_checkpointed_id, &mut segment.entries, wal)?;
+            segment.mark_checkpointed();
+        }
+        Ok(())
+    }
+}
+
+/// A segment passed to [`LogManager::checkpoint_to_many()`].
+#[derive(Debug)]
+pub struct SegmentCheckpoint<F = StdFile>
+where
+    F: file_manager::File,
+{
+    /// The id of the last entry in the segment.
+    pub last_checkpointed_id: EntryId,
+    /// A reader of the entries being checkpointed.
+    pub entries: SegmentReader<F>,
+    checkpointed: bool,
+}
+
+impl<F> SegmentCheckpoint<F>
+where
+    F: file_manager::File,
+{
+    pub(crate) fn new(last_checkpointed_id: EntryId, entries: SegmentReader<F>) -> Self {
+        Self {
+            last_checkpointed_id,
+            entries,
+            checkpointed: false,
+        }
+    }
+
+    /// Records that the entries of this segment have been checkpointed.
+    pub fn mark_checkpointed(&mut self) {
+        self.checkpointed = true;
+    }
+
+    /// Returns true if [`SegmentCheckpoint::mark_checkpointed()`] has been
+    /// called.
+    #[must_use]
+    pub const fn is_checkpointed(&self) -> bool {
+        self.checkpointed
+    }
 }
 
 /// Determines whether to recover a segment or not.
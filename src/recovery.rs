@@ -0,0 +1,347 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use file_manager::{File, FileManager, OpenOptions, PathId};
+
+use log::warn;
+
+use crate::{
+    entry::NEW_ENTRY, log_file::EntryVerification, Configuration, EntryId, Error, LogPosition,
+    SegmentReader,
+};
+
+/// How a [`WriteAheadLog`](crate::WriteAheadLog) handles a segment whose
+/// entries end before data that looks like more entries.
+///
+/// While recovering a segment, entries are read until one can't be read,
+/// which normally happens at the end of the entries written before the log
+/// was closed or crashed. Everything after that point is treated as free
+/// space, and is overwritten by new entries. If the bytes of an entry are
+/// corrupted, the complete entries that follow it are treated as free space
+/// as well.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RecoveryMode {
+    /// Recover the entries before the first one that can't be read, and
+    /// discard everything after it. This is the default.
+    Tolerant,
+    /// Refuse to open the log if a complete entry with valid CRCs is found
+    /// after the first entry that can't be read, or if the log manager found
+    /// a chunk whose CRC doesn't match in an entry it recovered. Opening the
+    /// log returns
+    /// [`Error::SegmentCorrupted`](crate::Error::SegmentCorrupted) or
+    /// [`Error::CrcMismatch`](crate::Error::CrcMismatch).
+    ///
+    /// An entry that can't be read at the end of a segment is expected after
+    /// a crash, and is discarded.
+    ///
+    /// Each segment's free space is scanned for entries, which makes opening
+    /// the log slower.
+    Strict,
+    /// Skip over the bytes that can't be read to the next complete entry with
+    /// valid CRCs, and recover it and the entries that follow it.
+    ///
+    /// The segment is rewritten with the recovered entries moved to where the
+    /// unreadable bytes began, so that it can be read without skipping them
+    /// again. The rewritten segment is written to a new file, which replaces
+    /// the segment once it has been synchronized. If salvaging is interrupted,
+    /// the new file is removed when the log is next opened.
+    ///
+    /// Because the entries move, the positions of their chunks change, and the
+    /// [`ChunkRecord`](crate::ChunkRecord)s and
+    /// [`LogPosition`](crate::LogPosition)s saved when they were written can
+    /// no longer be used to read them. The new positions are passed to
+    /// [`LogManager::recover()`](crate::LogManager::recover).
+    ///
+    /// Because the chunks of an encrypted segment can only be decrypted at
+    /// the offset they were written at, an encrypted segment can't be
+    /// salvaged, and opening the log returns
+    /// [`Error::SegmentCorrupted`](crate::Error::SegmentCorrupted) instead.
+    Salvage,
+}
+
+impl Default for RecoveryMode {
+    fn default() -> Self {
+        Self::Tolerant
+    }
+}
+
+/// What was found while recovering a [`WriteAheadLog`](crate::WriteAheadLog),
+/// returned from
+/// [`Configuration::open_with_report()`](crate::Configuration::open_with_report).
+///
+/// Recovery never fails because of a crash while the log was being written.
+/// Instead, the data that couldn't be recovered is discarded, and this report
+/// describes what was discarded.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct RecoveryReport {
+    /// The segments whose entries were passed to
+    /// [`LogManager::recover()`](crate::LogManager::recover), ordered by their
+    /// ids.
+    pub segments: Vec<SegmentRecovery>,
+    /// The ids of the segments that weren't recovered because
+    /// [`LogManager::should_recover_segment()`](crate::LogManager::should_recover_segment)
+    /// returned [`Recovery::Abandon`](crate::Recovery::Abandon). These
+    /// segments are reused for new entries.
+    pub abandoned_segments: Vec<u64>,
+    /// The checkpointed segment files that were deleted because there were
+    /// more than
+    /// [`Configuration::max_inactive_files`](crate::Configuration::max_inactive_files).
+    pub deleted_files: Vec<PathId>,
+}
+
+impl RecoveryReport {
+    /// Returns the total number of entries recovered from all segments.
+    #[must_use]
+    pub fn entries_recovered(&self) -> u64 {
+        self.segments
+            .iter()
+            .map(|segment| segment.entries_recovered)
+            .sum()
+    }
+
+    /// Returns true if no data was discarded while recovering the log:
+    /// no segments were abandoned, and every segment's entries were complete
+    /// and had valid CRCs.
+    #[must_use]
+    pub fn is_clean(&self) -> bool {
+        self.abandoned_segments.is_empty() && self.segments.iter().all(SegmentRecovery::is_clean)
+    }
+}
+
+/// What was found while recovering a single segment. See [`RecoveryReport`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct SegmentRecovery {
+    /// The id of the segment.
+    pub id: u64,
+    /// The path of the segment file.
+    pub path: PathId,
+    /// The number of complete entries passed to
+    /// [`LogManager::recover()`](crate::LogManager::recover).
+    pub entries_recovered: u64,
+    /// The number of entries that were discarded because they were never
+    /// completely written, such as an entry that was being written when the
+    /// process crashed. If
+    /// [`Configuration::verify_recovered_chunks`](crate::Configuration::verify_recovered_chunks)
+    /// is disabled, these entries are still passed to
+    /// [`LogManager::recover()`](crate::LogManager::recover), which receives
+    /// [`ReadChunkResult::AbortedEntry`](crate::ReadChunkResult::AbortedEntry)
+    /// while reading them.
+    pub aborted_entries: u64,
+    /// The number of bytes at the end of the segment file that were discarded
+    /// because the file ended partway through an entry. New entries are
+    /// written over these bytes.
+    pub truncated_bytes: u64,
+    /// The number of chunks whose CRC didn't match their contents. The entry
+    /// containing the chunk is discarded if
+    /// [`Configuration::verify_recovered_chunks`](crate::Configuration::verify_recovered_chunks)
+    /// is enabled. Otherwise, this counts the failures found by the log
+    /// manager using [`EntryChunk::check_crc()`](crate::EntryChunk::check_crc)
+    /// or [`Entry::read_all_chunks()`](crate::Entry::read_all_chunks).
+    pub crc_failures: u64,
+    /// The number of unreadable bytes skipped by
+    /// [`RecoveryMode::Salvage`].
+    pub corrupted_bytes: u64,
+    /// The number of entries recovered after skipping unreadable bytes with
+    /// [`RecoveryMode::Salvage`]. These entries are included in
+    /// `entries_recovered`.
+    pub salvaged_entries: u64,
+}
+
+impl SegmentRecovery {
+    /// Returns true if every entry in the segment was complete, no CRC
+    /// failures were found, and no bytes were skipped.
+    #[must_use]
+    pub const fn is_clean(&self) -> bool {
+        self.aborted_entries == 0
+            && self.truncated_bytes == 0
+            && self.crc_failures == 0
+            && self.corrupted_bytes == 0
+    }
+}
+
+/// The entries recovered by [`skip_corruption()`].
+pub(crate) struct Salvaged {
+    /// The number of bytes skipped.
+    pub corrupted_bytes: u64,
+    /// The number of entries moved to where the skipped bytes began.
+    pub entries: u64,
+}
+
+/// Checks whether more entries follow the point where `reader` stopped
+/// reading entries, and handles them according to
+/// [`Configuration::recovery_mode`].
+///
+/// Returns `None` if there is nothing left to recover. If entries were
+/// salvaged, they have been moved to where `reader` stopped, and `reader` has
+/// been reopened and positioned to read them.
+pub(crate) fn skip_corruption<M: FileManager>(
+    reader: &mut SegmentReader<M::File>,
+    path: &PathId,
+    config: &Configuration<M>,
+) -> io::Result<Option<Salvaged>> {
+    // A CRC failure found while verifying an entry ends the readable entries,
+    // and is only an error if more entries follow it, like any other
+    // unreadable entry. One found by the log manager was in an entry it has
+    // already recovered.
+    if config.recovery_mode == RecoveryMode::Strict {
+        if let Some(position) = reader.first_crc_failure {
+            if position.offset < reader.valid_until {
+                return Err(Error::CrcMismatch { position }.into());
+            }
+        }
+    }
+
+    let corrupted_at = reader.valid_until;
+    let offset = match find_entry(
+        path,
+        reader.file_id,
+        corrupted_at,
+        reader.last_entry_id,
+        config,
+    )? {
+        Some(offset) => offset,
+        None => return Ok(None),
+    };
+    if config.recovery_mode != RecoveryMode::Salvage || reader.header.key_id.is_some() {
+        return Err(Error::SegmentCorrupted {
+            position: LogPosition {
+                file_id: reader.file_id,
+                offset: corrupted_at,
+            },
+        }
+        .into());
+    }
+
+    warn!(
+        "Salvaging entries at {}:{offset} after corruption at {corrupted_at}",
+        reader.file_id
+    );
+    let entries = salvage(path, reader.file_id, corrupted_at, offset, config)?;
+    reader.reopen(path, &config.file_manager)?;
+    reader.seek_to(corrupted_at)?;
+    Ok(Some(Salvaged {
+        corrupted_bytes: offset - corrupted_at,
+        entries,
+    }))
+}
+
+/// The number of bytes read at a time while searching for an entry.
+const SCAN_BLOCK: usize = 64 * 1024;
+
+/// Returns the offset of the first complete entry with valid CRCs that starts
+/// at or after `from` in the segment at `path`, and whose id is greater than
+/// `after`.
+fn find_entry<M: FileManager>(
+    path: &PathId,
+    segment_id: u64,
+    from: u64,
+    after: Option<EntryId>,
+    config: &Configuration<M>,
+) -> io::Result<Option<u64>> {
+    let min_id = after.map_or(segment_id, |after| segment_id.max(after.0 + 1));
+    let mut file = config
+        .file_manager
+        .open(path, OpenOptions::new().read(true))?;
+    let mut verifier = SegmentReader::open(path, segment_id, config)?;
+
+    // Each block keeps the last bytes of the previous block, so that entry
+    // headers spanning two blocks are found.
+    let mut block = Vec::with_capacity(SCAN_BLOCK);
+    let mut block_offset = file.seek(SeekFrom::Start(from))?;
+    loop {
+        let carried = block.len();
+        block.resize(carried + SCAN_BLOCK, 0);
+        let bytes_read = file.read(&mut block[carried..])?;
+        block.truncate(carried + bytes_read);
+        if block.len() < 9 {
+            return Ok(None);
+        }
+
+        for index in 0..=block.len() - 9 {
+            if block[index] != NEW_ENTRY {
+                continue;
+            }
+            let id = u64::from_le_bytes(block[index + 1..index + 9].try_into().expect("8 bytes"));
+            let offset = block_offset + index as u64;
+            if id >= min_id && is_complete_entry(&mut verifier, offset)? {
+                return Ok(Some(offset));
+            }
+        }
+
+        if bytes_read == 0 {
+            return Ok(None);
+        }
+        let keep = 8;
+        block_offset += (block.len() - keep) as u64;
+        block.drain(..block.len() - keep);
+    }
+}
+
+/// Returns true if a complete entry with valid CRCs starts at `offset`.
+fn is_complete_entry<F: File>(reader: &mut SegmentReader<F>, offset: u64) -> io::Result<bool> {
+    reader.seek_to(offset)?;
+    Ok(read_complete_entry(reader))
+}
+
+/// Reads the next entry, returning true if it is complete and its CRCs are
+/// valid.
+fn read_complete_entry<F: File>(reader: &mut SegmentReader<F>) -> bool {
+    match reader.read_entry() {
+        Ok(Some(mut entry)) => matches!(entry.verify_chunks(), Ok(EntryVerification::Complete)),
+        Ok(None) | Err(_) => false,
+    }
+}
+
+/// The prefix of the name of the file a segment is salvaged to.
+pub(crate) const SALVAGE_PREFIX: &str = "salvage-";
+
+/// Replaces the segment at `path` with a copy whose bytes starting at `to` are
+/// replaced by the complete entries starting at `from`. Returns the number of
+/// entries moved.
+///
+/// The copy is written to a new file, which is renamed over the segment once
+/// it has been synchronized, so that a crash while salvaging leaves the
+/// segment unchanged.
+fn salvage<M: FileManager>(
+    path: &PathId,
+    segment_id: u64,
+    to: u64,
+    from: u64,
+    config: &Configuration<M>,
+) -> io::Result<u64> {
+    let mut reader = SegmentReader::open(path, segment_id, config)?;
+    reader.seek_to(from)?;
+    let mut entries = 0;
+    let mut end = from;
+    while read_complete_entry(&mut reader) {
+        entries += 1;
+        end = reader.file.stream_position()?;
+    }
+    drop(reader);
+
+    let directory = path.parent().expect("segments are in a directory");
+    let salvage_path = PathId::from(directory.join(format!("{SALVAGE_PREFIX}{segment_id}")));
+    let mut segment = config
+        .file_manager
+        .open(path, OpenOptions::new().read(true))?;
+    let mut salvaged = config.file_manager.open(
+        &salvage_path,
+        OpenOptions::new().create(true).write(true).truncate(true),
+    )?;
+    segment.rewind()?;
+    io::copy(&mut (&mut segment).take(to), &mut salvaged)?;
+    segment.seek(SeekFrom::Start(from))?;
+    io::copy(&mut (&mut segment).take(end - from), &mut salvaged)?;
+    salvaged.flush()?;
+    salvaged.sync_all()?;
+    drop(salvaged);
+    drop(segment);
+
+    config.file_manager.rename(&salvage_path, path.clone())?;
+    config
+        .file_manager
+        .sync_all(&PathId::from(directory.to_path_buf()))?;
+
+    Ok(entries)
+}
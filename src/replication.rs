@@ -0,0 +1,589 @@
+use std::{
+    collections::{btree_map, hash_map, BTreeMap, HashMap},
+    fmt::Debug,
+    io::{self, ErrorKind, Seek, SeekFrom, Write},
+    sync::Arc,
+};
+
+use file_manager::{fs::StdFileManager, File, FileManager, OpenOptions, PathId};
+use log::error;
+use parking_lot::{Condvar, Mutex};
+
+use crate::{
+    directory::DirectoryGuard, list_segments, to_io_result::ToIoResult, upgrade, Configuration,
+    Entry, EntryId, Error, LogManager, ReadChunkResult, RecoveredSegment, Recovery, SegmentFile,
+    SegmentReader, WriteAheadLog,
+};
+
+/// Receives the data written to the segment files of a [`WriteAheadLog`].
+///
+/// Each time bytes are synchronized to a segment file, the newly synchronized
+/// range is queued, and [`Replicator::replicate()`] is invoked with it from a
+/// background thread. Writers do not wait for their entries to be replicated.
+/// Ranges of a single segment are delivered in order, but ranges of different
+/// segments may be interleaved.
+///
+/// If an error is returned, replication stops: no further ranges are
+/// delivered, and the error is returned from
+/// [`WriteAheadLog::replication_error()`] and
+/// [`WriteAheadLog::wait_for_replication()`]. Entries continue to be committed
+/// to the log.
+pub trait Replicator: Send + Sync + Debug + 'static {
+    /// Replicates a range of synchronized bytes.
+    fn replicate(&self, range: &SegmentRange<'_>) -> io::Result<()>;
+}
+
+/// A range of bytes that has been synchronized to a segment file.
+#[derive(Debug, Clone, Copy)]
+pub struct SegmentRange<'a> {
+    /// The id of the segment. Segment files are named `wal-<segment_id>`.
+    pub segment_id: u64,
+    /// The offset within the segment file of the first byte of `data`.
+    pub offset: u64,
+    /// The synchronized bytes. This may be empty when `sealed` is true.
+    pub data: &'a [u8],
+    /// Every entry that ends at or before this offset has been committed.
+    pub committed_through: u64,
+    /// If true, no more entries will be written to this segment. A sealed
+    /// segment is complete once all bytes through `committed_through` have
+    /// been received.
+    pub sealed: bool,
+}
+
+/// A hot standby of a [`WriteAheadLog`].
+///
+/// A follower is opened using [`WriteAheadLog::open_follower()`]. It stores the
+/// segments it receives using the same `wal-<id>` naming as the primary log,
+/// and passes each entry to [`LogManager::recover()`] once the entry has been
+/// committed and fully received. Entries are recovered in order: the entries
+/// of a segment are only recovered once the previous segment has been sealed
+/// and all of its entries have been recovered.
+///
+/// To follow a log in the same process, pass a clone of the follower to
+/// [`Configuration::replicate_to()`]. Otherwise, each [`SegmentRange`] can be
+/// delivered to [`Follower::apply()`] by any other means.
+///
+/// A follower must receive each segment starting at its beginning. It should
+/// either be started in an empty directory before the primary log is first
+/// opened, or in a copy of the primary log's directory.
+///
+/// Received segments are kept until the follower is promoted, at which point
+/// they are checkpointed like any other recovered segment.
+#[derive(Debug, Clone)]
+pub struct Follower<M = StdFileManager>
+where
+    M: FileManager,
+{
+    data: Arc<FollowerData<M>>,
+}
+
+#[derive(Debug)]
+struct FollowerData<M>
+where
+    M: FileManager,
+{
+    config: Configuration<M>,
+    state: Mutex<FollowerState<M>>,
+    _directory: DirectoryGuard,
+}
+
+#[derive(Debug)]
+struct FollowerState<M>
+where
+    M: FileManager,
+{
+    /// The log manager, which is taken once the follower is promoted.
+    manager: Option<Box<dyn LogManager<M>>>,
+    segments: BTreeMap<u64, FollowedSegment>,
+    last_recovered_entry_id: Option<EntryId>,
+}
+
+#[derive(Debug)]
+struct FollowedSegment {
+    path: PathId,
+    received_through: u64,
+    /// `None` when the segment was found on disk while opening the follower,
+    /// in which case every complete entry is considered committed.
+    committed_through: Option<u64>,
+    /// `None` until the segment's header has been read.
+    recovered_through: Option<u64>,
+    sealed: bool,
+    abandoned: bool,
+    complete: bool,
+}
+
+impl<M> Follower<M>
+where
+    M: FileManager,
+{
+    pub(crate) fn open<Manager: LogManager<M>>(
+        config: Configuration<M>,
+        manager: Manager,
+    ) -> io::Result<Self> {
+        if !config.file_manager.exists(&config.directory) {
+            config.file_manager.create_dir_all(&config.directory)?;
+        }
+        let directory = DirectoryGuard::open(&config)?;
+        upgrade::remove_abandoned_files(&config)?;
+
+        let discovered_files = list_segments(&config.file_manager, &config.directory)?
+            .into_iter()
+            .filter(|segment| !segment.checkpointed)
+            .collect::<Vec<_>>();
+
+        // Every segment except the latest one was sealed by the primary.
+        let latest_segment = discovered_files.last().map(|segment| segment.id);
+        let mut segments = BTreeMap::new();
+        for SegmentFile { id, path, .. } in discovered_files {
+            let received_through = config
+                .file_manager
+                .open(&path, OpenOptions::new().read(true))?
+                .len()?;
+            let mut segment = FollowedSegment::new(path);
+            segment.received_through = received_through;
+            segment.committed_through = None;
+            segment.sealed = Some(id) != latest_segment;
+            segments.insert(id, segment);
+        }
+
+        let mut state = FollowerState {
+            manager: Some(Box::new(manager)),
+            segments,
+            last_recovered_entry_id: None,
+        };
+        state.recover_entries(&config)?;
+
+        Ok(Self {
+            data: Arc::new(FollowerData {
+                config,
+                state: Mutex::new(state),
+                _directory: directory,
+            }),
+        })
+    }
+
+    /// Writes `range` to this follower's copy of the segment, and recovers any
+    /// entries that it completes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the range does not continue the data previously
+    /// received for its segment, or if this follower has been promoted.
+    pub fn apply(&self, range: &SegmentRange<'_>) -> io::Result<()> {
+        let config = &self.data.config;
+        let mut state = self.data.state.lock();
+        if state.manager.is_none() {
+            return Err(promoted());
+        }
+
+        let segment = match state.segments.entry(range.segment_id) {
+            btree_map::Entry::Occupied(segment) => segment.into_mut(),
+            btree_map::Entry::Vacant(segment) if range.offset == 0 => {
+                segment.insert(FollowedSegment::new(PathId::from(
+                    config.directory.join(format!("wal-{}", range.segment_id)),
+                )))
+            }
+            // The primary sealed a segment this follower never received data
+            // for, such as one recovered when the primary was opened.
+            btree_map::Entry::Vacant(_) if range.data.is_empty() => return Ok(()),
+            btree_map::Entry::Vacant(_) => return Err(missing_data()),
+        };
+        if range.offset > segment.received_through {
+            return Err(missing_data());
+        }
+
+        let mut file = config.file_manager.open(
+            &segment.path,
+            OpenOptions::new().create(true).write(true).read(true),
+        )?;
+        if range.offset == 0 && segment.received_through == 0 {
+            config.file_manager.sync_all(&config.directory)?;
+        }
+        if !range.data.is_empty() {
+            file.seek(SeekFrom::Start(range.offset))?;
+            file.write_all(range.data)?;
+            file.sync_data()?;
+        }
+        segment.received_through = range.offset + u64::try_from(range.data.len()).to_io()?;
+        segment.committed_through = Some(range.committed_through);
+        segment.sealed |= range.sealed;
+
+        state.recover_entries(config)
+    }
+
+    /// Stops following and opens this follower's directory as a writable
+    /// [`WriteAheadLog`].
+    ///
+    /// Entries already passed to [`LogManager::recover()`] are not recovered
+    /// again. Any other complete entries found in the received segments are
+    /// recovered while the log is opened. Once promoted, all clones of this
+    /// follower return errors from [`Follower::apply()`].
+    pub fn promote(self) -> io::Result<WriteAheadLog<M>> {
+        let mut state = self.data.state.lock();
+        let manager = state.manager.take().ok_or_else(promoted)?;
+        let last_recovered_entry_id = state.last_recovered_entry_id;
+        state.segments.clear();
+        drop(state);
+
+        self.data.config.clone().open(PromotedManager {
+            manager,
+            last_recovered_entry_id,
+        })
+    }
+
+    /// Returns the id of the last entry passed to [`LogManager::recover()`].
+    #[must_use]
+    pub fn last_recovered_entry_id(&self) -> Option<EntryId> {
+        self.data.state.lock().last_recovered_entry_id
+    }
+}
+
+impl<M> Replicator for Follower<M>
+where
+    M: FileManager,
+{
+    fn replicate(&self, range: &SegmentRange<'_>) -> io::Result<()> {
+        self.apply(range)
+    }
+}
+
+impl<M> FollowerState<M>
+where
+    M: FileManager,
+{
+    fn recover_entries(&mut self, config: &Configuration<M>) -> io::Result<()> {
+        let manager = self.manager.as_mut().ok_or_else(promoted)?;
+        for (segment_id, segment) in &mut self.segments {
+            if segment.complete {
+                continue;
+            }
+
+            segment.recover_entries(
+                *segment_id,
+                manager.as_mut(),
+                &mut self.last_recovered_entry_id,
+                config,
+            )?;
+            if !segment.complete {
+                // Later segments must wait until this segment is complete.
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FollowedSegment {
+    const fn new(path: PathId) -> Self {
+        Self {
+            path,
+            received_through: 0,
+            committed_through: Some(0),
+            recovered_through: None,
+            sealed: false,
+            abandoned: false,
+            complete: false,
+        }
+    }
+
+    fn recover_entries<M: FileManager>(
+        &mut self,
+        segment_id: u64,
+        manager: &mut dyn LogManager<M>,
+        last_recovered_entry_id: &mut Option<EntryId>,
+        config: &Configuration<M>,
+    ) -> io::Result<()> {
+        let mut reader = match SegmentReader::open(&self.path, segment_id, config) {
+            Ok(reader) => reader,
+            // The header hasn't been fully received yet.
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let mut position = if let Some(position) = self.recovered_through {
+            reader.file.seek(SeekFrom::Start(position))?
+        } else {
+            if let Recovery::Abandon = manager.should_recover_segment(&reader.header)? {
+                self.abandoned = true;
+            }
+            reader.valid_until
+        };
+
+        let readable_through = self
+            .committed_through
+            .map_or(self.received_through, |committed| {
+                committed.min(self.received_through)
+            });
+        while !self.abandoned && position < readable_through {
+            // Verify the entry has been completely received before passing it
+            // to the manager.
+            reader.current_entry_id = None;
+            let is_complete = match reader.read_entry() {
+                Ok(Some(mut entry)) => matches!(entry.read_all_chunks(), Ok(Some(_))),
+                Ok(None) | Err(_) => false,
+            };
+            let end = reader.file.stream_position()?;
+            if !is_complete || end > readable_through {
+                if self
+                    .committed_through
+                    .map_or(false, |committed| committed <= self.received_through)
+                {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "committed entry could not be read from replicated segment",
+                    ));
+                }
+                break;
+            }
+
+            reader.file.seek(SeekFrom::Start(position))?;
+            reader.current_entry_id = None;
+            let mut entry = reader.read_entry()?.ok_or_else(|| {
+                io::Error::new(ErrorKind::InvalidData, "replicated entry changed")
+            })?;
+            let entry_id = entry.id();
+            recover_entry(manager, &mut entry)?;
+            *last_recovered_entry_id = Some(entry_id);
+            position = reader.file.seek(SeekFrom::Start(end))?;
+        }
+        self.recovered_through = Some(position);
+
+        if self.committed_through.is_none() {
+            // The entries found on disk are all that was received.
+            self.received_through = position;
+            self.committed_through = Some(position);
+        }
+        self.complete = self.sealed
+            && self.committed_through.map_or(false, |committed| {
+                self.received_through >= committed && (self.abandoned || position >= committed)
+            });
+
+        Ok(())
+    }
+}
+
+fn recover_entry<M: FileManager>(
+    manager: &mut dyn LogManager<M>,
+    entry: &mut Entry<'_, M::File>,
+) -> io::Result<()> {
+    manager.recover(entry)?;
+    while let Some(chunk) = match entry.read_chunk()? {
+        ReadChunkResult::Chunk(chunk) => Some(chunk),
+        ReadChunkResult::EndOfEntry | ReadChunkResult::AbortedEntry => None,
+    } {
+        chunk.skip_remaining_bytes()?;
+    }
+    Ok(())
+}
+
+/// Forwards to the manager of a promoted [`Follower`], skipping entries the
+/// follower already recovered.
+#[derive(Debug)]
+struct PromotedManager<M>
+where
+    M: FileManager,
+{
+    manager: Box<dyn LogManager<M>>,
+    last_recovered_entry_id: Option<EntryId>,
+}
+
+impl<M> LogManager<M> for PromotedManager<M>
+where
+    M: FileManager,
+{
+    fn should_recover_segment(&mut self, segment: &RecoveredSegment) -> io::Result<Recovery> {
+        self.manager.should_recover_segment(segment)
+    }
+
+    fn recover(&mut self, entry: &mut Entry<'_, M::File>) -> io::Result<()> {
+        if self
+            .last_recovered_entry_id
+            .map_or(false, |last_recovered| entry.id() <= last_recovered)
+        {
+            Ok(())
+        } else {
+            self.manager.recover(entry)
+        }
+    }
+
+    fn checkpoint_to(
+        &mut self,
+        last_checkpointed_id: EntryId,
+        checkpointed_entries: &mut SegmentReader<M::File>,
+        wal: &WriteAheadLog<M>,
+    ) -> io::Result<()> {
+        self.manager
+            .checkpoint_to(last_checkpointed_id, checkpointed_entries, wal)
+    }
+}
+
+/// Delivers the ranges synchronized to a log's segment files to its
+/// [`Replicator`] from a background thread.
+#[derive(Debug)]
+pub(crate) struct ReplicationQueue<F>
+where
+    F: File,
+{
+    sender: flume::Sender<QueuedRange<F>>,
+    progress: Arc<ReplicationProgress>,
+}
+
+/// A range of a segment that has been synchronized, waiting to be replicated.
+#[derive(Debug)]
+pub(crate) struct QueuedRange<F> {
+    /// A handle to the segment to read the range from, which is `None` if the
+    /// range is empty. The handle is shared by the segment's ranges, and is
+    /// only used by the replication thread.
+    pub file: Option<Arc<Mutex<F>>>,
+    pub segment_id: u64,
+    pub offset: u64,
+    pub length: u64,
+    pub committed_through: u64,
+    pub sealed: bool,
+}
+
+#[derive(Debug, Default)]
+struct ReplicationProgress {
+    state: Mutex<ProgressState>,
+    sync: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct ProgressState {
+    queued: u64,
+    delivered: u64,
+    /// The number of queued ranges of each segment.
+    pending: HashMap<u64, usize>,
+    error: Option<Error>,
+}
+
+impl<F> ReplicationQueue<F>
+where
+    F: File,
+{
+    /// Starts the thread that delivers queued ranges to `replicator`.
+    pub fn spawn(replicator: Arc<dyn Replicator>) -> io::Result<Self> {
+        let (sender, receiver) = flume::unbounded();
+        let progress = Arc::new(ReplicationProgress::default());
+        let thread_progress = progress.clone();
+        std::thread::Builder::new()
+            .name(String::from("okaywal-replicate"))
+            .spawn(move || {
+                // The thread stops once every writer has dropped the queue.
+                for range in receiver {
+                    thread_progress.deliver(range, replicator.as_ref());
+                }
+            })?;
+        Ok(Self { sender, progress })
+    }
+
+    /// Queues `range` to be replicated. Once replication has failed, ranges
+    /// are discarded.
+    pub fn enqueue(&self, range: QueuedRange<F>) {
+        let mut state = self.progress.state.lock();
+        if state.error.is_some() {
+            return;
+        }
+        let segment_id = range.segment_id;
+        if self.sender.send(range).is_ok() {
+            state.queued += 1;
+            *state.pending.entry(segment_id).or_default() += 1;
+        }
+    }
+
+    /// Stops replication because of `error`.
+    pub fn fail(&self, error: io::Error) {
+        self.progress.fail(error);
+    }
+
+    /// Returns the error that stopped replication, if any.
+    pub fn error(&self) -> Option<Error> {
+        self.progress.state.lock().error.clone()
+    }
+
+    /// Blocks until every range queued before this call has been replicated.
+    pub fn wait(&self) -> io::Result<()> {
+        let mut state = self.progress.state.lock();
+        let target = state.queued;
+        while state.delivered < target && state.error.is_none() {
+            self.progress.sync.wait(&mut state);
+        }
+        match &state.error {
+            Some(error) => Err(error.clone().into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Blocks until no ranges of the segment with `segment_id` are waiting to
+    /// be replicated. This must be done before the segment's bytes are
+    /// overwritten.
+    pub fn wait_for_segment(&self, segment_id: u64) {
+        let mut state = self.progress.state.lock();
+        while state.pending.contains_key(&segment_id) {
+            self.progress.sync.wait(&mut state);
+        }
+    }
+}
+
+impl ReplicationProgress {
+    fn deliver<F: File>(&self, mut range: QueuedRange<F>, replicator: &dyn Replicator) {
+        if self.state.lock().error.is_none() {
+            if let Err(err) = Self::replicate(range.file.take(), &range, replicator) {
+                self.fail(err);
+            }
+        }
+
+        let mut state = self.state.lock();
+        state.delivered += 1;
+        if let hash_map::Entry::Occupied(mut pending) = state.pending.entry(range.segment_id) {
+            *pending.get_mut() -= 1;
+            if *pending.get() == 0 {
+                pending.remove();
+            }
+        }
+        drop(state);
+        self.sync.notify_all();
+    }
+
+    fn replicate<F: File>(
+        file: Option<Arc<Mutex<F>>>,
+        range: &QueuedRange<F>,
+        replicator: &dyn Replicator,
+    ) -> io::Result<()> {
+        let mut data = vec![0; usize::try_from(range.length).to_io()?];
+        if let Some(file) = file {
+            let mut file = file.lock();
+            file.seek(SeekFrom::Start(range.offset))?;
+            file.read_exact(&mut data)?;
+        }
+
+        replicator.replicate(&SegmentRange {
+            segment_id: range.segment_id,
+            offset: range.offset,
+            data: &data,
+            committed_through: range.committed_through,
+            sealed: range.sealed,
+        })
+    }
+
+    fn fail(&self, error: io::Error) {
+        error!("Replication failed, no further ranges will be replicated: {error:?}");
+        let mut state = self.state.lock();
+        state
+            .error
+            .get_or_insert_with(|| Error::ReplicationFailed(Arc::new(error)));
+        drop(state);
+        self.sync.notify_all();
+    }
+}
+
+fn missing_data() -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        "follower is missing data preceding the replicated range",
+    )
+}
+
+fn promoted() -> io::Error {
+    io::Error::new(ErrorKind::Other, "follower has been promoted")
+}
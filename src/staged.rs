@@ -0,0 +1,270 @@
+use std::io::{self, Write};
+
+use crc32c::crc32c_append;
+use file_manager::FileManager;
+
+use crate::{
+    codec,
+    entry::{self, CHUNK},
+    log_file::LogFileWriter,
+    to_io_result::ToIoResult,
+    ChunkRecord, CommittedEntry, Compression, Configuration, EntryId, Error, LogPosition,
+    WriteAheadLog,
+};
+
+/// A writer for an entry that is staged in memory until it is committed.
+///
+/// Any number of staged writers can be active for a given [`WriteAheadLog`]
+/// at any given time. See [`WriteAheadLog::begin_staged_entry()`] for more
+/// information.
+#[derive(Debug)]
+#[must_use = "staged entries are discarded unless committed"]
+pub struct StagedEntryWriter<'a, M>
+where
+    M: FileManager,
+{
+    log: &'a WriteAheadLog<M>,
+    chunks: StagedChunks,
+}
+
+impl<'a, M> StagedEntryWriter<'a, M>
+where
+    M: FileManager,
+{
+    pub(crate) fn new(log: &'a WriteAheadLog<M>) -> Self {
+        Self {
+            log,
+            chunks: StagedChunks::new(&log.data.config),
+        }
+    }
+
+    /// Appends a chunk of data to this staged entry.
+    ///
+    /// The [`ChunkRecord`] for this chunk is returned in the
+    /// [`CommittedEntry`] once the entry is committed.
+    pub fn write_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        self.chunks.write_chunk(data)
+    }
+
+    /// Begins writing a chunk with the given `length`.
+    ///
+    /// The returned writer appends directly to this entry's staging buffer.
+    /// This function can be used to write a complex payload without needing to
+    /// first combine it in another buffer.
+    pub fn begin_chunk(&mut self, length: u32) -> StagedChunkWriter<'_> {
+        self.chunks.begin_chunk(length)
+    }
+
+    /// Commits this entry to the log. Once this call returns, all data is
+    /// atomically updated and synchronized to disk.
+    ///
+    /// The active file is locked only while the staged bytes are copied into
+    /// it.
+    pub fn commit(self) -> io::Result<CommittedEntry> {
+        self.log.commit_staged(&self.chunks, false)
+    }
+
+    /// Commits this entry to the log and forces a checkpoint to happen.
+    ///
+    /// See [`StagedEntryWriter::commit()`].
+    pub fn commit_and_checkpoint(self) -> io::Result<CommittedEntry> {
+        self.log.commit_staged(&self.chunks, true)
+    }
+
+    /// Abandons this entry. Because staged entries are not written to the log
+    /// until they are committed, this simply discards the staged data. This is
+    /// automatically done when dropped.
+    pub fn rollback(self) {}
+}
+
+/// A writer for a chunk of a [`StagedEntryWriter`].
+///
+/// If this writer is dropped before [`StagedChunkWriter::finish()`] is called,
+/// the partially written chunk is removed from the entry.
+#[derive(Debug)]
+pub struct StagedChunkWriter<'a> {
+    chunks: &'a mut StagedChunks,
+    offset: usize,
+    length: u32,
+    bytes_remaining: u32,
+    crc32: u32,
+    finished: bool,
+}
+
+impl StagedChunkWriter<'_> {
+    /// Finishes writing this chunk.
+    ///
+    /// Returns an error if the number of bytes written does not match the
+    /// length the chunk was started with.
+    pub fn finish(mut self) -> io::Result<()> {
+        if self.bytes_remaining != 0 {
+            return Err(Error::ChunkLengthMismatch.into());
+        }
+
+        // Encryption depends on where the chunk is written, so encrypted
+        // chunks are left unframed until the entry is committed.
+        if self.chunks.encrypted {
+            // Nothing to do until then.
+        } else if self.chunks.compression == Compression::None {
+            self.chunks
+                .bytes
+                .extend_from_slice(&self.crc32.to_le_bytes());
+        } else {
+            // The uncompressed data was staged in place of the chunk, and is
+            // replaced by the framed chunk.
+            let data = self.chunks.bytes.split_off(self.offset);
+            self.crc32 =
+                codec::write_chunk(self.chunks.compression, None, &data, &mut self.chunks.bytes)?;
+        }
+        self.chunks.chunks.push(StagedChunk {
+            offset: self.offset,
+            crc: self.crc32,
+            length: self.length,
+        });
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl Write for StagedChunkWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bytes_to_write = buf
+            .len()
+            .min(usize::try_from(self.bytes_remaining).to_io()?);
+
+        self.chunks.bytes.extend_from_slice(&buf[..bytes_to_write]);
+        self.bytes_remaining -= u32::try_from(bytes_to_write).to_io()?;
+        self.crc32 = crc32c_append(self.crc32, &buf[..bytes_to_write]);
+        Ok(bytes_to_write)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for StagedChunkWriter<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.chunks.bytes.truncate(self.offset);
+        }
+    }
+}
+
+/// The framed chunks of an entry that has not been written to a log file yet.
+///
+/// When the log is encrypted, only the chunks' data is staged, and each chunk
+/// is framed as it is written to the log file.
+#[derive(Debug)]
+pub(crate) struct StagedChunks {
+    bytes: Vec<u8>,
+    chunks: Vec<StagedChunk>,
+    compression: Compression,
+    encrypted: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct StagedChunk {
+    offset: usize,
+    crc: u32,
+    length: u32,
+}
+
+impl StagedChunks {
+    pub fn new<M: FileManager>(config: &Configuration<M>) -> Self {
+        Self {
+            bytes: Vec::new(),
+            chunks: Vec::new(),
+            compression: config.compression,
+            encrypted: config.cipher.is_some(),
+        }
+    }
+
+    pub fn write_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut writer = self.begin_chunk(u32::try_from(data.len()).to_io()?);
+        writer.write_all(data)?;
+        writer.finish()
+    }
+
+    pub fn begin_chunk(&mut self, length: u32) -> StagedChunkWriter<'_> {
+        let offset = self.bytes.len();
+        // When compressing, the chunk is framed once all of its data has been
+        // written.
+        if self.compression == Compression::None && !self.encrypted {
+            self.bytes.push(CHUNK);
+            self.bytes.extend_from_slice(&length.to_le_bytes());
+        }
+        StagedChunkWriter {
+            chunks: self,
+            offset,
+            length,
+            bytes_remaining: length,
+            crc32: 0,
+            finished: false,
+        }
+    }
+
+    /// Writes these chunks to `file` as a complete entry with the given `id`.
+    pub fn write_to<F: file_manager::File>(
+        &self,
+        file: &mut LogFileWriter<F>,
+        id: EntryId,
+    ) -> io::Result<CommittedEntry> {
+        if self.encrypted {
+            return self.encrypt_to(file, id);
+        }
+
+        entry::write_entry_header(file, id)?;
+        let file_id = file.id();
+        let start = file.position();
+        file.write_all(&self.bytes)?;
+        file.end_entry()?;
+
+        let chunks = self
+            .chunks
+            .iter()
+            .map(|chunk| {
+                Ok(ChunkRecord {
+                    position: LogPosition {
+                        file_id,
+                        offset: start + u64::try_from(chunk.offset).to_io()?,
+                    },
+                    crc: chunk.crc,
+                    length: chunk.length,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(CommittedEntry { id, chunks })
+    }
+
+    /// Frames and encrypts each staged chunk while writing it to `file`.
+    fn encrypt_to<F: file_manager::File>(
+        &self,
+        file: &mut LogFileWriter<F>,
+        id: EntryId,
+    ) -> io::Result<CommittedEntry> {
+        entry::write_entry_header(file, id)?;
+        let chunks = self
+            .chunks
+            .iter()
+            .map(|chunk| {
+                let data = &self.bytes
+                    [chunk.offset..chunk.offset + usize::try_from(chunk.length).to_io()?];
+                let position = LogPosition {
+                    file_id: file.id(),
+                    offset: file.position(),
+                };
+                let encryption = file.chunk_encryption();
+                let crc =
+                    codec::write_chunk(self.compression, encryption.as_ref(), data, &mut *file)?;
+                Ok(ChunkRecord {
+                    position,
+                    crc,
+                    length: chunk.length,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        file.end_entry()?;
+        Ok(CommittedEntry { id, chunks })
+    }
+}
@@ -0,0 +1,174 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// A snapshot of a [`WriteAheadLog`](crate::WriteAheadLog)'s activity since it
+/// was opened, returned from
+/// [`WriteAheadLog::stats()`](crate::WriteAheadLog::stats).
+///
+/// All counters only increase, which allows rates to be computed by comparing
+/// two snapshots.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct Stats {
+    /// The number of entries committed.
+    pub entries_committed: u64,
+    /// The total time spent writing the committed entries. Each entry is
+    /// measured from when it was given the active segment until it was
+    /// synchronized to disk.
+    pub entry_write_time: Duration,
+    /// The number of times a segment was asked to be synchronized to disk.
+    pub sync_requests: u64,
+    /// The number of times a segment was synchronized to disk. Requests made
+    /// while another thread is synchronizing the same segment are satisfied
+    /// by that thread, which makes this lower than `sync_requests`.
+    pub fsyncs: u64,
+    /// The total time spent synchronizing segments to disk.
+    pub fsync_time: Duration,
+    /// The number of commits made durable by the `fsyncs`. Entries written
+    /// using [`WriteAheadLog::append_batch()`](crate::WriteAheadLog::append_batch)
+    /// are counted as a single commit.
+    pub synchronized_commits: u64,
+    /// The number of segments that have been checkpointed.
+    pub checkpoints: u64,
+    /// The total time spent checkpointing segments, including the time spent
+    /// in [`LogManager::checkpoint_to()`](crate::LogManager::checkpoint_to).
+    pub checkpoint_time: Duration,
+    /// The number of segments waiting to be checkpointed.
+    pub pending_checkpoints: usize,
+    /// The number of times the log's directory was synchronized to disk.
+    pub directory_syncs: u64,
+    /// The segment files of the log, ordered by their ids.
+    pub segments: Vec<SegmentStats>,
+}
+
+impl Stats {
+    /// Returns the average time spent writing each committed entry, or `None`
+    /// if no entries have been committed.
+    #[must_use]
+    pub fn average_entry_write_time(&self) -> Option<Duration> {
+        let nanos = u64::try_from(self.entry_write_time.as_nanos()).unwrap_or(u64::MAX);
+        nanos
+            .checked_div(self.entries_committed)
+            .map(Duration::from_nanos)
+    }
+
+    /// Returns the average number of synchronization requests satisfied by
+    /// each call to `fsync`, or `None` if no segments have been synchronized.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)] // Precision is not needed for a ratio.
+    pub fn fsync_coalescing_ratio(&self) -> Option<f64> {
+        if self.fsyncs == 0 {
+            None
+        } else {
+            Some(self.sync_requests as f64 / self.fsyncs as f64)
+        }
+    }
+
+    /// Returns the average number of commits made durable by each call to
+    /// `fsync`, or `None` if no segments have been synchronized.
+    ///
+    /// This can be used to tune
+    /// [`Configuration::group_commit_window`](crate::Configuration::group_commit_window).
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)] // Precision is not needed for a ratio.
+    pub fn commits_per_fsync(&self) -> Option<f64> {
+        if self.fsyncs == 0 {
+            None
+        } else {
+            Some(self.synchronized_commits as f64 / self.fsyncs as f64)
+        }
+    }
+}
+
+/// Information about a segment file in [`Stats`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct SegmentStats {
+    /// The id of the segment.
+    pub id: u64,
+    /// The number of bytes written to the segment, including its header.
+    pub bytes: u64,
+    /// The number of readers currently reading from the segment.
+    pub readers: usize,
+    /// True if the segment has been checkpointed and is waiting to be reused.
+    pub checkpointed: bool,
+}
+
+/// Counters for activity that isn't specific to a segment file.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    entries_committed: AtomicU64,
+    entry_write_nanos: AtomicU64,
+    checkpoints: AtomicU64,
+    checkpoint_nanos: AtomicU64,
+    directory_syncs: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record_entries(&self, count: u64, elapsed: Duration) {
+        self.entries_committed.fetch_add(count, Ordering::Relaxed);
+        add_duration(&self.entry_write_nanos, elapsed);
+    }
+
+    pub fn record_checkpoint(&self, elapsed: Duration) {
+        self.checkpoints.fetch_add(1, Ordering::Relaxed);
+        add_duration(&self.checkpoint_nanos, elapsed);
+    }
+
+    pub fn record_directory_sync(&self) {
+        self.directory_syncs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Copies these counters into `stats`.
+    pub fn fill(&self, stats: &mut Stats) {
+        stats.entries_committed = self.entries_committed.load(Ordering::Relaxed);
+        stats.entry_write_time = load_duration(&self.entry_write_nanos);
+        stats.checkpoints = self.checkpoints.load(Ordering::Relaxed);
+        stats.checkpoint_time = load_duration(&self.checkpoint_nanos);
+        stats.directory_syncs = self.directory_syncs.load(Ordering::Relaxed);
+    }
+}
+
+/// Counters for synchronizing a segment file to disk.
+#[derive(Debug, Default)]
+pub(crate) struct SyncMetrics {
+    sync_requests: AtomicU64,
+    fsyncs: AtomicU64,
+    fsync_nanos: AtomicU64,
+    synchronized_commits: AtomicU64,
+}
+
+impl SyncMetrics {
+    pub fn record_request(&self) {
+        self.sync_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_fsync(&self, elapsed: Duration) {
+        self.fsyncs.fetch_add(1, Ordering::Relaxed);
+        add_duration(&self.fsync_nanos, elapsed);
+    }
+
+    pub fn record_synchronized_commits(&self, commits: u64) {
+        self.synchronized_commits
+            .fetch_add(commits, Ordering::Relaxed);
+    }
+
+    /// Adds these counters to the totals in `stats`.
+    pub fn add_to(&self, stats: &mut Stats) {
+        stats.sync_requests += self.sync_requests.load(Ordering::Relaxed);
+        stats.fsyncs += self.fsyncs.load(Ordering::Relaxed);
+        stats.fsync_time += load_duration(&self.fsync_nanos);
+        stats.synchronized_commits += self.synchronized_commits.load(Ordering::Relaxed);
+    }
+}
+
+fn add_duration(counter: &AtomicU64, elapsed: Duration) {
+    let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+    counter.fetch_add(nanos, Ordering::Relaxed);
+}
+
+fn load_duration(counter: &AtomicU64) -> Duration {
+    Duration::from_nanos(counter.load(Ordering::Relaxed))
+}
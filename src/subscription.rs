@@ -0,0 +1,238 @@
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind, Seek, SeekFrom},
+    time::{Duration, Instant},
+};
+
+use file_manager::{FileManager, PathId};
+
+use crate::{
+    log_file::{LogFile, SegmentProgress},
+    EntryId, Error, SegmentReader, WriteAheadLog,
+};
+
+/// A subscription to the committed entries of a [`WriteAheadLog`].
+///
+/// This type is returned from [`WriteAheadLog::subscribe()`]. Iterating a
+/// subscription returns each committed entry in the order of their ids, and
+/// blocks when all currently committed entries have been returned.
+///
+/// # Checkpointing
+///
+/// A subscription does not prevent entries from being checkpointed. If a
+/// subscription falls behind and the segment containing the next entry is
+/// checkpointed before the entry is read, an error with the kind
+/// [`ErrorKind::NotFound`] is returned. Once an error is returned by the
+/// [`Iterator`] implementation, the iterator is finished.
+#[derive(Debug)]
+pub struct Subscription<'a, M>
+where
+    M: FileManager,
+{
+    wal: &'a WriteAheadLog<M>,
+    next_entry_id: EntryId,
+    segment: LogFile<M::File>,
+    segment_id: u64,
+    offset: Option<u64>,
+    checked_through: u64,
+    entries: VecDeque<SubscribedEntry>,
+    finished: bool,
+}
+
+/// An entry returned from a [`Subscription`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SubscribedEntry {
+    /// The unique id of the entry.
+    pub id: EntryId,
+    /// The data of each chunk written in this entry, in the order they were
+    /// written.
+    pub chunks: Vec<Vec<u8>>,
+}
+
+impl<'a, M> Subscription<'a, M>
+where
+    M: FileManager,
+{
+    pub(crate) fn new(wal: &'a WriteAheadLog<M>, from: EntryId) -> io::Result<Self> {
+        let files = wal.data.files.lock();
+        if files
+            .last_checkpointed_entry_id
+            .map_or(false, |checkpointed| from <= checkpointed)
+        {
+            return Err(entry_checkpointed(from));
+        }
+
+        // Each segment is named after the first entry id it can contain, which
+        // means `from` can only be in the last segment whose id is less than
+        // or equal to it.
+        let mut segments = files
+            .all
+            .iter()
+            .map(|(id, file)| (*id, file.clone()))
+            .collect::<Vec<_>>();
+        drop(files);
+        segments.sort_by_key(|(id, _)| *id);
+        let (segment_id, segment) =
+            if let Some(index) = segments.iter().rposition(|(id, _)| *id <= from.0) {
+                if segments[index].1.is_checkpointed() {
+                    return Err(entry_checkpointed(from));
+                }
+                segments.swap_remove(index)
+            } else {
+                segments
+                    .into_iter()
+                    .find(|(_, file)| !file.is_checkpointed())
+                    .ok_or_else(|| entry_checkpointed(from))?
+            };
+
+        Ok(Self {
+            wal,
+            next_entry_id: from,
+            segment,
+            segment_id,
+            offset: None,
+            checked_through: 0,
+            entries: VecDeque::new(),
+            finished: false,
+        })
+    }
+
+    /// Returns the next committed entry, waiting up to `timeout` for one to be
+    /// committed. If no entry is available before the timeout elapses,
+    /// `Ok(None)` is returned.
+    pub fn next_timeout(&mut self, timeout: Duration) -> io::Result<Option<SubscribedEntry>> {
+        self.next_until(Some(Instant::now() + timeout))
+    }
+
+    /// Returns the next committed entry if one is available, without waiting
+    /// for new entries to be committed.
+    pub fn try_next(&mut self) -> io::Result<Option<SubscribedEntry>> {
+        self.next_until(Some(Instant::now()))
+    }
+
+    fn next_until(&mut self, deadline: Option<Instant>) -> io::Result<Option<SubscribedEntry>> {
+        loop {
+            if let Some(entry) = self.entries.pop_front() {
+                return Ok(Some(entry));
+            }
+
+            match self
+                .segment
+                .wait_for_entries(self.segment_id, self.checked_through, deadline)
+            {
+                SegmentProgress::Readable {
+                    through,
+                    at_entry_boundary,
+                } => {
+                    self.wal.register_reader(self.segment_id);
+                    let result = self.read_entries(through, at_entry_boundary);
+                    self.wal.unregister_reader(self.segment_id);
+                    result?;
+                    self.checked_through = through;
+                }
+                SegmentProgress::Sealed => self.advance_segment()?,
+                SegmentProgress::Checkpointed => {
+                    return Err(entry_checkpointed(self.next_entry_id))
+                }
+                SegmentProgress::TimedOut => return Ok(None),
+            }
+        }
+    }
+
+    /// Reads all entries in the current segment that end at or before
+    /// `through`.
+    fn read_entries(&mut self, through: u64, at_entry_boundary: bool) -> io::Result<()> {
+        let config = &self.wal.data.config;
+        let path = PathId::from(config.directory.join(format!("wal-{}", self.segment_id)));
+        let mut reader = match SegmentReader::open(&path, self.segment_id, config) {
+            Ok(reader) => reader,
+            // The segment was renamed after being checkpointed.
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Err(entry_checkpointed(self.next_entry_id))
+            }
+            Err(err) => return Err(err),
+        };
+        if let Some(offset) = self.offset {
+            reader.file.seek(SeekFrom::Start(offset))?;
+        }
+
+        while reader.file.stream_position()? < through {
+            let result = match reader.read_entry() {
+                Ok(Some(mut entry)) => {
+                    let id = entry.id();
+                    entry
+                        .read_all_chunks()
+                        .map(|chunks| chunks.map(|chunks| SubscribedEntry { id, chunks }))
+                }
+                Ok(None) => Ok(None),
+                Err(err) => Err(err),
+            };
+            let end = reader.file.stream_position()?;
+            match result {
+                Ok(Some(entry)) if end <= through => {
+                    self.offset = Some(end);
+                    if entry.id >= self.next_entry_id {
+                        self.next_entry_id = EntryId(entry.id.0 + 1);
+                        self.entries.push_back(entry);
+                    }
+                }
+                // When `through` isn't at the end of an entry, the last entry
+                // may not have been fully synchronized yet.
+                Ok(_) | Err(_) if !at_entry_boundary => break,
+                Ok(Some(_)) => break,
+                Ok(None) => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "committed entry could not be read",
+                    ))
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn advance_segment(&mut self) -> io::Result<()> {
+        let files = self.wal.data.files.lock();
+        let (segment_id, segment) = files
+            .all
+            .iter()
+            .filter(|(id, _)| **id > self.segment_id)
+            .min_by_key(|(id, _)| **id)
+            .map(|(id, file)| (*id, file.clone()))
+            .ok_or_else(|| entry_checkpointed(self.next_entry_id))?;
+        drop(files);
+
+        self.segment = segment;
+        self.segment_id = segment_id;
+        self.offset = None;
+        self.checked_through = 0;
+        Ok(())
+    }
+}
+
+impl<M> Iterator for Subscription<'_, M>
+where
+    M: FileManager,
+{
+    type Item = io::Result<SubscribedEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        match self.next_until(None) {
+            Ok(entry) => entry.map(Ok),
+            Err(err) => {
+                self.finished = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+fn entry_checkpointed(entry_id: EntryId) -> io::Error {
+    Error::EntryCheckpointed { entry_id }.into()
+}
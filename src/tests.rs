@@ -1,6 +1,6 @@
 use std::{
     collections::{BTreeMap, HashMap},
-    io::{self, ErrorKind, Read, Write},
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
     iter::repeat_with,
     path::Path,
     sync::Arc,
@@ -8,13 +8,22 @@ use std::{
 };
 
 use file_manager::{fs::StdFileManager, memory::MemoryFileManager, FileManager};
+use file_manager::{OpenOptions, PathId};
 use parking_lot::Mutex;
 use tempfile::tempdir;
 
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::IoUringFileManager;
 use crate::{
-    entry::NEW_ENTRY, Configuration, Entry, EntryId, LogManager, RecoveredSegment, Recovery,
-    SegmentReader, WriteAheadLog,
+    directory::DirectoryGuard,
+    entry::{self, CHUNK, END_OF_ENTRY, NEW_ENTRY},
+    faulty::{Crash, FaultyFileManager, FileOperation},
+    list_segments, ArchiveDirectory, CheckpointRetry, ChunkRecord, Configuration, Durability,
+    Entry, EntryId, Error, GroupCommitWindow, LogManager, ReadChunkResult, RecoveredSegment,
+    Recovery, RecoveryMode, SegmentCheckpoint, SegmentReader, WriteAheadLog,
 };
+#[cfg(unix)]
+use crate::{write_through::DirectBlocks, WriteThrough, WriteThroughFileManager};
 
 #[derive(Default, Debug, Clone)]
 struct LoggingCheckpointer {
@@ -35,6 +44,20 @@ enum CheckpointCall {
     },
 }
 
+impl LoggingCheckpointer {
+    /// Returns the ids of the entries whose chunks were recovered.
+    fn recovered_entry_ids(&self) -> Vec<EntryId> {
+        self.invocations
+            .lock()
+            .iter()
+            .filter_map(|call| match call {
+                CheckpointCall::Recover { entry_id, .. } => Some(*entry_id),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
 impl<M> LogManager<M> for LoggingCheckpointer
 where
     M: file_manager::FileManager,
@@ -207,8 +230,12 @@ fn test_max_disk_usage_percent_std_enough_disk_space() {
 fn test_max_disk_usage_percent_std_not_enough_disk_space() {
     let dir = tempdir().unwrap();
     let result = try_append_entry(StdFileManager::default(), &dir, 0);
-    assert!(result.is_err());
-    assert_eq!(result.err().unwrap().kind(), io::ErrorKind::OutOfMemory);
+    let err = result.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::OutOfMemory);
+    assert!(matches!(
+        Error::from_io_error(&err),
+        Some(Error::StorageFull { .. })
+    ));
 }
 
 #[test]
@@ -332,6 +359,37 @@ fn multithreaded_memory() {
     multithreaded(MemoryFileManager::default(), "/");
 }
 
+#[test]
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+fn multithreaded_io_uring() {
+    let dir = tempdir().unwrap();
+    multithreaded(IoUringFileManager::default(), &dir);
+}
+
+#[test]
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+fn io_uring_flush() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("file");
+    let manager = IoUringFileManager::default();
+    let mut file = manager
+        .open(
+            &PathId::from(path.clone()),
+            OpenOptions::new().create(true).write(true).read(true),
+        )
+        .unwrap();
+    // Queue more writes than fit in the ring's queue at once.
+    let mut expected = Vec::new();
+    for i in 0_u32..4096 {
+        file.write_all(&i.to_be_bytes()).unwrap();
+        expected.extend_from_slice(&i.to_be_bytes());
+    }
+    file.flush().unwrap();
+
+    // Once flushed, the writes are visible through other files.
+    assert_eq!(std::fs::read(&path).unwrap(), expected);
+}
+
 fn aborted_entry<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
     let checkpointer = LoggingCheckpointer::default();
 
@@ -343,7 +401,7 @@ fn aborted_entry<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
     let mut writer = wal.begin_entry().unwrap();
     let record = writer.write_chunk(message).unwrap();
     let written_entry_id = writer
-        .commit_and(|file| file.write_all(&[NEW_ENTRY]))
+        .commit_and(Durability::Fsync, |file| file.write_all(&[NEW_ENTRY]))
         .unwrap();
     println!("hello world written to {record:?} in {written_entry_id:?}");
     drop(wal);
@@ -386,123 +444,2909 @@ fn aborted_entry_memory() {
     aborted_entry(MemoryFileManager::default(), "/");
 }
 
-fn always_checkpointing<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
-    let checkpointer = LoggingCheckpointer::default();
-    let config =
-        Configuration::default_with_manager(path.as_ref(), manager).checkpoint_after_bytes(33);
+#[test]
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+fn aborted_entry_io_uring() {
+    let dir = tempdir().unwrap();
+    aborted_entry(IoUringFileManager::default(), &dir);
+}
 
-    let mut written_chunks = Vec::new();
-    for i in 0_usize..100 {
-        println!("About to insert {i}");
-        let wal = config.clone().open(checkpointer.clone()).unwrap();
+fn recovery_report<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let wal = Configuration::default_with_manager(path.as_ref(), manager.clone())
+        .open(LoggingCheckpointer::default())
+        .unwrap();
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"hello world").unwrap();
+    let written_entry_id = writer.commit().unwrap();
+    // Begin another entry that is never finished.
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"torn").unwrap();
+    writer
+        .commit_and(Durability::Fsync, |file| {
+            file.write_all(&[NEW_ENTRY])?;
+            file.write_all(&(written_entry_id.0 + 2).to_le_bytes())
+        })
+        .unwrap();
+    drop(wal);
+
+    let (wal, report) = Configuration::default_with_manager(path.as_ref(), manager)
+        .open_with_report(LoggingCheckpointer::default())
+        .unwrap();
+    assert_eq!(report.segments.len(), 1);
+    let segment = &report.segments[0];
+    assert_eq!(segment.id, written_entry_id.0);
+    assert_eq!(segment.entries_recovered, 2);
+    assert_eq!(segment.aborted_entries, 1);
+    assert_eq!(segment.truncated_bytes, 0);
+    assert_eq!(segment.crc_failures, 0);
+    assert_eq!(report.entries_recovered(), 2);
+    assert!(report.abandoned_segments.is_empty());
+    assert!(report.deleted_files.is_empty());
+    assert!(!report.is_clean());
+    drop(wal);
+}
+
+#[test]
+fn recovery_report_std() {
+    let dir = tempdir().unwrap();
+    recovery_report(StdFileManager::default(), &dir);
+}
+
+#[test]
+fn recovery_report_memory() {
+    recovery_report(MemoryFileManager::default(), "/");
+}
+
+/// Writes three single-chunk entries to a new log, returning the record of
+/// each entry's chunk and its id.
+fn write_three_entries<M: FileManager>(
+    config: &Configuration<M>,
+) -> (Vec<ChunkRecord>, Vec<EntryId>) {
+    let wal = config.clone().open(LoggingCheckpointer::default()).unwrap();
+    let mut records = Vec::new();
+    let mut entry_ids = Vec::new();
+    for message in [&b"first"[..], b"second", b"third"] {
         let mut writer = wal.begin_entry().unwrap();
-        println!("Writing {i}");
-        let record = writer.write_chunk(&i.to_be_bytes()).unwrap();
-        let written_entry_id = writer.commit().unwrap();
-        println!("{i} written to {record:?} in {written_entry_id:?}");
-        written_chunks.push((written_entry_id, record));
-        wal.shutdown().unwrap();
+        records.push(writer.write_chunk(message).unwrap());
+        entry_ids.push(writer.commit().unwrap());
     }
+    drop(wal);
+    (records, entry_ids)
+}
 
-    // Because we write and close the database so quickly, it's possible for the
-    // final write to not have been checkpointed by the background thread. So,
-    // we'll reopen the WAL one more time and sleep for a moment to allow it to
-    // finish.
-    let _wal = config.open(checkpointer.clone()).unwrap();
-    std::thread::sleep(Duration::from_millis(100));
+/// Flips the bits of the byte at `offset` in the segment `segment_id`.
+fn corrupt_byte<M: FileManager>(config: &Configuration<M>, segment_id: u64, offset: u64) {
+    let segment_path = PathId::from(config.directory.join(format!("wal-{segment_id}")));
+    let mut file = config
+        .file_manager
+        .open(&segment_path, OpenOptions::new().read(true).write(true))
+        .unwrap();
+    let mut byte = [0];
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.read_exact(&mut byte).unwrap();
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.write_all(&[!byte[0]]).unwrap();
+}
 
-    let invocations = checkpointer.invocations.lock();
-    println!("Invocations: {invocations:?}");
+fn recovery_modes<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let config = Configuration::default_with_manager(path.as_ref(), manager);
+    let (records, entry_ids) = write_three_entries(&config);
 
-    for (index, (entry_id, _)) in written_chunks.into_iter().enumerate() {
-        println!("Checking {index}");
-        let chunk_data = invocations
-            .iter()
-            .find_map(|call| {
-                if let CheckpointCall::Checkpoint { data, .. } = call {
-                    data.get(&entry_id)
-                } else {
-                    None
-                }
-            })
-            .expect("entry not checkpointed");
+    // Corrupt the header of the second entry, which precedes its first chunk.
+    let second_entry_offset = records[1].position.offset - 9;
+    corrupt_byte(&config, records[1].position.file_id, second_entry_offset);
 
-        assert_eq!(chunk_data[0], index.to_be_bytes());
-    }
+    // Tolerant recovery stops at the corrupted entry.
+    let checkpointer = LoggingCheckpointer::default();
+    let (wal, report) = config
+        .clone()
+        .open_with_report(checkpointer.clone())
+        .unwrap();
+    assert_eq!(checkpointer.recovered_entry_ids(), &entry_ids[..1]);
+    assert_eq!(report.entries_recovered(), 1);
+    drop(wal);
+
+    // Strict recovery refuses to open the log.
+    let err = config
+        .clone()
+        .recovery_mode(RecoveryMode::Strict)
+        .open(LoggingCheckpointer::default())
+        .unwrap_err();
+    assert!(matches!(
+        Error::from_io_error(&err),
+        Some(Error::SegmentCorrupted { position })
+            if position.offset == second_entry_offset
+    ));
+
+    // Salvaging recovers the third entry, and moves it over the corruption.
+    let checkpointer = LoggingCheckpointer::default();
+    let (wal, report) = config
+        .clone()
+        .recovery_mode(RecoveryMode::Salvage)
+        .open_with_report(checkpointer.clone())
+        .unwrap();
+    assert_eq!(
+        checkpointer.recovered_entry_ids(),
+        [entry_ids[0], entry_ids[2]]
+    );
+    let segment = &report.segments[0];
+    assert_eq!(segment.salvaged_entries, 1);
+    assert_eq!(
+        segment.corrupted_bytes,
+        records[2].position.offset - records[1].position.offset
+    );
+    assert!(!report.is_clean());
+    drop(wal);
+
+    // The salvaged segment can now be recovered strictly.
+    let checkpointer = LoggingCheckpointer::default();
+    let (wal, report) = config
+        .recovery_mode(RecoveryMode::Strict)
+        .open_with_report(checkpointer.clone())
+        .unwrap();
+    assert_eq!(
+        checkpointer.recovered_entry_ids(),
+        [entry_ids[0], entry_ids[2]]
+    );
+    assert!(report.is_clean());
+    drop(wal);
 }
 
 #[test]
-fn always_checkpointing_std() {
+fn recovery_modes_std() {
     let dir = tempdir().unwrap();
-    always_checkpointing(StdFileManager::default(), &dir);
+    recovery_modes(StdFileManager::default(), &dir);
 }
 
 #[test]
-fn always_checkpointing_memory() {
-    always_checkpointing(MemoryFileManager::default(), "/");
+fn recovery_modes_memory() {
+    recovery_modes(MemoryFileManager::default(), "/");
 }
 
-#[derive(Debug)]
-struct FailingCheckpointer {
-    call_count: u32,
-    call_limit: u32,
+fn salvage_skips_rolled_back_batch<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let config = Configuration::default_with_manager(path.as_ref(), manager)
+        .recovery_mode(RecoveryMode::Salvage);
+    let wal = config.clone().open(LoggingCheckpointer::default()).unwrap();
+
+    // Both entries of the batch are complete on disk when it is rolled back.
+    let compression = config.compression;
+    wal.append_entries(2, false, |writer, first_entry_id| {
+        entry::write_entry(writer, first_entry_id, &[[1_u8; 64]], compression)?;
+        entry::write_entry(
+            writer,
+            EntryId(first_entry_id.0 + 1),
+            &[[2_u8; 64]],
+            compression,
+        )?;
+        writer.flush()?;
+        Err::<(), _>(io::Error::new(ErrorKind::Other, "rolled back"))
+    })
+    .unwrap_err();
+
+    // The next entry overwrites the start of the batch's first entry, leaving
+    // the batch's second entry after the segment's readable entries.
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"kept").unwrap();
+    let kept = writer.commit().unwrap();
+    drop(wal);
+
+    let checkpointer = LoggingCheckpointer::default();
+    let (wal, report) = config.open_with_report(checkpointer.clone()).unwrap();
+    assert_eq!(
+        recovered_entries(&checkpointer),
+        [(kept, vec![b"kept".to_vec()])]
+    );
+    assert_eq!(report.segments[0].salvaged_entries, 0);
+    drop(wal);
 }
 
-impl FailingCheckpointer {
-    pub fn new(call_limit: u32) -> Self {
-        Self {
-            call_count: 0,
-            call_limit,
-        }
-    }
+#[test]
+fn salvage_skips_rolled_back_batch_std() {
+    let dir = tempdir().unwrap();
+    salvage_skips_rolled_back_batch(StdFileManager::default(), &dir);
 }
 
-impl LogManager for FailingCheckpointer {
-    fn recover(&mut self, _entry: &mut Entry<'_>) -> std::io::Result<()> {
-        Ok(())
-    }
+#[test]
+fn salvage_skips_rolled_back_batch_memory() {
+    salvage_skips_rolled_back_batch(MemoryFileManager::default(), "/");
+}
 
-    fn checkpoint_to(
-        &mut self,
-        _last_checkpointed_id: EntryId,
-        _reader: &mut SegmentReader,
-        _wal: &WriteAheadLog,
-    ) -> std::io::Result<()> {
-        self.call_count += 1;
-        if self.call_count >= self.call_limit {
-            println!("Call limit reached, returning err so that that checkpointer thread returns");
-            return Err(io::Error::new(io::ErrorKind::BrokenPipe, ""));
-        }
-        println!(
-            "Call limit not reached yet. current count: {:?}, limit: {:?}",
-            self.call_count, self.call_limit
-        );
-        Ok(())
-    }
+#[test]
+fn salvage_failure() {
+    use file_manager::File;
+
+    let manager = FaultyFileManager::default();
+    let config = Configuration::default_with_manager("/", manager.clone());
+    let (records, entry_ids) = write_three_entries(&config);
+    corrupt_byte(
+        &config,
+        records[1].position.file_id,
+        records[1].position.offset - 9,
+    );
+    config
+        .file_manager
+        .open(
+            &PathId::from(config.directory.join("wal-1")),
+            OpenOptions::new().write(true),
+        )
+        .unwrap()
+        .sync_all()
+        .unwrap();
+
+    // The salvaged copy of the segment replaces it with a rename. If the
+    // rename never happens, the segment is unchanged and can be salvaged
+    // again.
+    manager.fail_after(FileOperation::Rename, 0);
+    let config = config.recovery_mode(RecoveryMode::Salvage);
+    config
+        .clone()
+        .open(LoggingCheckpointer::default())
+        .unwrap_err();
+    let manager = manager.crash(Crash::LoseUnsynced).unwrap();
+
+    let checkpointer = LoggingCheckpointer::default();
+    let (wal, report) = Configuration::default_with_manager("/", manager.clone())
+        .recovery_mode(RecoveryMode::Salvage)
+        .open_with_report(checkpointer.clone())
+        .unwrap();
+    assert_eq!(
+        checkpointer.recovered_entry_ids(),
+        [entry_ids[0], entry_ids[2]]
+    );
+    assert_eq!(report.segments[0].salvaged_entries, 1);
+    assert!(!manager.exists(&PathId::from(Path::new("/salvage-1").to_path_buf())));
+    drop(wal);
+}
+
+fn recovery_verifies_crcs<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let config = Configuration::default_with_manager(path.as_ref(), manager);
+    let (records, entry_ids) = write_three_entries(&config);
+
+    // Corrupt the data of the second entry's chunk, which follows the chunk's
+    // 5 byte header.
+    let position = records[1].position;
+    corrupt_byte(&config, position.file_id, position.offset + 5);
+
+    // The log manager relies on its own CRC checks when verification is
+    // disabled.
+    let err = config
+        .clone()
+        .verify_recovered_chunks(false)
+        .open(LoggingCheckpointer::default())
+        .unwrap_err();
+    assert!(matches!(
+        Error::from_io_error(&err),
+        Some(Error::CrcMismatch { position: failed }) if *failed == position
+    ));
+
+    // Strict recovery refuses to discard the third entry.
+    let err = config
+        .clone()
+        .recovery_mode(RecoveryMode::Strict)
+        .open(LoggingCheckpointer::default())
+        .unwrap_err();
+    assert!(matches!(
+        Error::from_io_error(&err),
+        Some(Error::SegmentCorrupted { position: corrupted_at })
+            if corrupted_at.offset == position.offset - 9
+    ));
+
+    // The corrupted entry is never passed to the log manager.
+    let checkpointer = LoggingCheckpointer::default();
+    let (wal, report) = config.open_with_report(checkpointer.clone()).unwrap();
+    assert_eq!(checkpointer.recovered_entry_ids(), &entry_ids[..1]);
+    assert_eq!(report.segments[0].crc_failures, 1);
+    assert_eq!(report.segments[0].aborted_entries, 0);
+    assert!(!report.is_clean());
+    drop(wal);
 }
 
 #[test]
-fn test_is_checkpoint_thread_running() {
+fn recovery_verifies_crcs_std() {
     let dir = tempdir().unwrap();
-    let checkpointer = FailingCheckpointer::new(2);
-    let config = Configuration::default_with_manager(dir.as_ref(), StdFileManager::default())
-        .checkpoint_after_bytes(33);
+    recovery_verifies_crcs(StdFileManager::default(), &dir);
+}
 
-    // First write doesn't cause the checkpointing thread to halt.
-    let wal = config.open(checkpointer).unwrap();
+#[test]
+fn recovery_verifies_crcs_memory() {
+    recovery_verifies_crcs(MemoryFileManager::default(), "/");
+}
+
+fn entry_crcs<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let config = Configuration::default_with_manager(path.as_ref(), manager);
+    let (records, entry_ids) = write_three_entries(&config);
+
+    // Corrupt the last byte of the second entry's id, which precedes its first
+    // chunk. Only the entry's CRC covers it.
+    let position = records[1].position;
+    corrupt_byte(&config, position.file_id, position.offset - 1);
+
+    let checkpointer = LoggingCheckpointer::default();
+    let (wal, report) = config.open_with_report(checkpointer.clone()).unwrap();
+    assert_eq!(checkpointer.recovered_entry_ids(), &entry_ids[..1]);
+    assert_eq!(report.segments[0].crc_failures, 1);
+    drop(wal);
+}
+
+#[test]
+fn entry_crcs_std() {
+    let dir = tempdir().unwrap();
+    entry_crcs(StdFileManager::default(), &dir);
+}
+
+#[test]
+fn entry_crcs_memory() {
+    entry_crcs(MemoryFileManager::default(), "/");
+}
+
+fn version_0_segments<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let config = Configuration::default_with_manager(path.as_ref(), manager).format_version(0);
+    config
+        .file_manager
+        .create_dir_all(&config.directory)
+        .unwrap();
+
+    // Write a segment containing one entry, whose end isn't followed by a
+    // length or CRC.
+    let segment_path = PathId::from(config.directory.join("wal-1"));
+    let mut file = config
+        .file_manager
+        .open(
+            &segment_path,
+            OpenOptions::new().create(true).write(true).read(true),
+        )
+        .unwrap();
+    file.write_all(b"okw\0\0").unwrap();
+    file.write_all(&[NEW_ENTRY]).unwrap();
+    file.write_all(&1_u64.to_le_bytes()).unwrap();
+    file.write_all(&[CHUNK]).unwrap();
+    file.write_all(&5_u32.to_le_bytes()).unwrap();
+    file.write_all(b"hello").unwrap();
+    file.write_all(&crc32c::crc32c(b"hello").to_le_bytes())
+        .unwrap();
+    file.write_all(&[END_OF_ENTRY]).unwrap();
+    drop(file);
+
+    let reader = SegmentReader::new(&segment_path, 1, &config.file_manager).unwrap();
+    assert_eq!(reader.header().format_version, 0);
+    drop(reader);
+
+    let checkpointer = LoggingCheckpointer::default();
+    let (wal, report) = config
+        .clone()
+        .open_with_report(checkpointer.clone())
+        .unwrap();
+    assert_eq!(checkpointer.recovered_entry_ids(), [EntryId(1)]);
+    assert!(report.is_clean());
+
+    // New entries can be written alongside the existing one.
     let mut writer = wal.begin_entry().unwrap();
-    let _record = writer.write_chunk(&0i32.to_be_bytes()).unwrap();
-    let written_entry_id = writer.commit_and_checkpoint().unwrap();
-    wal.wait_checkpointed_for(&written_entry_id, Duration::from_secs(10 * 60))
+    writer.write_chunk(b"world").unwrap();
+    let second_id = writer.commit().unwrap();
+    drop(wal);
+
+    let checkpointer = LoggingCheckpointer::default();
+    let (wal, report) = config
+        .clone()
+        .open_with_report(checkpointer.clone())
         .unwrap();
-    assert!(wal.is_checkpoint_thread_running());
+    assert_eq!(checkpointer.recovered_entry_ids(), [EntryId(1), second_id]);
+    assert!(report.is_clean());
+    drop(wal);
 
-    // Second write causes the checkpoitning thread to halt.
+    let reader = SegmentReader::new(&segment_path, 1, &config.file_manager).unwrap();
+    assert_eq!(reader.header().format_version, 0);
+}
+
+#[test]
+fn version_0_segments_std() {
+    let dir = tempdir().unwrap();
+    version_0_segments(StdFileManager::default(), &dir);
+}
+
+#[test]
+fn version_0_segments_memory() {
+    version_0_segments(MemoryFileManager::default(), "/");
+}
+
+fn torn_chunks<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let config = Configuration::default_with_manager(path, manager);
+    let wal = config.clone().open(LoggingCheckpointer::default()).unwrap();
     let mut writer = wal.begin_entry().unwrap();
-    let _record = writer.write_chunk(&0i32.to_be_bytes()).unwrap();
-    let written_entry_id = writer.commit_and_checkpoint().unwrap();
-    // Since the thread is halted waititng for a checkpoint times out.
-    let result = wal.wait_checkpointed_for(&written_entry_id, Duration::from_secs(10));
-    assert!(result.is_err() && result.err().unwrap().kind() == ErrorKind::TimedOut);
-    assert!(!wal.is_checkpoint_thread_running());
+    writer.write_chunk(b"intact").unwrap();
+    let intact_id = writer.commit().unwrap();
+    let mut writer = wal.begin_entry().unwrap();
+    let torn = writer.write_chunk(&[42; 100]).unwrap().position;
+    writer.commit().unwrap();
+    wal.shutdown().unwrap();
+
+    // The torn entry's header precedes its chunk's marker and length.
+    let torn_entry_start = torn.offset() - 9;
+    let segment_path = PathId::from(config.directory.join("wal-1"));
+    let mut file = config
+        .file_manager
+        .open(&segment_path, OpenOptions::new().write(true).read(true))
+        .unwrap();
+
+    // Skipping a chunk whose length runs past the end of the file ends the
+    // segment's valid data at the chunk's entry, even if the length is the
+    // largest a chunk can have.
+    file.seek(SeekFrom::Start(torn.offset() + 1)).unwrap();
+    file.write_all(&u32::MAX.to_le_bytes()).unwrap();
+    let mut reader = SegmentReader::new(&segment_path, 1, &config.file_manager).unwrap();
+    assert_eq!(reader.read_entry().unwrap().unwrap().id(), intact_id);
+    assert!(reader.read_entry().unwrap().is_some());
+    assert!(reader.read_entry().unwrap().is_none());
+    assert_eq!(reader.valid_until(), torn_entry_start);
+
+    // A chunk cut short by the end of the file is reported as torn rather
+    // than as an error.
+    file.seek(SeekFrom::Start(torn.offset() + 1)).unwrap();
+    file.write_all(&100_u32.to_le_bytes()).unwrap();
+    file_manager::File::set_len(&file, torn.offset() + 55).unwrap();
+    drop(file);
+    let mut reader = SegmentReader::new(&segment_path, 1, &config.file_manager).unwrap();
+    let mut entry = reader.read_entry().unwrap().unwrap();
+    assert_eq!(
+        entry.read_all_chunks().unwrap(),
+        Some(vec![b"intact".to_vec()])
+    );
+    let mut entry = reader.read_entry().unwrap().unwrap();
+    assert_eq!(entry.read_all_chunks().unwrap(), None);
+    assert!(reader.read_entry().unwrap().is_none());
+    assert_eq!(reader.valid_until(), torn_entry_start);
+
+    // Recovery ends at the torn entry.
+    let checkpointer = LoggingCheckpointer::default();
+    let wal = config.open(checkpointer.clone()).unwrap();
+    assert_eq!(checkpointer.recovered_entry_ids(), [intact_id]);
+    drop(wal);
+}
+
+#[test]
+fn torn_chunks_std() {
+    let dir = tempdir().unwrap();
+    torn_chunks(StdFileManager::default(), &dir);
+}
+
+#[test]
+fn torn_chunks_memory() {
+    torn_chunks(MemoryFileManager::default(), "/");
+}
+
+fn stale_chunk_after_entry<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let config = Configuration::default_with_manager(path, manager);
+    let wal = config.clone().open(LoggingCheckpointer::default()).unwrap();
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"entry").unwrap();
+    let entry_id = writer.commit().unwrap();
+    wal.shutdown().unwrap();
+
+    let segment_path = PathId::from(config.directory.join("wal-1"));
+    let mut reader = SegmentReader::new(&segment_path, 1, &config.file_manager).unwrap();
+    while reader.read_entry().unwrap().is_some() {}
+    let entry_end = reader.valid_until();
+    drop(reader);
+
+    // Write a chunk after the entry, like one left over from before the
+    // segment was recycled.
+    let mut file = config
+        .file_manager
+        .open(&segment_path, OpenOptions::new().write(true).read(true))
+        .unwrap();
+    file.seek(SeekFrom::Start(entry_end)).unwrap();
+    file.write_all(&[CHUNK]).unwrap();
+    file.write_all(&5_u32.to_le_bytes()).unwrap();
+    file.write_all(b"stale").unwrap();
+    file.write_all(&crc32c::crc32c(b"stale").to_le_bytes())
+        .unwrap();
+    drop(file);
+
+    // Once an entry has been read completely, reading more chunks or the next
+    // entry doesn't treat the stale chunk as part of it.
+    let mut reader = SegmentReader::new(&segment_path, 1, &config.file_manager).unwrap();
+    let mut entry = reader.read_entry().unwrap().unwrap();
+    assert_eq!(entry.id(), entry_id);
+    assert_eq!(
+        entry.read_all_chunks().unwrap(),
+        Some(vec![b"entry".to_vec()])
+    );
+    assert!(matches!(
+        entry.read_chunk().unwrap(),
+        ReadChunkResult::EndOfEntry
+    ));
+    assert!(reader.read_entry().unwrap().is_none());
+    assert_eq!(reader.valid_until(), entry_end);
+
+    // New entries are written after the recovered entry, rather than after
+    // the stale chunk.
+    let checkpointer = LoggingCheckpointer::default();
+    let wal = config.clone().open(checkpointer.clone()).unwrap();
+    assert_eq!(checkpointer.recovered_entry_ids(), [entry_id]);
+    let mut writer = wal.begin_entry().unwrap();
+    let record = writer.write_chunk(b"next").unwrap();
+    writer.commit().unwrap();
+    assert_eq!(record.position.offset(), entry_end + 9);
+}
+
+#[test]
+fn stale_chunk_after_entry_std() {
+    let dir = tempdir().unwrap();
+    stale_chunk_after_entry(StdFileManager::default(), &dir);
+}
+
+#[test]
+fn stale_chunk_after_entry_memory() {
+    stale_chunk_after_entry(MemoryFileManager::default(), "/");
+}
+
+fn uninitialized_segment<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let config = Configuration::default_with_manager(path, manager);
+    let wal = config.clone().open(LoggingCheckpointer::default()).unwrap();
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"entry").unwrap();
+    let entry_id = writer.commit().unwrap();
+    wal.shutdown().unwrap();
+
+    // Leave the next segment as a crash during its creation would: only part
+    // of the magic code made it to disk before the preallocated zeroes.
+    let segment_path = PathId::from(config.directory.join("wal-2"));
+    let mut file = config
+        .file_manager
+        .open(
+            &segment_path,
+            OpenOptions::new().write(true).create(true).truncate(true),
+        )
+        .unwrap();
+    file.write_all(b"ok").unwrap();
+    file.write_all(&[0; 1024]).unwrap();
+    drop(file);
+
+    // The log opens, recovers the committed entry, and keeps the segment to
+    // be reused rather than treating it as corrupt.
+    let checkpointer = LoggingCheckpointer::default();
+    let wal = config.clone().open(checkpointer.clone()).unwrap();
+    assert_eq!(checkpointer.recovered_entry_ids(), [entry_id]);
+    let segment_ids: Vec<u64> = list_segments(&config.file_manager, &config.directory)
+        .unwrap()
+        .into_iter()
+        .map(|segment| segment.id)
+        .collect();
+    assert_eq!(segment_ids, [1, 2]);
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"next").unwrap();
+    let next_id = writer.commit().unwrap();
+    wal.shutdown().unwrap();
+
+    let checkpointer = LoggingCheckpointer::default();
+    let wal = config.clone().open(checkpointer.clone()).unwrap();
+    assert_eq!(checkpointer.recovered_entry_ids(), [entry_id, next_id]);
+    drop(wal);
+
+    // A segment whose header isn't a prefix of the magic code is still an
+    // error.
+    let mut file = config
+        .file_manager
+        .open(
+            &segment_path,
+            OpenOptions::new().write(true).create(true).truncate(true),
+        )
+        .unwrap();
+    file.write_all(b"oops").unwrap();
+    drop(file);
+    assert!(config.open(LoggingCheckpointer::default()).is_err());
+}
+
+fn rollback_first_entry<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let config = Configuration::default_with_manager(path, manager);
+    let wal = config.clone().open(LoggingCheckpointer::default()).unwrap();
+
+    // Rolling back the first entry of a new segment reverts to the end of its
+    // header, which must not be written again.
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"rolled back").unwrap();
+    writer.rollback().unwrap();
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"entry").unwrap();
+    let entry_id = writer.commit().unwrap();
+    wal.shutdown().unwrap();
+
+    let checkpointer = LoggingCheckpointer::default();
+    let wal = config.open(checkpointer.clone()).unwrap();
+    assert_eq!(checkpointer.recovered_entry_ids(), [entry_id]);
+    drop(wal);
+}
+
+#[test]
+fn rollback_first_entry_std() {
+    let dir = tempdir().unwrap();
+    rollback_first_entry(StdFileManager::default(), &dir);
+}
+
+#[test]
+fn rollback_first_entry_memory() {
+    rollback_first_entry(MemoryFileManager::default(), "/");
+}
+
+fn rollback_within_buffer<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let config = Configuration::default_with_manager(path, manager);
+    let wal = config.clone().open(LoggingCheckpointer::default()).unwrap();
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"first").unwrap();
+    let first_id = writer.commit().unwrap();
+
+    // The rolled back entry is still buffered, so reverting seeks backwards
+    // within the buffer. The shorter entry that replaces it is flushed along
+    // with the rest of the buffer, and the next entry must follow it rather
+    // than the end of the buffer.
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(&[42; 1024]).unwrap();
+    writer.rollback().unwrap();
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"second").unwrap();
+    let second_id = writer.commit().unwrap();
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"third").unwrap();
+    let third_id = writer.commit().unwrap();
+    wal.shutdown().unwrap();
+
+    let checkpointer = LoggingCheckpointer::default();
+    let wal = config.open(checkpointer.clone()).unwrap();
+    assert_eq!(
+        checkpointer.recovered_entry_ids(),
+        [first_id, second_id, third_id]
+    );
+    drop(wal);
+}
+
+#[test]
+fn rollback_within_buffer_std() {
+    let dir = tempdir().unwrap();
+    rollback_within_buffer(StdFileManager::default(), &dir);
+}
+
+#[test]
+fn rollback_within_buffer_memory() {
+    rollback_within_buffer(MemoryFileManager::default(), "/");
+}
+
+fn empty_segment_in_old_format<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let config = Configuration::default_with_manager(path, manager);
+    config
+        .file_manager
+        .create_dir_all(&config.directory)
+        .unwrap();
+
+    // A header torn after the magic code reads as an empty segment written in
+    // the first format version.
+    let segment_path = PathId::from(config.directory.join("wal-1"));
+    let mut file = config
+        .file_manager
+        .open(
+            &segment_path,
+            OpenOptions::new().write(true).create(true).truncate(true),
+        )
+        .unwrap();
+    file.write_all(b"okw").unwrap();
+    file.write_all(&[0; 1024]).unwrap();
+    drop(file);
+
+    // The segment is rewritten and used for new entries, rather than being
+    // checkpointed while a new segment with the same id is activated.
+    let wal = config.clone().open(LoggingCheckpointer::default()).unwrap();
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"entry").unwrap();
+    let entry_id = writer.commit().unwrap();
+    wal.shutdown().unwrap();
+
+    let checkpointer = LoggingCheckpointer::default();
+    let wal = config.clone().open(checkpointer.clone()).unwrap();
+    assert_eq!(checkpointer.recovered_entry_ids(), [entry_id]);
+    drop(wal);
+    assert_eq!(segment_format_versions(&config), [1]);
+}
+
+#[test]
+fn empty_segment_in_old_format_std() {
+    let dir = tempdir().unwrap();
+    empty_segment_in_old_format(StdFileManager::default(), &dir);
+}
+
+#[test]
+fn empty_segment_in_old_format_memory() {
+    empty_segment_in_old_format(MemoryFileManager::default(), "/");
+}
+
+fn uninitialized_segment_after_checkpoint<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let config = Configuration::default_with_manager(path, manager);
+    config
+        .file_manager
+        .create_dir_all(&config.directory)
+        .unwrap();
+
+    // Every entry has been checkpointed, and a crash interrupted activating
+    // the next segment.
+    for (name, contents) in [("wal-1-cp", &b"okw\x01\0"[..]), ("wal-2", b"ok\0\0")] {
+        let mut file = config
+            .file_manager
+            .open(
+                &PathId::from(config.directory.join(name)),
+                OpenOptions::new().write(true).create(true).truncate(true),
+            )
+            .unwrap();
+        file.write_all(contents).unwrap();
+    }
+
+    // The segment with the partial header is activated, rather than the
+    // checkpointed segment being renamed over it.
+    let wal = config.clone().open(LoggingCheckpointer::default()).unwrap();
+    let segments: Vec<(u64, bool)> = list_segments(&config.file_manager, &config.directory)
+        .unwrap()
+        .into_iter()
+        .map(|segment| (segment.id, segment.checkpointed))
+        .collect();
+    assert_eq!(segments, [(1, true), (2, false)]);
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"entry").unwrap();
+    let entry_id = writer.commit().unwrap();
+    wal.shutdown().unwrap();
+
+    let checkpointer = LoggingCheckpointer::default();
+    let wal = config.open(checkpointer.clone()).unwrap();
+    assert_eq!(checkpointer.recovered_entry_ids(), [entry_id]);
+    drop(wal);
+}
+
+#[test]
+fn uninitialized_segment_after_checkpoint_std() {
+    let dir = tempdir().unwrap();
+    uninitialized_segment_after_checkpoint(StdFileManager::default(), &dir);
+}
+
+#[test]
+fn uninitialized_segment_after_checkpoint_memory() {
+    uninitialized_segment_after_checkpoint(MemoryFileManager::default(), "/");
+}
+
+#[test]
+fn uninitialized_segment_std() {
+    let dir = tempdir().unwrap();
+    uninitialized_segment(StdFileManager::default(), &dir);
+}
+
+#[test]
+fn uninitialized_segment_memory() {
+    uninitialized_segment(MemoryFileManager::default(), "/");
+}
+
+/// Returns the format version of each segment in the log, ordered by the
+/// segments' ids.
+fn segment_format_versions<M: FileManager>(config: &Configuration<M>) -> Vec<u8> {
+    list_segments(&config.file_manager, &config.directory)
+        .unwrap()
+        .into_iter()
+        .filter(|segment| !segment.checkpointed)
+        .map(|segment| {
+            SegmentReader::new(&segment.path, segment.id, &config.file_manager)
+                .unwrap()
+                .header()
+                .format_version
+        })
+        .collect()
+}
+
+fn upgrade_format<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let config = Configuration::default_with_manager(path.as_ref(), manager);
+    let (_, mut entry_ids) = write_three_entries(&config.clone().format_version(0));
+    assert_eq!(segment_format_versions(&config), [0]);
+
+    let upgraded = config.upgrade_format().unwrap();
+    assert_eq!(upgraded, [entry_ids[0].0]);
+    assert_eq!(segment_format_versions(&config), [1]);
+    assert!(config.upgrade_format().unwrap().is_empty());
+
+    // The upgraded segment's entries are recovered, and new entries are
+    // written to it.
+    let checkpointer = LoggingCheckpointer::default();
+    let (wal, report) = config
+        .clone()
+        .open_with_report(checkpointer.clone())
+        .unwrap();
+    assert_eq!(checkpointer.recovered_entry_ids(), entry_ids);
+    assert!(report.is_clean());
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"fourth").unwrap();
+    entry_ids.push(writer.commit().unwrap());
+    drop(wal);
+
+    let checkpointer = LoggingCheckpointer::default();
+    let (wal, report) = config
+        .clone()
+        .open_with_report(checkpointer.clone())
+        .unwrap();
+    assert_eq!(checkpointer.recovered_entry_ids(), entry_ids);
+    assert!(report.is_clean());
+    drop(wal);
+    assert_eq!(segment_format_versions(&config), [1]);
+}
+
+#[test]
+fn upgrade_format_std() {
+    let dir = tempdir().unwrap();
+    upgrade_format(StdFileManager::default(), &dir);
+}
+
+#[test]
+fn upgrade_format_memory() {
+    // Directories are identified by their path, so this test's upgrade must
+    // not use the directory of other tests' logs.
+    upgrade_format(MemoryFileManager::default(), "/upgrade_format");
+}
+
+fn upgrade_format_while_open<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let config = Configuration::default_with_manager(path.as_ref(), manager);
+    let wal = config.clone().open(LoggingCheckpointer::default()).unwrap();
+    let err = config.upgrade_format().unwrap_err();
+    assert!(matches!(
+        Error::from_io_error(&err),
+        Some(Error::DirectoryInUse)
+    ));
+    drop(wal);
+
+    // Logs can't be opened while their segments are upgraded.
+    let upgrading = DirectoryGuard::upgrade(&config).unwrap();
+    let err = config
+        .clone()
+        .open(LoggingCheckpointer::default())
+        .unwrap_err();
+    assert!(matches!(
+        Error::from_io_error(&err),
+        Some(Error::DirectoryInUse)
+    ));
+    drop(upgrading);
+
+    let wal = config.clone().open(LoggingCheckpointer::default()).unwrap();
+    drop(wal);
+    assert!(config.upgrade_format().unwrap().is_empty());
+}
+
+#[test]
+fn upgrade_format_while_open_std() {
+    let dir = tempdir().unwrap();
+    upgrade_format_while_open(StdFileManager::default(), &dir);
+}
+
+#[test]
+fn upgrade_format_while_open_memory() {
+    upgrade_format_while_open(MemoryFileManager::default(), "/upgrade_format_while_open");
+}
+
+#[test]
+fn upgrade_format_directory_identity() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("wal");
+    let wal = Configuration::default_for(&path)
+        .open(LoggingCheckpointer::default())
+        .unwrap();
+
+    // The directory is detected through a different path to it.
+    let alias = path.join("..").join("wal");
+    let err = Configuration::default_for(&alias)
+        .upgrade_format()
+        .unwrap_err();
+    assert!(matches!(
+        Error::from_io_error(&err),
+        Some(Error::DirectoryInUse)
+    ));
+    drop(wal);
+
+    // A log stored in memory doesn't use the directory on disk with the same
+    // path.
+    let memory_wal = Configuration::default_with_manager(&path, MemoryFileManager::default())
+        .open(LoggingCheckpointer::default())
+        .unwrap();
+    assert!(Configuration::default_for(&path)
+        .upgrade_format()
+        .unwrap()
+        .is_empty());
+    drop(memory_wal);
+}
+
+fn abandoned_upgrade<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let config = Configuration::default_with_manager(path.as_ref(), manager);
+    config
+        .file_manager
+        .create_dir_all(&config.directory)
+        .unwrap();
+    let upgrade_path = PathId::from(config.directory.join("upgrade-1"));
+    let salvage_path = PathId::from(config.directory.join("salvage-1"));
+    let write_abandoned_file = |path: &PathId| {
+        let mut file = config
+            .file_manager
+            .open(
+                path,
+                OpenOptions::new().create(true).write(true).truncate(true),
+            )
+            .unwrap();
+        file.write_all(b"okw").unwrap();
+    };
+
+    write_abandoned_file(&upgrade_path);
+    config.upgrade_format().unwrap();
+    assert!(!config.file_manager.exists(&upgrade_path));
+
+    // Salvaging a segment also writes a new file that replaces it.
+    write_abandoned_file(&upgrade_path);
+    write_abandoned_file(&salvage_path);
+    let wal = config.clone().open(LoggingCheckpointer::default()).unwrap();
+    assert!(!config.file_manager.exists(&upgrade_path));
+    assert!(!config.file_manager.exists(&salvage_path));
+    drop(wal);
+}
+
+#[test]
+fn abandoned_upgrade_std() {
+    let dir = tempdir().unwrap();
+    abandoned_upgrade(StdFileManager::default(), &dir);
+}
+
+#[test]
+fn abandoned_upgrade_memory() {
+    abandoned_upgrade(MemoryFileManager::default(), "/abandoned_upgrade");
+}
+
+fn always_checkpointing<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let checkpointer = LoggingCheckpointer::default();
+    let config =
+        Configuration::default_with_manager(path.as_ref(), manager).checkpoint_after_bytes(33);
+
+    let mut written_chunks = Vec::new();
+    for i in 0_usize..100 {
+        println!("About to insert {i}");
+        let wal = config.clone().open(checkpointer.clone()).unwrap();
+        let mut writer = wal.begin_entry().unwrap();
+        println!("Writing {i}");
+        let record = writer.write_chunk(&i.to_be_bytes()).unwrap();
+        let written_entry_id = writer.commit().unwrap();
+        println!("{i} written to {record:?} in {written_entry_id:?}");
+        written_chunks.push((written_entry_id, record));
+        wal.shutdown().unwrap();
+    }
+
+    // Because we write and close the database so quickly, it's possible for the
+    // final write to not have been checkpointed by the background thread. So,
+    // we'll reopen the WAL one more time and sleep for a moment to allow it to
+    // finish.
+    let _wal = config.open(checkpointer.clone()).unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+
+    let invocations = checkpointer.invocations.lock();
+    println!("Invocations: {invocations:?}");
+
+    for (index, (entry_id, _)) in written_chunks.into_iter().enumerate() {
+        println!("Checking {index}");
+        let chunk_data = invocations
+            .iter()
+            .find_map(|call| {
+                if let CheckpointCall::Checkpoint { data, .. } = call {
+                    data.get(&entry_id)
+                } else {
+                    None
+                }
+            })
+            .expect("entry not checkpointed");
+
+        assert_eq!(chunk_data[0], index.to_be_bytes());
+    }
+}
+
+#[test]
+fn always_checkpointing_std() {
+    let dir = tempdir().unwrap();
+    always_checkpointing(StdFileManager::default(), &dir);
+}
+
+#[test]
+fn always_checkpointing_memory() {
+    always_checkpointing(MemoryFileManager::default(), "/");
+}
+
+fn append_batch<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let checkpointer = LoggingCheckpointer::default();
+    let config = Configuration::default_with_manager(path, manager);
+    let wal = config.clone().open(checkpointer.clone()).unwrap();
+
+    let batch = vec![
+        vec![&b"first"[..], &b"entry"[..]],
+        vec![&b"second"[..]],
+        vec![&b"third"[..]],
+    ];
+    let committed = wal.append_batch(batch.clone()).unwrap();
+    assert_eq!(committed.len(), 3);
+    assert_eq!(committed[1].id.0, committed[0].id.0 + 1);
+    assert_eq!(committed[2].id.0, committed[0].id.0 + 2);
+
+    for (entry, chunks) in committed.iter().zip(&batch) {
+        assert_eq!(entry.chunks.len(), chunks.len());
+        for (record, chunk) in entry.chunks.iter().zip(chunks) {
+            let mut reader = wal.read_at(record.position).unwrap();
+            let mut buffer = Vec::new();
+            reader.read_to_end(&mut buffer).unwrap();
+            assert_eq!(&buffer, chunk);
+            assert!(reader.crc_is_valid().unwrap());
+        }
+    }
+
+    // Entries written after a batch continue the id sequence.
+    let writer = wal.begin_entry().unwrap();
+    assert_eq!(writer.id().0, committed[2].id.0 + 1);
+    writer.rollback().unwrap();
+    drop(wal);
+
+    config.open(checkpointer.clone()).unwrap();
+    let invocations = checkpointer.invocations.lock();
+    let recovered = invocations
+        .iter()
+        .filter_map(|call| match call {
+            CheckpointCall::Recover { entry_id, data } => Some((*entry_id, data.clone())),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        recovered,
+        committed
+            .iter()
+            .zip(&batch)
+            .map(|(entry, chunks)| (
+                entry.id,
+                chunks
+                    .iter()
+                    .map(|chunk| chunk.to_vec())
+                    .collect::<Vec<_>>()
+            ))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn append_batch_std() {
+    let dir = tempdir().unwrap();
+    append_batch(StdFileManager::default(), &dir);
+}
+
+#[test]
+fn append_batch_memory() {
+    append_batch(MemoryFileManager::default(), "/");
+}
+
+fn staged_entries<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let checkpointer = LoggingCheckpointer::default();
+    let config = Configuration::default_with_manager(path, manager);
+    let wal = config.clone().open(checkpointer.clone()).unwrap();
+
+    let mut long = wal.begin_staged_entry();
+    long.write_chunk(b"first").unwrap();
+    // An unfinished chunk is removed from the staged entry.
+    let mut unfinished = long.begin_chunk(8);
+    unfinished.write_all(b"partial").unwrap();
+    drop(unfinished);
+    let mut chunk = long.begin_chunk(6);
+    chunk.write_all(b"second").unwrap();
+    chunk.finish().unwrap();
+
+    // While the staged entry is being written, other entries can be committed.
+    let mut short = wal.begin_entry().unwrap();
+    short.write_chunk(b"short").unwrap();
+    let short_id = short.commit().unwrap();
+
+    let long = long.commit().unwrap();
+    assert!(long.id > short_id);
+    assert_eq!(long.chunks.len(), 2);
+    for (record, expected) in long.chunks.iter().zip([&b"first"[..], &b"second"[..]]) {
+        let mut reader = wal.read_at(record.position).unwrap();
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer).unwrap();
+        assert_eq!(buffer, expected);
+        assert!(reader.crc_is_valid().unwrap());
+    }
+
+    // Rolled back staged entries never allocate an id.
+    let mut abandoned = wal.begin_staged_entry();
+    abandoned.write_chunk(b"abandoned").unwrap();
+    abandoned.rollback();
+    let writer = wal.begin_entry().unwrap();
+    assert_eq!(writer.id().0, long.id.0 + 1);
+    writer.rollback().unwrap();
+    drop(wal);
+
+    config.open(checkpointer.clone()).unwrap();
+    let invocations = checkpointer.invocations.lock();
+    let recovered = invocations
+        .iter()
+        .filter_map(|call| match call {
+            CheckpointCall::Recover { entry_id, data } => Some((*entry_id, data.clone())),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        recovered,
+        vec![
+            (short_id, vec![b"short".to_vec()]),
+            (long.id, vec![b"first".to_vec(), b"second".to_vec()])
+        ]
+    );
+}
+
+#[test]
+fn staged_entries_std() {
+    let dir = tempdir().unwrap();
+    staged_entries(StdFileManager::default(), &dir);
+}
+
+#[test]
+fn staged_entries_memory() {
+    staged_entries(MemoryFileManager::default(), "/");
+}
+
+fn subscription<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let config = Configuration::default_with_manager(path, manager);
+    let wal = config.open(LoggingCheckpointer::default()).unwrap();
+
+    let mut writer = wal.begin_entry().unwrap();
+    let first_id = writer.id();
+    writer.write_chunk(b"first").unwrap();
+    writer.commit().unwrap();
+    let mut subscription = wal.subscribe(first_id).unwrap();
+
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"second").unwrap();
+    writer.write_chunk(b"entry").unwrap();
+    let second_id = writer.commit().unwrap();
+
+    let first = subscription.next().unwrap().unwrap();
+    assert_eq!(first.id, first_id);
+    assert_eq!(first.chunks, vec![b"first".to_vec()]);
+    let second = subscription.next().unwrap().unwrap();
+    assert_eq!(second.id, second_id);
+    assert_eq!(second.chunks, vec![b"second".to_vec(), b"entry".to_vec()]);
+    assert!(subscription
+        .next_timeout(Duration::from_millis(10))
+        .unwrap()
+        .is_none());
+
+    // Entries committed while the subscription is blocked are returned.
+    let background = std::thread::spawn({
+        let wal = wal.clone();
+        move || {
+            std::thread::sleep(Duration::from_millis(100));
+            let mut writer = wal.begin_entry().unwrap();
+            writer.write_chunk(b"third").unwrap();
+            writer.commit().unwrap()
+        }
+    });
+    let third = subscription.next().unwrap().unwrap();
+    assert_eq!(third.id, background.join().unwrap());
+    assert_eq!(third.chunks, vec![b"third".to_vec()]);
+
+    // Once an entry the subscription hasn't returned is checkpointed, the
+    // subscription ends.
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"checkpointed").unwrap();
+    let checkpointed_id = writer.commit_and_checkpoint().unwrap();
+    wal.wait_checkpointed_for(&checkpointed_id, Duration::from_secs(10))
+        .unwrap();
+    let err = subscription.next().unwrap().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    assert!(matches!(
+        Error::from_io_error(&err),
+        Some(Error::EntryCheckpointed { .. })
+    ));
+    assert!(subscription.next().is_none());
+    assert_eq!(
+        wal.subscribe(first_id).unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+
+    // Subscribing to entries that haven't been written yet waits for them.
+    let mut subscription = wal.subscribe(EntryId(checkpointed_id.0 + 1)).unwrap();
+    assert!(subscription.try_next().unwrap().is_none());
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"after").unwrap();
+    let after_id = writer.commit().unwrap();
+    let after = subscription.try_next().unwrap().unwrap();
+    assert_eq!(after.id, after_id);
+    assert_eq!(after.chunks, vec![b"after".to_vec()]);
+}
+
+#[test]
+fn subscription_std() {
+    let dir = tempdir().unwrap();
+    subscription(StdFileManager::default(), &dir);
+}
+
+#[test]
+fn subscription_memory() {
+    subscription(MemoryFileManager::default(), "/");
+}
+
+fn read_entry<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let config = Configuration::default_with_manager(path, manager);
+    let wal = config.clone().open(LoggingCheckpointer::default()).unwrap();
+
+    // Write enough entries for several of them to be indexed.
+    let mut entries = Vec::new();
+    for index in 0_u32..200 {
+        let chunks = vec![index.to_le_bytes().to_vec(), vec![42; 500]];
+        let mut writer = wal.begin_entry().unwrap();
+        for chunk in &chunks {
+            writer.write_chunk(chunk).unwrap();
+        }
+        entries.push((writer.commit().unwrap(), chunks));
+    }
+
+    // Entries that were rolled back or haven't been written aren't found.
+    let writer = wal.begin_entry().unwrap();
+    let rolled_back_id = writer.id();
+    writer.rollback().unwrap();
+    for entry_id in [rolled_back_id, EntryId(rolled_back_id.0 + 100)] {
+        let err = wal.read_entry(entry_id).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(matches!(
+            Error::from_io_error(&err),
+            Some(Error::EntryNotFound { .. })
+        ));
+    }
+
+    for (entry_id, chunks) in entries.iter().rev() {
+        let mut reader = wal.read_entry(*entry_id).unwrap();
+        assert_eq!(reader.id(), *entry_id);
+        assert_eq!(reader.read_all_chunks().unwrap().as_ref(), Some(chunks));
+    }
+
+    // The index is rebuilt when the log is recovered.
+    wal.shutdown().unwrap();
+    let wal = config.open(LoggingCheckpointer::default()).unwrap();
+    for (entry_id, chunks) in &entries {
+        let mut reader = wal.read_entry(*entry_id).unwrap();
+        assert_eq!(reader.read_all_chunks().unwrap().as_ref(), Some(chunks));
+    }
+
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"checkpointed").unwrap();
+    let checkpointed_id = writer.commit_and_checkpoint().unwrap();
+    wal.wait_checkpointed_for(&checkpointed_id, Duration::from_secs(10))
+        .unwrap();
+    for entry_id in [entries[0].0, checkpointed_id] {
+        let err = wal.read_entry(entry_id).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(matches!(
+            Error::from_io_error(&err),
+            Some(Error::EntryCheckpointed { .. })
+        ));
+    }
+}
+
+#[test]
+fn read_entry_std() {
+    let dir = tempdir().unwrap();
+    read_entry(StdFileManager::default(), &dir);
+}
+
+#[test]
+fn read_entry_memory() {
+    read_entry(MemoryFileManager::default(), "/");
+}
+
+fn read_at_checkpointed<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let wal = Configuration::default_with_manager(path, manager)
+        .open(LoggingCheckpointer::default())
+        .unwrap();
+
+    let mut writer = wal.begin_entry().unwrap();
+    let record = writer.write_chunk(b"checkpointed").unwrap();
+    let entry_id = writer.commit_and_checkpoint().unwrap();
+    wal.wait_checkpointed_for(&entry_id, Duration::from_secs(10))
+        .unwrap();
+
+    // The checkpointed segment is kept to be reused, but its chunks can no
+    // longer be read.
+    let err = wal.read_at(record.position).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    assert!(matches!(
+        Error::from_io_error(&err),
+        Some(Error::PositionCheckpointed { .. })
+    ));
+}
+
+#[test]
+fn read_at_checkpointed_std() {
+    let dir = tempdir().unwrap();
+    read_at_checkpointed(StdFileManager::default(), &dir);
+}
+
+#[test]
+fn read_at_checkpointed_memory() {
+    read_at_checkpointed(MemoryFileManager::default(), "/");
+}
+
+fn archive<M: FileManager>(manager: &M, path: &Path) {
+    for (name, archiver) in [
+        ("copy", ArchiveDirectory::copy_to(path.join("copy-archive"))),
+        ("move", ArchiveDirectory::move_to(path.join("move-archive"))),
+    ] {
+        let config = Configuration::default_with_manager(path.join(name), manager.clone())
+            .archive_with(archiver.clone());
+        let mut entries = Vec::new();
+        for _ in 0..2 {
+            let wal = config.clone().open(LoggingCheckpointer::default()).unwrap();
+            for _ in 0..2 {
+                let chunk = format!("{name} {}", entries.len()).into_bytes();
+                let mut writer = wal.begin_entry().unwrap();
+                writer.write_chunk(&chunk).unwrap();
+                let entry_id = writer.commit_and_checkpoint().unwrap();
+                wal.wait_checkpointed_for(&entry_id, Duration::from_secs(10))
+                    .unwrap();
+                entries.push((entry_id, chunk));
+            }
+            wal.shutdown().unwrap();
+        }
+
+        // Each checkpointed entry was written to its own segment, which was
+        // archived with its original name.
+        let archived = list_segments(manager, archiver.directory()).unwrap();
+        assert_eq!(archived.len(), entries.len());
+        for (segment, (entry_id, chunk)) in archived.iter().zip(&entries) {
+            assert_eq!(segment.id, entry_id.0);
+            assert!(!segment.checkpointed);
+            let mut reader = SegmentReader::new(&segment.path, segment.id, manager).unwrap();
+            let mut entry = reader.read_entry().unwrap().unwrap();
+            assert_eq!(entry.id(), *entry_id);
+            assert_eq!(entry.read_all_chunks().unwrap(), Some(vec![chunk.clone()]));
+            assert!(reader.read_entry().unwrap().is_none());
+        }
+
+        // Moved segments are not reused.
+        let remaining = list_segments(manager, &config.directory).unwrap();
+        if name == "move" {
+            assert!(remaining.iter().all(|segment| !segment.checkpointed
+                && archived.iter().all(|archived| archived.id != segment.id)));
+        }
+    }
+}
+
+#[test]
+fn archive_std() {
+    let dir = tempdir().unwrap();
+    archive(&StdFileManager::default(), dir.path());
+}
+
+#[test]
+fn archive_memory() {
+    archive(&MemoryFileManager::default(), Path::new("/"));
+}
+
+fn replay<M: FileManager>(manager: &M, path: &Path) {
+    let archive_config = Configuration::default_with_manager(path.join("archive"), manager.clone());
+    let config = Configuration::default_with_manager(path.join("log"), manager.clone())
+        .archive_with(ArchiveDirectory::copy_to(&archive_config.directory));
+    let wal = config.clone().open(LoggingCheckpointer::default()).unwrap();
+
+    // Write three segments of three entries, archiving the first two.
+    let mut entries = Vec::new();
+    for segment in 0..3 {
+        for index in 0..3 {
+            let chunk = format!("{segment} {index}").into_bytes();
+            let mut writer = wal.begin_entry().unwrap();
+            writer.write_chunk(&chunk).unwrap();
+            let entry_id = if index == 2 && segment < 2 {
+                let entry_id = writer.commit_and_checkpoint().unwrap();
+                wal.wait_checkpointed_for(&entry_id, Duration::from_secs(10))
+                    .unwrap();
+                entry_id
+            } else {
+                writer.commit().unwrap()
+            };
+            entries.push((entry_id, vec![chunk]));
+
+            // Rolled back entries are skipped.
+            let mut writer = wal.begin_entry().unwrap();
+            writer.write_chunk(b"rolled back").unwrap();
+            writer.rollback().unwrap();
+        }
+    }
+    wal.shutdown().unwrap();
+
+    let last_id = entries.last().unwrap().0;
+    let mut checkpointer = LoggingCheckpointer::default();
+    let replayed = crate::replay(&archive_config, EntryId(0), last_id, &mut checkpointer).unwrap();
+    assert_eq!(replayed, Some(entries[5].0));
+    assert_eq!(recovered_entries(&checkpointer), &entries[..6]);
+
+    let mut checkpointer = LoggingCheckpointer::default();
+    let replayed = crate::replay(
+        &archive_config,
+        entries[1].0,
+        entries[4].0,
+        &mut checkpointer,
+    )
+    .unwrap();
+    assert_eq!(replayed, Some(entries[4].0));
+    assert_eq!(recovered_entries(&checkpointer), &entries[1..=4]);
+
+    // Only the segment that wasn't checkpointed remains in the log's directory.
+    let mut checkpointer = LoggingCheckpointer::default();
+    let replayed = crate::replay(&config, EntryId(0), last_id, &mut checkpointer).unwrap();
+    assert_eq!(replayed, Some(last_id));
+    assert_eq!(recovered_entries(&checkpointer), &entries[6..]);
+
+    let mut checkpointer = LoggingCheckpointer::default();
+    let replayed = crate::replay(
+        &config,
+        EntryId(last_id.0 + 1),
+        EntryId(u64::MAX),
+        &mut checkpointer,
+    )
+    .unwrap();
+    assert_eq!(replayed, None);
+}
+
+#[test]
+fn replay_std() {
+    let dir = tempdir().unwrap();
+    replay(&StdFileManager::default(), dir.path());
+}
+
+#[test]
+fn replay_memory() {
+    replay(&MemoryFileManager::default(), Path::new("/"));
+}
+
+fn recovered_entries(checkpointer: &LoggingCheckpointer) -> Vec<(EntryId, Vec<Vec<u8>>)> {
+    checkpointer
+        .invocations
+        .lock()
+        .iter()
+        .filter_map(|call| match call {
+            CheckpointCall::Recover { entry_id, data } => Some((*entry_id, data.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+fn replication<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let primary_path = path.as_ref().join("primary");
+    let follower_path = path.as_ref().join("follower");
+    let follower_config = Configuration::default_with_manager(&follower_path, manager.clone());
+    let follower_checkpointer = LoggingCheckpointer::default();
+    let follower =
+        WriteAheadLog::open_follower(follower_config.clone(), follower_checkpointer.clone())
+            .unwrap();
+
+    let wal = Configuration::default_with_manager(&primary_path, manager)
+        .replicate_to(follower.clone())
+        .open(LoggingCheckpointer::default())
+        .unwrap();
+    let mut expected = Vec::new();
+    for (data, checkpoint) in [(&b"first"[..], false), (b"second", true), (b"third", false)] {
+        let mut writer = wal.begin_entry().unwrap();
+        writer.write_chunk(data).unwrap();
+        let entry_id = if checkpoint {
+            writer.commit_and_checkpoint().unwrap()
+        } else {
+            writer.commit().unwrap()
+        };
+        expected.push((entry_id, vec![data.to_vec()]));
+
+        // Entries are recovered by the follower once they are replicated,
+        // including the first entry of the segment activated by the
+        // checkpoint.
+        wal.wait_for_replication().unwrap();
+        assert_eq!(recovered_entries(&follower_checkpointer), expected);
+    }
+    assert_eq!(follower.last_recovered_entry_id(), Some(expected[2].0));
+    wal.shutdown().unwrap();
+    drop(follower);
+
+    // Reopening the follower recovers the received segments from disk.
+    let follower_checkpointer = LoggingCheckpointer::default();
+    let follower =
+        WriteAheadLog::open_follower(follower_config, follower_checkpointer.clone()).unwrap();
+    assert_eq!(recovered_entries(&follower_checkpointer), expected);
+
+    // Promoting does not recover the same entries again.
+    let promoted = follower.promote().unwrap();
+    assert_eq!(recovered_entries(&follower_checkpointer), expected);
+    let mut writer = promoted.begin_entry().unwrap();
+    assert!(writer.id() > expected[2].0);
+    writer.write_chunk(b"promoted").unwrap();
+    writer.commit().unwrap();
+}
+
+#[test]
+fn replication_std() {
+    let dir = tempdir().unwrap();
+    replication(StdFileManager::default(), &dir);
+}
+
+#[test]
+fn replication_memory() {
+    replication(MemoryFileManager::default(), "/");
+}
+
+#[derive(Debug, Default)]
+struct FailingReplicator {
+    attempts: std::sync::atomic::AtomicUsize,
+}
+
+impl crate::Replicator for Arc<FailingReplicator> {
+    fn replicate(&self, _range: &crate::SegmentRange<'_>) -> io::Result<()> {
+        self.attempts
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        Err(io::Error::new(ErrorKind::Other, "replica unavailable"))
+    }
+}
+
+#[test]
+fn replication_failure() {
+    let replicator = Arc::new(FailingReplicator::default());
+    let wal = Configuration::default_with_manager("/", MemoryFileManager::default())
+        .replicate_to(replicator.clone())
+        .open(LoggingCheckpointer::default())
+        .unwrap();
+    assert!(wal.replication_error().is_none());
+
+    // Commits succeed even though their entries can't be replicated.
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"first").unwrap();
+    let first = writer.commit().unwrap();
+    let err = wal.wait_for_replication().unwrap_err();
+    assert!(matches!(
+        Error::from_io_error(&err),
+        Some(Error::ReplicationFailed(_))
+    ));
+    assert!(matches!(
+        wal.replication_error(),
+        Some(Error::ReplicationFailed(_))
+    ));
+
+    // No further ranges are replicated once replication has failed.
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"second").unwrap();
+    let second = writer.commit().unwrap();
+    assert!(wal.wait_for_replication().is_err());
+    assert_eq!(
+        replicator
+            .attempts
+            .load(std::sync::atomic::Ordering::SeqCst),
+        1
+    );
+
+    let mut reader = wal.read_entry(second).unwrap();
+    assert_eq!(
+        reader.read_all_chunks().unwrap(),
+        Some(vec![b"second".to_vec()])
+    );
+    assert!(first < second);
+}
+
+#[test]
+fn replication_reuses_segment_handle() {
+    let follower = WriteAheadLog::open_follower(
+        Configuration::default_with_manager("/", MemoryFileManager::default()),
+        LoggingCheckpointer::default(),
+    )
+    .unwrap();
+    let manager = FaultyFileManager::default();
+    let wal = Configuration::default_with_manager("/", manager.clone())
+        .replicate_to(follower)
+        .open(LoggingCheckpointer::default())
+        .unwrap();
+    commit_within_timeout(&wal).unwrap();
+    wal.wait_for_replication().unwrap();
+
+    // Syncing duplicates the segment's handle, but replicating the synced
+    // range reads through the handle opened for the segment's first range
+    // rather than opening the segment again.
+    manager.fail_after(FileOperation::Open, 1);
+    commit_within_timeout(&wal).unwrap();
+    wal.wait_for_replication().unwrap();
+    assert!(wal.replication_error().is_none());
+}
+
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+fn compression<M: FileManager, P: AsRef<Path>>(
+    compression: crate::Compression,
+    manager: M,
+    path: P,
+) {
+    let checkpointer = LoggingCheckpointer::default();
+    let config = Configuration::default_with_manager(path, manager).compression(compression);
+    let wal = config.clone().open(checkpointer.clone()).unwrap();
+
+    let compressible = b"{\"key\":\"value\"}".repeat(64);
+    let incompressible = b"short";
+
+    let mut writer = wal.begin_entry().unwrap();
+    let mut chunk = writer
+        .begin_chunk(u32::try_from(compressible.len()).unwrap())
+        .unwrap();
+    chunk.write_all(&compressible).unwrap();
+    let streamed = chunk.finish().unwrap();
+    let raw = writer.write_chunk(incompressible).unwrap();
+    let first_id = writer.commit().unwrap();
+
+    let mut staged = wal.begin_staged_entry();
+    staged.write_chunk(&compressible).unwrap();
+    let staged = staged.commit().unwrap();
+    let batch = wal.append_batch([[&compressible]]).unwrap();
+
+    // The CRC covers the stored bytes, which for compressed chunks differ from
+    // the data that was written.
+    assert_ne!(streamed.crc, crc32c::crc32c(&compressible));
+    assert_eq!(raw.crc, crc32c::crc32c(incompressible));
+    let records = [
+        (streamed, &compressible[..]),
+        (raw, &incompressible[..]),
+        (staged.chunks[0], &compressible[..]),
+        (batch[0].chunks[0], &compressible[..]),
+    ];
+    for (record, expected) in records {
+        assert_eq!(record.length, u32::try_from(expected.len()).unwrap());
+        let mut reader = wal.read_at(record.position).unwrap();
+        assert_eq!(reader.chunk_length(), record.length);
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer).unwrap();
+        assert_eq!(buffer, expected);
+        assert!(reader.crc_is_valid().unwrap());
+    }
+    drop(wal);
+
+    let wal = config.open(checkpointer.clone()).unwrap();
+    assert_eq!(
+        recovered_entries(&checkpointer),
+        vec![
+            (
+                first_id,
+                vec![compressible.clone(), incompressible.to_vec()]
+            ),
+            (staged.id, vec![compressible.clone()]),
+            (batch[0].id, vec![compressible]),
+        ]
+    );
+    wal.shutdown().unwrap();
+}
+
+#[test]
+#[cfg(feature = "lz4")]
+fn compression_lz4_std() {
+    let dir = tempdir().unwrap();
+    compression(crate::Compression::Lz4, StdFileManager::default(), &dir);
+}
+
+#[test]
+#[cfg(feature = "lz4")]
+fn compression_lz4_memory() {
+    compression(crate::Compression::Lz4, MemoryFileManager::default(), "/");
+}
+
+#[test]
+#[cfg(feature = "zstd")]
+fn compression_zstd_std() {
+    let dir = tempdir().unwrap();
+    compression(crate::Compression::Zstd(3), StdFileManager::default(), &dir);
+}
+
+#[test]
+#[cfg(feature = "zstd")]
+fn compression_zstd_memory() {
+    compression(
+        crate::Compression::Zstd(3),
+        MemoryFileManager::default(),
+        "/",
+    );
+}
+
+#[cfg(any(feature = "aes256-gcm", feature = "chacha20-poly1305"))]
+fn encryption<M, P, C>(manager: M, path: P, cipher: C, rotated_cipher: C)
+where
+    M: FileManager,
+    P: AsRef<Path>,
+    C: crate::Cipher,
+{
+    let checkpointer = LoggingCheckpointer::default();
+    let config = Configuration::default_with_manager(path, manager);
+    let wal = config
+        .clone()
+        .encrypt_with(cipher)
+        .open(checkpointer.clone())
+        .unwrap();
+
+    let secret = b"a secret that should not be stored in plaintext";
+    let mut writer = wal.begin_entry().unwrap();
+    let record = writer.write_chunk(secret).unwrap();
+    let first_id = writer.commit().unwrap();
+    let mut staged = wal.begin_staged_entry();
+    staged.write_chunk(secret).unwrap();
+    let staged = staged.commit().unwrap();
+    let batch = wal.append_batch([[secret]]).unwrap();
+
+    for record in [record, staged.chunks[0], batch[0].chunks[0]] {
+        let mut reader = wal.read_at(record.position).unwrap();
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer).unwrap();
+        assert_eq!(buffer, secret);
+        assert!(reader.crc_is_valid().unwrap());
+    }
+    drop(wal);
+
+    let mut segment = Vec::new();
+    config
+        .file_manager
+        .open(
+            &file_manager::PathId::from(config.directory.join("wal-1")),
+            file_manager::OpenOptions::new().read(true),
+        )
+        .unwrap()
+        .read_to_end(&mut segment)
+        .unwrap();
+    assert!(!segment.windows(secret.len()).any(|window| window == secret));
+
+    // Without the cipher, the encrypted entries can't be recovered.
+    let err = config
+        .clone()
+        .open(LoggingCheckpointer::default())
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Unsupported);
+
+    // After rotating keys, the segment is still decrypted using the key id
+    // stored in its header.
+    let wal = config
+        .encrypt_with(rotated_cipher)
+        .open(checkpointer.clone())
+        .unwrap();
+    assert_eq!(
+        recovered_entries(&checkpointer),
+        vec![
+            (first_id, vec![secret.to_vec()]),
+            (staged.id, vec![secret.to_vec()]),
+            (batch[0].id, vec![secret.to_vec()]),
+        ]
+    );
+    let mut writer = wal.begin_entry().unwrap();
+    let record = writer.write_chunk(secret).unwrap();
+    writer.commit().unwrap();
+    let mut reader = wal.read_at(record.position).unwrap();
+    let mut buffer = Vec::new();
+    reader.read_to_end(&mut buffer).unwrap();
+    assert_eq!(buffer, secret);
+    drop(reader);
+    wal.shutdown().unwrap();
+}
+
+#[cfg(feature = "aes256-gcm")]
+fn aes256_gcm_ciphers() -> (crate::Aes256GcmCipher, crate::Aes256GcmCipher) {
+    (
+        crate::Aes256GcmCipher::new(1, &[1; 32].into()),
+        crate::Aes256GcmCipher::new(2, &[2; 32].into()).with_previous_key(1, &[1; 32].into()),
+    )
+}
+
+#[test]
+#[cfg(feature = "aes256-gcm")]
+fn encryption_aes256_gcm_std() {
+    let dir = tempdir().unwrap();
+    let (cipher, rotated_cipher) = aes256_gcm_ciphers();
+    encryption(StdFileManager::default(), &dir, cipher, rotated_cipher);
+}
+
+#[test]
+#[cfg(feature = "aes256-gcm")]
+fn encryption_aes256_gcm_memory() {
+    let (cipher, rotated_cipher) = aes256_gcm_ciphers();
+    encryption(MemoryFileManager::default(), "/", cipher, rotated_cipher);
+}
+
+#[test]
+#[cfg(feature = "chacha20-poly1305")]
+fn encryption_chacha20_poly1305_memory() {
+    encryption(
+        MemoryFileManager::default(),
+        "/",
+        crate::ChaCha20Poly1305Cipher::new(1, &[1; 32].into()),
+        crate::ChaCha20Poly1305Cipher::new(2, &[2; 32].into())
+            .with_previous_key(1, &[1; 32].into()),
+    );
+}
+
+#[derive(Debug)]
+struct FailingCheckpointer {
+    call_count: u32,
+    call_limit: u32,
+}
+
+impl FailingCheckpointer {
+    pub fn new(call_limit: u32) -> Self {
+        Self {
+            call_count: 0,
+            call_limit,
+        }
+    }
+}
+
+impl LogManager for FailingCheckpointer {
+    fn recover(&mut self, _entry: &mut Entry<'_>) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn checkpoint_to(
+        &mut self,
+        _last_checkpointed_id: EntryId,
+        _reader: &mut SegmentReader,
+        _wal: &WriteAheadLog,
+    ) -> std::io::Result<()> {
+        self.call_count += 1;
+        if self.call_count >= self.call_limit {
+            println!("Call limit reached, returning err so that that checkpointer thread returns");
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, ""));
+        }
+        println!(
+            "Call limit not reached yet. current count: {:?}, limit: {:?}",
+            self.call_count, self.call_limit
+        );
+        Ok(())
+    }
+}
+
+#[test]
+fn test_is_checkpoint_thread_running() {
+    let dir = tempdir().unwrap();
+    let checkpointer = FailingCheckpointer::new(2);
+    let config = Configuration::default_with_manager(dir.as_ref(), StdFileManager::default())
+        .checkpoint_after_bytes(33);
+
+    // First write doesn't cause the checkpointing thread to halt.
+    let wal = config.open(checkpointer).unwrap();
+    let mut writer = wal.begin_entry().unwrap();
+    let _record = writer.write_chunk(&0i32.to_be_bytes()).unwrap();
+    let written_entry_id = writer.commit_and_checkpoint().unwrap();
+    wal.wait_checkpointed_for(&written_entry_id, Duration::from_secs(10 * 60))
+        .unwrap();
+    assert!(wal.is_checkpoint_thread_running());
+
+    // Second write causes the checkpoitning thread to halt.
+    let mut writer = wal.begin_entry().unwrap();
+    let _record = writer.write_chunk(&0i32.to_be_bytes()).unwrap();
+    let written_entry_id = writer.commit_and_checkpoint().unwrap();
+    // Since the thread is halted, waiting for the checkpoint returns its error.
+    let err = wal
+        .wait_checkpointed_for(&written_entry_id, Duration::from_secs(10))
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+    assert!(matches!(
+        Error::from_io_error(&err),
+        Some(Error::CheckpointerFailed(_))
+    ));
+    assert!(!wal.is_checkpoint_thread_running());
+}
+
+#[derive(Debug)]
+struct PanickingCheckpointer;
+
+impl LogManager for PanickingCheckpointer {
+    fn recover(&mut self, _entry: &mut Entry<'_>) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn checkpoint_to(
+        &mut self,
+        _last_checkpointed_id: EntryId,
+        _reader: &mut SegmentReader,
+        _wal: &WriteAheadLog,
+    ) -> std::io::Result<()> {
+        panic!("checkpointer panicked");
+    }
+}
+
+#[test]
+fn checkpoint_thread_panic() {
+    let dir = tempdir().unwrap();
+    let config = Configuration::default_with_manager(dir.as_ref(), StdFileManager::default())
+        .checkpoint_after_bytes(33);
+    let wal = config.open(PanickingCheckpointer).unwrap();
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(&0i32.to_be_bytes()).unwrap();
+    writer.commit_and_checkpoint().unwrap();
+
+    // A panic doesn't record an error, but the thread is no longer running.
+    let started_at = std::time::Instant::now();
+    while wal.is_checkpoint_thread_running() {
+        assert!(started_at.elapsed() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    wal.restart_checkpointer().unwrap();
+    assert!(wal.is_checkpoint_thread_running());
+}
+
+fn stats<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let wal = Configuration::default_with_manager(path, manager)
+        .open(LoggingCheckpointer::default())
+        .unwrap();
+    let initial = wal.stats();
+    assert_eq!(initial.entries_committed, 0);
+    assert_eq!(initial.segments.len(), 1);
+
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"hello").unwrap();
+    let entry_id = writer.commit_and_checkpoint().unwrap();
+    wal.append_batch([[b"world"], [b"again"]]).unwrap();
+    wal.wait_checkpointed_for(&entry_id, Duration::from_secs(10))
+        .unwrap();
+
+    let stats = wal.stats();
+    assert_eq!(stats.entries_committed, 3);
+    assert!(stats.average_entry_write_time().is_some());
+    assert!(stats.fsyncs >= 2);
+    assert!(stats.sync_requests >= stats.fsyncs);
+    assert!(stats.fsync_coalescing_ratio().unwrap() >= 1.);
+    assert_eq!(stats.synchronized_commits, 2);
+    assert!(stats.commits_per_fsync().unwrap() <= 1.);
+    assert_eq!(stats.checkpoints, 1);
+    assert!(stats.directory_syncs >= 1);
+    assert_eq!(stats.pending_checkpoints, 0);
+    assert_eq!(stats.segments.len(), 2);
+    assert!(stats.segments[0].checkpointed);
+    let active = stats.segments[1];
+    assert!(!active.checkpointed);
+    assert!(active.bytes > initial.segments[0].bytes);
+    assert_eq!(active.readers, 0);
+}
+
+#[test]
+fn stats_std() {
+    let dir = tempdir().unwrap();
+    stats(StdFileManager::default(), &dir);
+}
+
+#[test]
+fn stats_memory() {
+    stats(MemoryFileManager::default(), "/");
+}
+
+fn group_commit<M: FileManager>(manager: &M, path: &Path) {
+    const COMMITTERS: u64 = 4;
+
+    fn commit(wal: &WriteAheadLog<impl FileManager>) {
+        let mut writer = wal.begin_entry().unwrap();
+        writer.write_chunk(b"grouped").unwrap();
+        writer.commit().unwrap();
+    }
+
+    // Measure how many bytes each commit writes.
+    let wal = Configuration::default_with_manager(path.join("measured"), manager.clone())
+        .open(LoggingCheckpointer::default())
+        .unwrap();
+    let initial_bytes = wal.stats().segments[0].bytes;
+    commit(&wal);
+    let entry_bytes = wal.stats().segments[0].bytes - initial_bytes;
+    wal.shutdown().unwrap();
+
+    // Waiting until every committer's entry has been written causes all of
+    // them to be synchronized by one fsync.
+    let wal = Configuration::default_with_manager(path, manager.clone())
+        .group_commit_window(
+            GroupCommitWindow::new(Duration::from_secs(60)).max_bytes(entry_bytes * COMMITTERS),
+        )
+        .open(LoggingCheckpointer::default())
+        .unwrap();
+    let threads = (0..COMMITTERS)
+        .map(|_| {
+            let wal = wal.clone();
+            std::thread::spawn(move || commit(&wal))
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let stats = wal.stats();
+    assert_eq!(stats.fsyncs, 1);
+    assert_eq!(stats.synchronized_commits, COMMITTERS);
+    assert!((stats.commits_per_fsync().unwrap() - 4.).abs() < f64::EPSILON);
+}
+
+#[test]
+fn group_commit_std() {
+    let dir = tempdir().unwrap();
+    group_commit(&StdFileManager::default(), dir.path());
+}
+
+#[test]
+fn group_commit_memory() {
+    group_commit(&MemoryFileManager::default(), Path::new("/"));
+}
+
+#[cfg(unix)]
+fn write_through(mode: WriteThrough) {
+    let dir = tempdir().unwrap();
+    // Not every filesystem supports direct I/O.
+    let probe = PathId::from(dir.path().join("probe"));
+    match WriteThroughFileManager::new(mode)
+        .open(&probe, OpenOptions::new().create(true).write(true))
+    {
+        Ok(_) => std::fs::remove_file(&*probe).unwrap(),
+        Err(err) if err.kind() == ErrorKind::InvalidInput => return,
+        Err(err) => unreachable!("error opening probe: {err}"),
+    }
+
+    // Write entries that start and end in the middle of blocks, and read them
+    // back through the same file manager.
+    let wal = Configuration::default_write_through_for(&dir, mode)
+        .open(LoggingCheckpointer::default())
+        .unwrap();
+    let mut expected = Vec::new();
+    for length in [1_u32, 4095, 4097, 20_000] {
+        let chunk = (0..length)
+            .map(|i| u8::try_from(i % 251).unwrap())
+            .collect::<Vec<_>>();
+        let mut writer = wal.begin_entry().unwrap();
+        let record = writer.write_chunk(&chunk).unwrap();
+        let entry_id = writer.commit().unwrap();
+        let mut reader = wal.read_at(record.position).unwrap();
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer).unwrap();
+        assert_eq!(buffer, chunk);
+        expected.push((entry_id, vec![chunk]));
+    }
+    wal.shutdown().unwrap();
+
+    // The segments can be recovered without write-through.
+    let checkpointer = LoggingCheckpointer::default();
+    let _wal = Configuration::default_for(&dir)
+        .open(checkpointer.clone())
+        .unwrap();
+    assert_eq!(recovered_entries(&checkpointer), expected);
+}
+
+/// Exercises the block handling used by [`WriteThrough::Direct`] using a file
+/// opened without direct I/O, which every filesystem supports.
+#[test]
+#[cfg(unix)]
+fn direct_blocks() {
+    use std::os::unix::fs::FileExt;
+
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("blocks");
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .unwrap();
+    let mut blocks = DirectBlocks::default();
+    let mut expected = Vec::new();
+    let write =
+        |blocks: &mut DirectBlocks, expected: &mut Vec<u8>, position: usize, length: usize| {
+            let bytes = (0..length)
+                .map(|i| u8::try_from((position + i) % 251).unwrap())
+                .collect::<Vec<_>>();
+            blocks.write(&file, position as u64, &bytes).unwrap();
+            if expected.len() < position + length {
+                expected.resize(position + length, 0);
+            }
+            expected[position..position + length].copy_from_slice(&bytes);
+
+            // Only whole blocks are written, and the bytes around the write are
+            // preserved.
+            let contents = std::fs::read(&path).unwrap();
+            assert_eq!(contents.len() % 4096, 0);
+            assert_eq!(contents[..expected.len()], expected[..]);
+            assert!(contents[expected.len()..].iter().all(|byte| *byte == 0));
+        };
+
+    // Writes that start and end within blocks, continuing from the last
+    // block written.
+    write(&mut blocks, &mut expected, 0, 1);
+    write(&mut blocks, &mut expected, 1, 4095);
+    write(&mut blocks, &mut expected, 4096, 4097);
+    write(&mut blocks, &mut expected, 8193, 10_000);
+    // Writes that read the blocks they touch from the file: one within a
+    // block, one spanning several blocks, and one past the end of the file.
+    write(&mut blocks, &mut expected, 100, 200);
+    write(&mut blocks, &mut expected, 4000, 9000);
+    write(&mut blocks, &mut expected, 30_000, 10);
+
+    // A cached block that no longer matches the file must be invalidated.
+    file.write_all_at(b"changed", 30_010).unwrap();
+    expected.extend_from_slice(b"changed");
+    blocks.invalidate();
+    write(&mut blocks, &mut expected, 30_017, 3);
+
+    // Reads return the requested bytes, stopping at the end of the file.
+    let length = usize::try_from(std::fs::metadata(&path).unwrap().len()).unwrap();
+    expected.resize(length, 0);
+    for (position, requested) in [
+        (0, 1),
+        (1, 4096),
+        (4095, 2),
+        (8000, 20_000),
+        (30_000, 10_000),
+    ] {
+        let mut buffer = vec![0; requested];
+        let bytes_read = blocks.read(&file, position as u64, &mut buffer).unwrap();
+        assert_eq!(bytes_read, requested.min(length - position));
+        assert_eq!(
+            buffer[..bytes_read],
+            expected[position..position + bytes_read]
+        );
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn write_through_data_sync() {
+    write_through(WriteThrough::DataSync);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn write_through_direct() {
+    write_through(WriteThrough::Direct);
+}
+
+/// Fails a number of checkpoints before succeeding.
+#[derive(Debug, Clone)]
+struct FlakyCheckpointer {
+    failures_remaining: Arc<Mutex<u32>>,
+}
+
+impl FlakyCheckpointer {
+    fn new(failures: u32) -> Self {
+        Self {
+            failures_remaining: Arc::new(Mutex::new(failures)),
+        }
+    }
+}
+
+impl<M> LogManager<M> for FlakyCheckpointer
+where
+    M: FileManager,
+{
+    fn recover(&mut self, _entry: &mut Entry<'_, M::File>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn checkpoint_to(
+        &mut self,
+        _last_checkpointed_id: EntryId,
+        _checkpointed_entries: &mut SegmentReader<M::File>,
+        _wal: &WriteAheadLog<M>,
+    ) -> io::Result<()> {
+        let mut failures_remaining = self.failures_remaining.lock();
+        if *failures_remaining > 0 {
+            *failures_remaining -= 1;
+            Err(io::Error::new(ErrorKind::Other, "flaky checkpoint"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn restart_checkpointer<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let wal = Configuration::default_with_manager(path, manager)
+        .open(FlakyCheckpointer::new(1))
+        .unwrap();
+
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"hello").unwrap();
+    let entry_id = writer.commit_and_checkpoint().unwrap();
+
+    // The failure is returned until the checkpointer is restarted.
+    let err = wal
+        .wait_checkpointed_for(&entry_id, Duration::from_secs(10))
+        .unwrap_err();
+    assert!(matches!(
+        Error::from_io_error(&err),
+        Some(Error::CheckpointerFailed(_))
+    ));
+    let err = wal.begin_entry().unwrap_err();
+    assert!(matches!(
+        Error::from_io_error(&err),
+        Some(Error::CheckpointerFailed(_))
+    ));
+    assert!(!wal.is_checkpoint_thread_running());
+
+    // Restarting checkpoints the segment that failed.
+    wal.restart_checkpointer().unwrap();
+    wal.wait_checkpointed_for(&entry_id, Duration::from_secs(10))
+        .unwrap();
+    assert!(wal.is_checkpoint_thread_running());
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"world").unwrap();
+    writer.commit().unwrap();
+    wal.shutdown().unwrap();
+}
+
+#[test]
+fn restart_checkpointer_std() {
+    let dir = tempdir().unwrap();
+    restart_checkpointer(StdFileManager::default(), &dir);
+}
+
+#[test]
+fn restart_checkpointer_memory() {
+    restart_checkpointer(MemoryFileManager::default(), "/");
+}
+
+fn checkpoint_retry<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let wal = Configuration::default_with_manager(path, manager)
+        .checkpoint_retry(CheckpointRetry {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(10),
+        })
+        .open(FlakyCheckpointer::new(2))
+        .unwrap();
+
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"hello").unwrap();
+    let entry_id = writer.commit_and_checkpoint().unwrap();
+    wal.wait_checkpointed_for(&entry_id, Duration::from_secs(10))
+        .unwrap();
+    assert!(wal.is_checkpoint_thread_running());
+    wal.shutdown().unwrap();
+}
+
+#[test]
+fn checkpoint_retry_std() {
+    let dir = tempdir().unwrap();
+    checkpoint_retry(StdFileManager::default(), &dir);
+}
+
+#[test]
+fn checkpoint_retry_memory() {
+    checkpoint_retry(MemoryFileManager::default(), "/");
+}
+
+/// Records the segments passed to each call to
+/// [`LogManager::checkpoint_to_many()`], and fails batches of more than one
+/// segment after checkpointing only the first.
+#[derive(Debug, Clone)]
+struct BatchCheckpointer {
+    gate: Arc<Mutex<()>>,
+    batches: Arc<Mutex<Vec<Vec<EntryId>>>>,
+    failures_remaining: Arc<Mutex<u32>>,
+}
+
+impl<M> LogManager<M> for BatchCheckpointer
+where
+    M: FileManager,
+{
+    fn recover(&mut self, _entry: &mut Entry<'_, M::File>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn checkpoint_to(
+        &mut self,
+        _last_checkpointed_id: EntryId,
+        _checkpointed_entries: &mut SegmentReader<M::File>,
+        _wal: &WriteAheadLog<M>,
+    ) -> io::Result<()> {
+        unreachable!("checkpoint_to_many is implemented")
+    }
+
+    fn checkpoint_to_many(
+        &mut self,
+        segments: &mut [SegmentCheckpoint<M::File>],
+        _wal: &WriteAheadLog<M>,
+    ) -> io::Result<()> {
+        drop(self.gate.lock());
+        self.batches.lock().push(
+            segments
+                .iter()
+                .map(|segment| segment.last_checkpointed_id)
+                .collect(),
+        );
+        let mut failures_remaining = self.failures_remaining.lock();
+        if segments.len() > 1 && *failures_remaining > 0 {
+            *failures_remaining -= 1;
+            segments[0].mark_checkpointed();
+            Err(io::Error::new(ErrorKind::Other, "partial checkpoint"))
+        } else {
+            for segment in segments {
+                segment.mark_checkpointed();
+            }
+            Ok(())
+        }
+    }
+}
+
+fn checkpoint_batch<M: FileManager, P: AsRef<Path>>(manager: M, path: P) {
+    let checkpointer = BatchCheckpointer {
+        gate: Arc::default(),
+        batches: Arc::default(),
+        failures_remaining: Arc::new(Mutex::new(1)),
+    };
+    let wal = Configuration::default_with_manager(path, manager)
+        .checkpoint_batch_size(4)
+        .checkpoint_retry(CheckpointRetry {
+            max_retries: 0,
+            ..CheckpointRetry::default()
+        })
+        .open(checkpointer.clone())
+        .unwrap();
+
+    // Hold the checkpointer in the first checkpoint while two more segments
+    // are queued behind it.
+    let gate = checkpointer.gate.lock();
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"first").unwrap();
+    let first = writer.commit_and_checkpoint().unwrap();
+    while wal.pending_checkpoints() > 0 {
+        std::thread::yield_now();
+    }
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"second").unwrap();
+    let second = writer.commit_and_checkpoint().unwrap();
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"third").unwrap();
+    let third = writer.commit_and_checkpoint().unwrap();
+    drop(gate);
+
+    // The queued segments are checkpointed together. Only the segment marked
+    // before the failure is treated as checkpointed.
+    wal.wait_checkpointed_for(&second, Duration::from_secs(10))
+        .unwrap();
+    let err = wal
+        .wait_checkpointed_for(&third, Duration::from_secs(10))
+        .unwrap_err();
+    assert!(matches!(
+        Error::from_io_error(&err),
+        Some(Error::CheckpointerFailed(_))
+    ));
+
+    // Restarting checkpoints the remaining segment.
+    wal.restart_checkpointer().unwrap();
+    wal.wait_checkpointed_for(&third, Duration::from_secs(10))
+        .unwrap();
+    assert_eq!(
+        &*checkpointer.batches.lock(),
+        &[vec![first], vec![second, third], vec![third]]
+    );
+    wal.shutdown().unwrap();
+}
+
+#[test]
+fn checkpoint_batch_std() {
+    let dir = tempdir().unwrap();
+    checkpoint_batch(StdFileManager::default(), &dir);
+}
+
+#[test]
+fn checkpoint_batch_memory() {
+    checkpoint_batch(MemoryFileManager::default(), "/");
+}
+
+/// Tracks which committed entries a [`WriteAheadLog`] must be able to
+/// recover after a crash.
+#[derive(Debug, Default, Clone)]
+struct CrashCheckpointer {
+    /// Committed entries that have not been checkpointed.
+    committed: Arc<Mutex<BTreeMap<EntryId, Vec<Vec<u8>>>>>,
+    recovered: Arc<Mutex<BTreeMap<EntryId, Vec<Vec<u8>>>>>,
+}
+
+impl<M> LogManager<M> for CrashCheckpointer
+where
+    M: FileManager,
+{
+    fn recover(&mut self, entry: &mut Entry<'_, M::File>) -> io::Result<()> {
+        if let Some(chunks) = read_intact_chunks(entry)? {
+            self.recovered.lock().insert(entry.id(), chunks);
+        }
+        Ok(())
+    }
+
+    fn checkpoint_to(
+        &mut self,
+        _last_checkpointed_id: EntryId,
+        checkpointed_entries: &mut SegmentReader<M::File>,
+        _wal: &WriteAheadLog<M>,
+    ) -> io::Result<()> {
+        let mut checkpointed = Vec::new();
+        while let Some(mut entry) = checkpointed_entries.read_entry()? {
+            if let Some(chunks) = read_intact_chunks(&mut entry)? {
+                checkpointed.push((entry.id(), chunks));
+            }
+        }
+
+        let mut committed = self.committed.lock();
+        for (entry_id, chunks) in checkpointed {
+            if let Some(expected) = committed.remove(&entry_id) {
+                assert_eq!(expected, chunks, "checkpointed {entry_id:?} does not match");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads all chunks of `entry`, returning `None` if it was torn by a crash.
+/// Only entries that were never committed can be torn, because committing
+/// synchronizes the entry to disk.
+fn read_intact_chunks<F: file_manager::File>(
+    entry: &mut Entry<'_, F>,
+) -> io::Result<Option<Vec<Vec<u8>>>> {
+    match entry.read_all_chunks() {
+        Ok(chunks) => Ok(chunks),
+        Err(err) if matches!(Error::from_io_error(&err), Some(Error::CrcMismatch { .. })) => {
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Writes entries to a log while injecting failures and crashing at random,
+/// verifying after each crash that every committed entry that wasn't
+/// checkpointed is recovered.
+///
+/// A fixed set of seeds is used so that failures are reproducible. Setting
+/// `OKAYWAL_CRASH_SEED` runs only that seed instead.
+#[test]
+fn crash_recovery() {
+    const SEEDS: [u64; 8] = [0, 1, 2, 3, 42, 7919, 0x5eed_cafe, 0xdead_beef_f00d];
+
+    match std::env::var("OKAYWAL_CRASH_SEED") {
+        Ok(seed) => crash_recovery_with_seed(seed.parse().expect("invalid OKAYWAL_CRASH_SEED")),
+        Err(_) => {
+            for seed in SEEDS {
+                crash_recovery_with_seed(seed);
+            }
+        }
+    }
+}
+
+fn crash_recovery_with_seed(seed: u64) {
+    const OPERATIONS: [FileOperation; 6] = [
+        FileOperation::Write,
+        FileOperation::SetLen,
+        FileOperation::SyncData,
+        FileOperation::SyncDirectory,
+        FileOperation::Rename,
+        FileOperation::Open,
+    ];
+
+    let rng = fastrand::Rng::with_seed(seed);
+    let checkpointer = CrashCheckpointer::default();
+    let mut manager = FaultyFileManager::default();
+
+    for round in 0..50 {
+        let wal = Configuration::default_with_manager("/", manager.clone())
+            .preallocate_bytes(4096)
+            .checkpoint_after_bytes(rng.u64(256..4096))
+            .open(checkpointer.clone())
+            .unwrap();
+
+        let mut recovered = checkpointer.recovered.lock();
+        let committed = checkpointer.committed.lock();
+        for (entry_id, chunks) in &*committed {
+            assert_eq!(
+                recovered.get(entry_id),
+                Some(chunks),
+                "round {round}: {entry_id:?} was lost (seed {seed})"
+            );
+        }
+        recovered.clear();
+        drop(committed);
+        drop(recovered);
+
+        if rng.bool() {
+            manager.fail_after(OPERATIONS[rng.usize(..OPERATIONS.len())], rng.u32(..50));
+        }
+
+        for _ in 0..rng.usize(1..20) {
+            let chunks = (0..rng.usize(1..4))
+                .map(|_| repeat_with(|| rng.u8(..)).take(rng.usize(..512)).collect())
+                .collect::<Vec<Vec<u8>>>();
+            let mut writer = match wal.begin_entry() {
+                Ok(writer) => writer,
+                Err(_) => break,
+            };
+            if chunks
+                .iter()
+                .try_for_each(|chunk| writer.write_chunk(chunk).map(|_| ()))
+                .is_err()
+            {
+                let _ = writer.rollback();
+                break;
+            }
+
+            // The entry must be known before it is committed, as it can be
+            // checkpointed before this thread continues.
+            let entry_id = writer.id();
+            checkpointer.committed.lock().insert(entry_id, chunks);
+            if writer.commit().is_err() {
+                checkpointer.committed.lock().remove(&entry_id);
+                break;
+            }
+        }
+
+        let crash = if rng.bool() {
+            Crash::LoseUnsynced
+        } else {
+            Crash::Torn { seed: rng.u64(..) }
+        };
+        manager = manager.crash(crash).unwrap();
+        assert_eq!(manager.unsynced_changes(), 0);
+        drop(wal);
+    }
+}
+
+/// Commits an entry on another thread, failing if the commit doesn't finish
+/// in time instead of waiting forever.
+fn commit_within_timeout(wal: &WriteAheadLog<FaultyFileManager>) -> io::Result<EntryId> {
+    let wal = wal.clone();
+    let (sender, receiver) = flume::bounded(1);
+    std::thread::spawn(move || {
+        let result = wal.begin_entry().and_then(|mut writer| {
+            writer.write_chunk(b"entry")?;
+            writer.commit()
+        });
+        let _ = sender.send(result);
+    });
+    receiver
+        .recv_timeout(Duration::from_secs(10))
+        .expect("commit never finished")
+}
+
+#[test]
+fn sync_failure() {
+    let manager = FaultyFileManager::default();
+    let wal = Configuration::default_with_manager("/", manager.clone())
+        .open(LoggingCheckpointer::default())
+        .unwrap();
+
+    // Each failure must leave the segment able to be synchronized again:
+    // duplicating the file's handle (counted as an open) fails before the
+    // fsync starts, and the fsync itself fails after it starts.
+    for operation in [FileOperation::Open, FileOperation::SyncData] {
+        manager.fail_after(operation, 0);
+        assert!(commit_within_timeout(&wal).is_err());
+        commit_within_timeout(&wal).unwrap();
+    }
+    assert_eq!(manager.unsynced_changes(), 0);
+}
+
+#[test]
+fn directory_sync_failure() {
+    let manager = FaultyFileManager::default();
+    let wal = Configuration::default_with_manager("/", manager.clone())
+        .open(LoggingCheckpointer::default())
+        .unwrap();
+
+    // The first commit synchronizes the directory containing the new segment.
+    // Once that fails, the next commit must try again rather than wait for
+    // the failed sync to finish.
+    manager.fail_after(FileOperation::SyncDirectory, 0);
+    assert!(commit_within_timeout(&wal).is_err());
+    commit_within_timeout(&wal).unwrap();
+    assert_eq!(wal.stats().directory_syncs, 1);
+}
+
+#[test]
+fn activation_failure() {
+    let manager = FaultyFileManager::default();
+    let wal = Configuration::default_with_manager("/", manager.clone())
+        .checkpoint_after_bytes(1)
+        .open(LoggingCheckpointer::default())
+        .unwrap();
+
+    // Each commit fills the segment, which activates a new one. Once creating
+    // it fails, the full segment must remain active so that the next commit
+    // can try again.
+    manager.fail_after(FileOperation::Open, 0);
+    assert!(commit_within_timeout(&wal).is_err());
+    commit_within_timeout(&wal).unwrap();
+}
+
+#[test]
+fn crash_after_activation() {
+    for crash in [
+        Crash::LoseUnsynced,
+        Crash::Torn { seed: 1 },
+        Crash::Torn { seed: 2 },
+        Crash::Torn { seed: 3 },
+    ] {
+        // Activating a segment doesn't synchronize its header, so a crash
+        // can leave any part of it behind.
+        let manager = FaultyFileManager::default();
+        let wal = Configuration::default_with_manager("/", manager.clone())
+            .open(LoggingCheckpointer::default())
+            .unwrap();
+        assert!(manager.unsynced_changes() > 0);
+        let manager = manager.crash(crash).unwrap();
+        drop(wal);
+
+        let wal = Configuration::default_with_manager("/", manager.clone())
+            .open(LoggingCheckpointer::default())
+            .unwrap();
+        let mut writer = wal.begin_entry().unwrap();
+        writer.write_chunk(b"entry").unwrap();
+        let entry_id = writer.commit().unwrap();
+        let manager = manager.crash(crash).unwrap();
+        drop(wal);
+
+        let checkpointer = LoggingCheckpointer::default();
+        let _wal = Configuration::default_with_manager("/", manager)
+            .open(checkpointer.clone())
+            .unwrap();
+        assert_eq!(checkpointer.recovered_entry_ids(), [entry_id], "{crash:?}");
+    }
+}
+
+#[test]
+fn faulty_rename_replaces_file() {
+    let manager = FaultyFileManager::default();
+    let replaced = PathId::from(Path::new("/replaced"));
+    let renamed = PathId::from(Path::new("/renamed"));
+    for (path, contents) in [(&replaced, &b"unsynced"[..]), (&renamed, b"synced")] {
+        let mut file = manager
+            .open(path, OpenOptions::new().write(true).create(true))
+            .unwrap();
+        file.write_all(contents).unwrap();
+        if path == &renamed {
+            file_manager::File::sync_all(&file).unwrap();
+        }
+    }
+
+    // Renaming over a file discards its unsynchronized changes along with
+    // it, rather than undoing them on the file that replaced it.
+    manager.rename(&renamed, replaced.clone()).unwrap();
+    assert_eq!(manager.unsynced_changes(), 0);
+    let manager = manager.crash(Crash::LoseUnsynced).unwrap();
+    let mut contents = Vec::new();
+    manager
+        .open(&replaced, OpenOptions::new().read(true))
+        .unwrap()
+        .read_to_end(&mut contents)
+        .unwrap();
+    assert_eq!(contents, b"synced");
+}
+
+#[test]
+fn durability() {
+    let manager = FaultyFileManager::default();
+    let wal = Configuration::default_with_manager("/", manager.clone())
+        .open(LoggingCheckpointer::default())
+        .unwrap();
+
+    // Only entries committed with `Durability::Fsync` are synchronized, and
+    // only entries committed with `Durability::Flush` are written to the file
+    // before the commit returns.
+    let mut committed = Vec::new();
+    for (durability, written) in [
+        (Durability::Fsync, 0),
+        (Durability::Flush, 1),
+        (Durability::Buffered, 1),
+    ] {
+        let fsyncs = wal.stats().fsyncs;
+        let mut writer = wal.begin_entry().unwrap();
+        let chunk = format!("{durability:?}").into_bytes();
+        writer.write_chunk(&chunk).unwrap();
+        committed.push((writer.commit_with(durability).unwrap(), vec![chunk]));
+        assert_eq!(wal.stats().fsyncs > fsyncs, durability == Durability::Fsync);
+        assert_eq!(manager.unsynced_changes(), written);
+    }
+
+    // A crash loses the entries that weren't synchronized.
+    let manager = manager.crash(Crash::LoseUnsynced).unwrap();
+    drop(wal);
+    let checkpointer = LoggingCheckpointer::default();
+    let wal = Configuration::default_with_manager("/", manager.clone())
+        .open(checkpointer.clone())
+        .unwrap();
+    assert_eq!(recovered_entries(&checkpointer), &committed[..1]);
+
+    // Reading an entry synchronizes it.
+    let mut writer = wal.begin_entry().unwrap();
+    let record = writer.write_chunk(b"read").unwrap();
+    writer.commit_with(Durability::Buffered).unwrap();
+    let mut reader = wal.read_at(record.position).unwrap();
+    let mut buffer = Vec::new();
+    reader.read_to_end(&mut buffer).unwrap();
+    assert_eq!(buffer, b"read");
+    assert_eq!(manager.unsynced_changes(), 0);
+    drop(reader);
+    wal.shutdown().unwrap();
+
+    // The background flusher synchronizes buffered entries, and shutting down
+    // synchronizes the rest.
+    let wal = Configuration::default_with_manager("/", manager.clone())
+        .flush_interval(Duration::from_millis(10))
+        .open(LoggingCheckpointer::default())
+        .unwrap();
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"flushed").unwrap();
+    let flushed_id = writer.commit_with(Durability::Buffered).unwrap();
+    let mut subscription = wal.subscribe(flushed_id).unwrap();
+    let flushed = subscription
+        .next_timeout(Duration::from_secs(10))
+        .unwrap()
+        .unwrap();
+    assert_eq!(flushed.chunks, vec![b"flushed".to_vec()]);
+    drop(subscription);
+    let mut writer = wal.begin_entry().unwrap();
+    writer.write_chunk(b"shutdown").unwrap();
+    let shutdown_id = writer.commit_with(Durability::Buffered).unwrap();
+    wal.shutdown().unwrap();
+
+    let manager = manager.crash(Crash::LoseUnsynced).unwrap();
+    let checkpointer = LoggingCheckpointer::default();
+    let _wal = Configuration::default_with_manager("/", manager)
+        .open(checkpointer.clone())
+        .unwrap();
+    let recovered = recovered_entries(&checkpointer);
+    assert_eq!(
+        recovered.last(),
+        Some(&(shutdown_id, vec![b"shutdown".to_vec()]))
+    );
+}
+
+#[cfg(feature = "async")]
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    use std::task::{Context, Poll, Wake, Waker};
+
+    struct ThreadWaker(std::thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut context = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(result) => return result,
+            Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "async")]
+fn async_commit_and_checkpoint() {
+    let checkpointer = LoggingCheckpointer::default();
+    let wal = crate::AsyncWriteAheadLog::from(
+        Configuration::default_with_manager("/", MemoryFileManager::default())
+            .open(checkpointer.clone())
+            .unwrap(),
+    );
+
+    let mut writer = wal.begin_entry();
+    writer.write_chunk(b"first message").unwrap();
+    let first = block_on(writer.commit()).unwrap();
+    assert_eq!(first.chunks.len(), 1);
+
+    let mut reader = block_on(wal.read_at(first.chunks[0].position)).unwrap();
+    let mut buffer = Vec::new();
+    reader.read_to_end(&mut buffer).unwrap();
+    assert_eq!(buffer, b"first message");
+    assert!(reader.crc_is_valid().unwrap());
+    drop(reader);
+
+    let mut writer = wal.begin_entry();
+    writer.write_chunk(b"second message").unwrap();
+    let second = block_on(writer.commit_and_checkpoint()).unwrap();
+    block_on(wal.wait_checkpointed(second.id)).unwrap();
+
+    let invocations = checkpointer.invocations.lock();
+    match &invocations[..] {
+        [CheckpointCall::Checkpoint { data }] => {
+            assert_eq!(data[&first.id][0], b"first message");
+            assert_eq!(data[&second.id][0], b"second message");
+        }
+        other => unreachable!("unexpected invocations: {other:?}"),
+    }
+}
+
+#[test]
+#[cfg(feature = "async")]
+fn async_concurrent_commits() {
+    let checkpointer = LoggingCheckpointer::default();
+    let wal = crate::AsyncWriteAheadLog::from(
+        Configuration::default_with_manager("/", MemoryFileManager::default())
+            .open(checkpointer)
+            .unwrap(),
+    );
+
+    // Every commit is handed to the background thread before any of them are
+    // awaited, allowing them to be written together.
+    let commits = (0_u8..16)
+        .map(|index| {
+            let mut writer = wal.begin_entry();
+            writer.write_chunk(&[index; 32]).unwrap();
+            let mut commit = Box::pin(writer.commit());
+            let waker = noop_waker();
+            match std::future::Future::poll(
+                commit.as_mut(),
+                &mut std::task::Context::from_waker(&waker),
+            ) {
+                std::task::Poll::Ready(result) => (commit, Some(result)),
+                std::task::Poll::Pending => (commit, None),
+            }
+        })
+        .collect::<Vec<_>>();
+    let committed = commits
+        .into_iter()
+        .map(|(commit, result)| result.unwrap_or_else(|| block_on(commit)))
+        .collect::<Vec<_>>();
+
+    let mut previous_id = None;
+    for (index, entry) in (0_u8..).zip(committed) {
+        let entry = entry.unwrap();
+        assert!(previous_id < Some(entry.id));
+        previous_id = Some(entry.id);
+
+        let mut reader = block_on(wal.read_at(entry.chunks[0].position)).unwrap();
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer).unwrap();
+        assert_eq!(buffer, [index; 32]);
+        assert!(reader.crc_is_valid().unwrap());
+    }
+}
+
+#[test]
+#[cfg(feature = "async")]
+fn async_batch_sync_failure() {
+    let manager = FaultyFileManager::default();
+    let config = Configuration::default_with_manager("/", manager.clone());
+    let wal = config.clone().open(LoggingCheckpointer::default()).unwrap();
+    // Synchronizes the directory containing the first segment.
+    commit_within_timeout(&wal).unwrap();
+
+    // The batch is written before its sync fails, so its entries must not be
+    // committed again.
+    manager.fail_after(FileOperation::SyncData, 0);
+    let (commits, results): (Vec<_>, Vec<_>) = (0_u8..4)
+        .map(|index| {
+            let mut chunks = crate::staged::StagedChunks::new(&config);
+            chunks.write_chunk(&[index; 32]).unwrap();
+            let (sender, receiver) = flume::bounded(1);
+            ((chunks, false, sender), receiver)
+        })
+        .unzip();
+    crate::asynchronous::commit_all(&wal, commits);
+    for result in results {
+        assert!(result.recv().unwrap().is_err());
+    }
+    commit_within_timeout(&wal).unwrap();
+    drop(wal);
+
+    let checkpointer = LoggingCheckpointer::default();
+    let _wal = config.open(checkpointer.clone()).unwrap();
+    let recovered = recovered_entries(&checkpointer);
+    for index in 0_u8..4 {
+        assert_eq!(
+            recovered
+                .iter()
+                .filter(|(_, data)| data == &[vec![index; 32]])
+                .count(),
+            1
+        );
+    }
+}
+
+#[cfg(feature = "async")]
+fn noop_waker() -> std::task::Waker {
+    use std::task::Wake;
+
+    struct NoopWaker;
+
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    std::task::Waker::from(Arc::new(NoopWaker))
 }
@@ -1,5 +1,7 @@
 use std::{io, num::TryFromIntError};
 
+use crate::Error;
+
 pub trait ToIoResult<T> {
     fn to_io(self) -> io::Result<T>;
 }
@@ -17,10 +19,7 @@ impl<T, U> ToIoResult<T> for Result<T, flume::SendError<U>> {
     fn to_io(self) -> io::Result<T> {
         match self {
             Ok(value) => Ok(value),
-            Err(_) => Err(io::Error::new(
-                io::ErrorKind::BrokenPipe,
-                "sender disconnected",
-            )),
+            Err(_) => Err(Error::Closed.into()),
         }
     }
 }
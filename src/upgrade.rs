@@ -0,0 +1,153 @@
+use std::{ffi::OsStr, io, sync::Arc};
+
+use file_manager::{FileManager, PathId};
+use log::info;
+
+use crate::{
+    directory::DirectoryGuard,
+    list_segments,
+    log_file::{self, LogFile, LogFileWriter},
+    recovery::{self, SALVAGE_PREFIX},
+    staged::StagedChunks,
+    Configuration, RecoveryMode, SegmentFile, SegmentReader,
+};
+
+/// The prefix of the name of the file a segment is rewritten to.
+const UPGRADE_PREFIX: &str = "upgrade-";
+
+/// Rewrites the segments in `config.directory` that haven't been checkpointed
+/// and are written in an older format than `config.format_version`. Returns
+/// the ids of the rewritten segments.
+pub(crate) fn upgrade_segments<M: FileManager>(config: &Configuration<M>) -> io::Result<Vec<u64>> {
+    let mut upgraded = Vec::new();
+    if !config.file_manager.exists(&config.directory) {
+        return Ok(upgraded);
+    }
+    let _directory = DirectoryGuard::upgrade(config)?;
+    remove_abandoned_files(config)?;
+
+    for segment in list_segments(&config.file_manager, &config.directory)? {
+        if segment.checkpointed {
+            continue;
+        }
+        let reader = match SegmentReader::open(&segment.path, segment.id, config) {
+            Ok(reader) => reader,
+            // Nothing was committed to a segment whose header was never
+            // written, and it is reused when the log is opened.
+            Err(_) if log_file::is_uninitialized(&segment.path, &config.file_manager)? => continue,
+            Err(err) => return Err(err),
+        };
+        if reader.header.format_version >= config.format_version {
+            continue;
+        }
+
+        info!(
+            "Upgrading segment {} from format {} to {}",
+            segment.id, reader.header.format_version, config.format_version
+        );
+        rewrite_segment(reader, &segment, config)?;
+        upgraded.push(segment.id);
+    }
+
+    if !upgraded.is_empty() {
+        config.file_manager.sync_all(&config.directory)?;
+    }
+    Ok(upgraded)
+}
+
+/// Removes the files left in `config.directory` by upgrades and salvages that
+/// were interrupted before their segment was replaced.
+pub(crate) fn remove_abandoned_files<M: FileManager>(config: &Configuration<M>) -> io::Result<()> {
+    let mut removed = false;
+    for path in config.file_manager.list(&config.directory)? {
+        if path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .map_or(false, |name| {
+                name.starts_with(UPGRADE_PREFIX) || name.starts_with(SALVAGE_PREFIX)
+            })
+        {
+            info!("Removing abandoned file {}", path.display());
+            config.file_manager.remove_file(&path)?;
+            removed = true;
+        }
+    }
+
+    if removed {
+        config.file_manager.sync_all(&config.directory)?;
+    }
+    Ok(())
+}
+
+/// Writes the entries `reader` recovers to a new file in the configured
+/// format, and replaces the segment with it.
+fn rewrite_segment<M: FileManager>(
+    mut reader: SegmentReader<M::File>,
+    segment: &SegmentFile,
+    config: &Configuration<M>,
+) -> io::Result<()> {
+    // The file is only named like a segment once it is complete.
+    let upgrade_path = PathId::from(
+        config
+            .directory
+            .join(format!("{UPGRADE_PREFIX}{}", segment.id)),
+    );
+
+    // The rewritten segment keeps the version info it was created with, and
+    // isn't sent to the replicator, whose copy of the segment can't be
+    // replaced.
+    let mut segment_config = config.clone();
+    segment_config.version_info = Arc::new(reader.header.version_info.clone());
+    let file = LogFile::write(
+        segment.id,
+        upgrade_path.clone(),
+        0,
+        None,
+        None,
+        &segment_config,
+    )?;
+
+    let mut writer = file.lock();
+    loop {
+        copy_entries(&mut reader, &mut writer, &segment_config)?;
+        if config.recovery_mode == RecoveryMode::Tolerant
+            || recovery::skip_corruption(&mut reader, &segment.path, config)?.is_none()
+        {
+            break;
+        }
+    }
+    let length = writer.position();
+    drop(writer);
+    file.synchronize(length)?;
+    drop(file);
+    drop(reader);
+
+    config
+        .file_manager
+        .rename(&upgrade_path, segment.path.clone())
+}
+
+/// Copies the entries `reader` can read to `writer`, until an entry can't be
+/// read.
+fn copy_entries<M: FileManager>(
+    reader: &mut SegmentReader<M::File>,
+    writer: &mut LogFileWriter<M::File>,
+    config: &Configuration<M>,
+) -> io::Result<()> {
+    while reader.verify_next_entry()? {
+        let mut entry = match reader.read_entry()? {
+            Some(entry) => entry,
+            None => break,
+        };
+        let chunks = match entry.read_all_chunks()? {
+            Some(chunks) => chunks,
+            None => break,
+        };
+        let mut staged = StagedChunks::new(config);
+        for chunk in &chunks {
+            staged.write_chunk(chunk)?;
+        }
+        staged.write_to(writer, entry.id())?;
+    }
+    Ok(())
+}
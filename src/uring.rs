@@ -0,0 +1,503 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    os::unix::fs::FileExt,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use file_manager::{fs::StdFileManager, File, FileManager, OpenOptions, PathId};
+use log::warn;
+use parking_lot::{Condvar, Mutex};
+use tokio_uring::{buf::IoBuf, Runtime};
+
+use crate::{to_io_result::ToIoResult, write_through::seek_position};
+
+/// A [`FileManager`] that writes and synchronizes files using `io_uring`.
+///
+/// Each write copies its bytes and queues them for a background thread that
+/// owns an `io_uring` instance, and returns without waiting for the write to
+/// complete. [`Write::flush()`] waits for the file's queued writes to
+/// complete, after which other files opened for the same path see the written
+/// bytes. [`File::sync_data()`] queues an `fsync` that the background thread
+/// submits once every write queued before it has completed, rather than
+/// linking it to the writes with `IOSQE_IO_LINK`. Writes to overlapping ranges
+/// of a file are performed in the order they were queued.
+///
+/// At most 1,024 operations can be queued for the background thread at once.
+/// Once the queue is full, writing waits for the thread to catch up.
+///
+/// Once a write fails, every following read, flush, length query and
+/// synchronization of the file returns its error, as the file's contents are
+/// no longer known.
+///
+/// If `io_uring` isn't supported by the kernel, a warning is logged and files
+/// are written using the standard library, the same as with
+/// [`StdFileManager`].
+#[derive(Debug, Clone)]
+pub struct IoUringFileManager {
+    inner: StdFileManager,
+    ring: Option<Arc<Ring>>,
+}
+
+impl Default for IoUringFileManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IoUringFileManager {
+    /// Returns a new file manager, starting the thread that submits its
+    /// operations to `io_uring`.
+    #[must_use]
+    pub fn new() -> Self {
+        let ring = match Ring::start() {
+            Ok(ring) => Some(Arc::new(ring)),
+            Err(err) => {
+                warn!("io_uring is unavailable, using standard file operations: {err}");
+                None
+            }
+        };
+        Self {
+            inner: StdFileManager::default(),
+            ring,
+        }
+    }
+
+    /// Returns true if files are written using `io_uring`, or false if
+    /// `io_uring` is unsupported and the standard library is used instead.
+    #[must_use]
+    pub fn is_using_io_uring(&self) -> bool {
+        self.ring.is_some()
+    }
+}
+
+impl FileManager for IoUringFileManager {
+    type File = IoUringFile;
+
+    fn list(&self, directory: &PathId) -> io::Result<Vec<PathId>> {
+        self.inner.list(directory)
+    }
+
+    fn exists(&self, path: &PathId) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn create_dir_all(&self, path: &PathId) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+
+    fn open(&self, path: &PathId, options: OpenOptions) -> io::Result<Self::File> {
+        // The standard file manager applies the options, such as creating or
+        // truncating the file, before it is reopened for reading and writing.
+        drop(self.inner.open(path, options)?);
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&**path)?;
+        let ring = if let Some(ring) = &self.ring {
+            let id = ring.next_file_id.fetch_add(1, Ordering::Relaxed);
+            ring.submit(Command::Open {
+                id,
+                file: file.try_clone()?,
+            })?;
+            Some(RingFile {
+                id,
+                ring: ring.clone(),
+            })
+        } else {
+            None
+        };
+
+        Ok(IoUringFile {
+            state: Arc::new(FileState {
+                file,
+                ring,
+                pending: Arc::default(),
+            }),
+            manager: self.clone(),
+            position: 0,
+        })
+    }
+
+    fn remove_file(&self, path: &PathId) -> io::Result<()> {
+        self.inner.remove_file(path)
+    }
+
+    fn rename(&self, from: &PathId, to: PathId) -> io::Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn sync_all(&self, path: &PathId) -> io::Result<()> {
+        self.inner.sync_all(path)
+    }
+
+    fn available_space_bytes(&self, path: &PathId) -> io::Result<u64> {
+        self.inner.available_space_bytes(path)
+    }
+
+    fn total_space_bytes(&self, path: &PathId) -> io::Result<u64> {
+        self.inner.total_space_bytes(path)
+    }
+}
+
+/// A file opened by an [`IoUringFileManager`].
+#[derive(Debug)]
+pub struct IoUringFile {
+    state: Arc<FileState>,
+    manager: IoUringFileManager,
+    position: u64,
+}
+
+#[derive(Debug)]
+struct FileState {
+    file: fs::File,
+    ring: Option<RingFile>,
+    /// Shared with the writes in progress, which don't keep the rest of the
+    /// state alive. This ensures the file is always closed by the thread that
+    /// dropped it, rather than by the ring's thread.
+    pending: Arc<PendingWrites>,
+}
+
+#[derive(Debug, Default)]
+struct PendingWrites {
+    state: Mutex<PendingState>,
+    completed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct PendingState {
+    in_progress: usize,
+    error: Option<io::Error>,
+}
+
+impl PendingWrites {
+    fn complete(&self, result: io::Result<()>) {
+        let mut state = self.state.lock();
+        state.in_progress -= 1;
+        if let Err(err) = result {
+            // Only the first error is kept, as later writes may have failed
+            // because of it.
+            state.error.get_or_insert(err);
+        }
+        drop(state);
+        self.completed.notify_all();
+    }
+
+    /// Waits for all queued writes to complete, returning the error of the
+    /// first write that failed, if any.
+    fn wait(&self) -> io::Result<()> {
+        let mut state = self.state.lock();
+        while state.in_progress > 0 {
+            self.completed.wait(&mut state);
+        }
+        match &state.error {
+            Some(err) => Err(io::Error::new(err.kind(), err.to_string())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FileState {
+    fn sync(&self, data_only: bool) -> io::Result<()> {
+        if let Some(ring) = &self.ring {
+            let (result_sender, result) = flume::bounded(1);
+            ring.ring.submit(Command::Sync {
+                id: ring.id,
+                data_only,
+                result: result_sender,
+            })?;
+            let synced = result.recv().map_err(|_| ring_stopped())?;
+            // The sync only runs after the writes queued before it, but a
+            // failed write must still be reported.
+            self.pending.wait()?;
+            synced
+        } else if data_only {
+            self.file.sync_data()
+        } else {
+            self.file.sync_all()
+        }
+    }
+}
+
+impl Read for IoUringFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.state.pending.wait()?;
+        let bytes_read = self.state.file.read_at(buf, self.position)?;
+        self.position += u64::try_from(bytes_read).to_io()?;
+        Ok(bytes_read)
+    }
+}
+
+impl Write for IoUringFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if let Some(ring) = &self.state.ring {
+            self.state.pending.state.lock().in_progress += 1;
+            let submitted = ring.ring.submit(Command::Write {
+                id: ring.id,
+                bytes: buf.to_vec(),
+                position: self.position,
+                pending: self.state.pending.clone(),
+            });
+            if let Err(err) = submitted {
+                self.state.pending.complete(Ok(()));
+                return Err(err);
+            }
+            self.position += u64::try_from(buf.len()).to_io()?;
+            Ok(buf.len())
+        } else {
+            let bytes_written = self.state.file.write_at(buf, self.position)?;
+            self.position += u64::try_from(bytes_written).to_io()?;
+            Ok(bytes_written)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.state.pending.wait()
+    }
+}
+
+impl Seek for IoUringFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = seek_position(pos, self.position, || self.len())?;
+        Ok(self.position)
+    }
+}
+
+impl File for IoUringFile {
+    type Manager = IoUringFileManager;
+
+    fn len(&self) -> io::Result<u64> {
+        self.state.pending.wait()?;
+        Ok(self.state.file.metadata()?.len())
+    }
+
+    fn set_len(&self, new_length: u64) -> io::Result<()> {
+        self.state.pending.wait()?;
+        self.state.file.set_len(new_length)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            state: self.state.clone(),
+            manager: self.manager.clone(),
+            position: self.position,
+        })
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.state.sync(false)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.state.sync(true)
+    }
+}
+
+/// The number of operations that can be queued for a [`Ring`]'s thread before
+/// queueing another waits.
+const QUEUE_LENGTH: usize = 1024;
+
+/// The thread that owns the `io_uring` instance of an [`IoUringFileManager`].
+#[derive(Debug)]
+struct Ring {
+    commands: flume::Sender<Command>,
+    next_file_id: AtomicU64,
+}
+
+impl Ring {
+    fn start() -> io::Result<Self> {
+        let (commands, command_receiver) = flume::bounded(QUEUE_LENGTH);
+        let (started_sender, started) = flume::bounded(1);
+        std::thread::Builder::new()
+            .name(String::from("okaywal-uring"))
+            .spawn(move || match Runtime::new(&tokio_uring::builder()) {
+                Ok(runtime) => {
+                    let _ = started_sender.send(Ok(()));
+                    runtime.block_on(run_ring(&command_receiver));
+                }
+                Err(err) => {
+                    let _ = started_sender.send(Err(err));
+                }
+            })?;
+        started.recv().map_err(|_| ring_stopped())??;
+
+        Ok(Self {
+            commands,
+            next_file_id: AtomicU64::new(0),
+        })
+    }
+
+    fn submit(&self, command: Command) -> io::Result<()> {
+        self.commands.send(command).map_err(|_| ring_stopped())
+    }
+}
+
+/// A file that has been opened by the [`Ring`]'s thread.
+#[derive(Debug)]
+struct RingFile {
+    id: u64,
+    ring: Arc<Ring>,
+}
+
+impl Drop for RingFile {
+    fn drop(&mut self) {
+        // The ring may have already stopped, which is fine.
+        let _ = self.ring.submit(Command::Close { id: self.id });
+    }
+}
+
+#[derive(Debug)]
+enum Command {
+    Open {
+        id: u64,
+        file: fs::File,
+    },
+    Write {
+        id: u64,
+        bytes: Vec<u8>,
+        position: u64,
+        pending: Arc<PendingWrites>,
+    },
+    Sync {
+        id: u64,
+        data_only: bool,
+        result: flume::Sender<io::Result<()>>,
+    },
+    Close {
+        id: u64,
+    },
+}
+
+/// A file owned by the [`Ring`]'s thread, and the writes to it that are in
+/// progress.
+struct OpenFile {
+    file: Rc<tokio_uring::fs::File>,
+    writes: Vec<WriteInProgress>,
+}
+
+impl OpenFile {
+    /// Returns receivers that are disconnected once each write in progress
+    /// that overlaps `range` completes.
+    fn writes_overlapping(&mut self, range: Range<u64>) -> Vec<flume::Receiver<()>> {
+        self.writes
+            .retain(|write| !write.completed.is_disconnected());
+        self.writes
+            .iter()
+            .filter(|write| write.range.start < range.end && range.start < write.range.end)
+            .map(|write| write.completed.clone())
+            .collect()
+    }
+}
+
+struct WriteInProgress {
+    range: Range<u64>,
+    /// Disconnected when the write completes.
+    completed: flume::Receiver<()>,
+}
+
+async fn wait_for_all(writes: Vec<flume::Receiver<()>>) {
+    for write in writes {
+        // The write has completed once its sender is dropped.
+        let _ = write.recv_async().await;
+    }
+}
+
+async fn run_ring(commands: &flume::Receiver<Command>) {
+    let mut files = HashMap::new();
+    while let Ok(command) = commands.recv_async().await {
+        match command {
+            Command::Open { id, file } => {
+                files.insert(
+                    id,
+                    OpenFile {
+                        file: Rc::new(tokio_uring::fs::File::from_std(file)),
+                        writes: Vec::new(),
+                    },
+                );
+            }
+            Command::Write {
+                id,
+                bytes,
+                position,
+                pending,
+            } => {
+                if let Some(open) = files.get_mut(&id) {
+                    let range = position..position + bytes.len() as u64;
+                    let overlapping = open.writes_overlapping(range.clone());
+                    let (completed_sender, completed) = flume::bounded::<()>(1);
+                    open.writes.push(WriteInProgress { range, completed });
+
+                    let file = open.file.clone();
+                    tokio_uring::spawn(async move {
+                        wait_for_all(overlapping).await;
+                        let result = write_all_at(&file, bytes, position).await;
+                        pending.complete(result);
+                        drop(completed_sender);
+                    });
+                } else {
+                    pending.complete(Err(ring_stopped()));
+                }
+            }
+            Command::Sync {
+                id,
+                data_only,
+                result,
+            } => {
+                if let Some(open) = files.get_mut(&id) {
+                    let file = open.file.clone();
+                    // Waiting for the writes queued before the sync ensures
+                    // that it covers them.
+                    let writes = open.writes_overlapping(0..u64::MAX);
+                    tokio_uring::spawn(async move {
+                        wait_for_all(writes).await;
+                        let synced = if data_only {
+                            file.sync_data().await
+                        } else {
+                            file.sync_all().await
+                        };
+                        let _ = result.send(synced);
+                    });
+                } else {
+                    let _ = result.send(Err(ring_stopped()));
+                }
+            }
+            Command::Close { id } => {
+                files.remove(&id);
+            }
+        }
+    }
+}
+
+async fn write_all_at(
+    file: &tokio_uring::fs::File,
+    mut bytes: Vec<u8>,
+    position: u64,
+) -> io::Result<()> {
+    let mut written = 0;
+    while written < bytes.len() {
+        let offset = position + u64::try_from(written).to_io()?;
+        let (result, slice) = file.write_at(bytes.slice(written..), offset).await;
+        bytes = slice.into_inner();
+        match result {
+            Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+            Ok(bytes_written) => written += bytes_written,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+fn ring_stopped() -> io::Error {
+    io::Error::new(ErrorKind::BrokenPipe, "io_uring thread stopped")
+}
@@ -0,0 +1,407 @@
+use std::{
+    fs::{self, OpenOptions as StdOpenOptions},
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    os::unix::fs::{FileExt, OpenOptionsExt},
+    sync::Arc,
+};
+
+use file_manager::{fs::StdFileManager, File, FileManager, OpenOptions, PathId};
+use parking_lot::Mutex;
+
+use crate::to_io_result::ToIoResult;
+
+/// The alignment of the offsets, lengths and buffers used by
+/// [`WriteThrough::Direct`].
+const BLOCK: usize = 4096;
+
+/// How a [`WriteThroughFileManager`] opens files.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum WriteThrough {
+    /// Files are opened with `O_DSYNC`, which causes each write to be
+    /// synchronized to disk before it returns.
+    DataSync,
+    /// Files are opened with `O_DIRECT` and `O_DSYNC`, which causes each write
+    /// to bypass the page cache and be synchronized to disk before it returns.
+    ///
+    /// Direct I/O requires reads and writes to cover whole blocks, so each
+    /// read or write is performed using an aligned buffer covering the blocks
+    /// it touches. A write that ends beyond the end of the file extends the
+    /// file to the end of its last block. Not every filesystem supports
+    /// direct I/O; opening a file returns an error if it isn't supported.
+    ///
+    /// This mode is only supported on Linux.
+    Direct,
+}
+
+/// A [`FileManager`] that opens files so that writes are synchronized to disk
+/// as they are made, avoiding the need for a separate `fsync`.
+///
+/// When a [`WriteAheadLog`](crate::WriteAheadLog) is opened with this file
+/// manager, synchronizing a segment only writes its buffered bytes, as
+/// [`File::sync_data()`] has nothing left to do. [`File::sync_all()`] still
+/// synchronizes the file's metadata.
+///
+/// Files are always opened for both reading and writing, after being opened
+/// with the requested [`OpenOptions`] to create or truncate them.
+#[derive(Debug, Clone)]
+pub struct WriteThroughFileManager {
+    mode: WriteThrough,
+    inner: StdFileManager,
+}
+
+impl WriteThroughFileManager {
+    /// Returns a file manager that opens files using `mode`.
+    #[must_use]
+    pub fn new(mode: WriteThrough) -> Self {
+        Self {
+            mode,
+            inner: StdFileManager::default(),
+        }
+    }
+
+    /// Returns the mode files are opened with.
+    #[must_use]
+    pub const fn mode(&self) -> WriteThrough {
+        self.mode
+    }
+
+    #[allow(clippy::unnecessary_wraps)] // Direct I/O fails on other platforms.
+    fn custom_flags(&self) -> io::Result<i32> {
+        match self.mode {
+            WriteThrough::DataSync => Ok(libc::O_DSYNC),
+            #[cfg(target_os = "linux")]
+            WriteThrough::Direct => Ok(libc::O_DIRECT | libc::O_DSYNC),
+            #[cfg(not(target_os = "linux"))]
+            WriteThrough::Direct => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "direct i/o is only supported on linux",
+            )),
+        }
+    }
+}
+
+impl FileManager for WriteThroughFileManager {
+    type File = WriteThroughFile;
+
+    fn list(&self, directory: &PathId) -> io::Result<Vec<PathId>> {
+        self.inner.list(directory)
+    }
+
+    fn exists(&self, path: &PathId) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn create_dir_all(&self, path: &PathId) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+
+    fn open(&self, path: &PathId, options: OpenOptions) -> io::Result<Self::File> {
+        // The standard file manager applies the options, such as creating or
+        // truncating the file, before it is reopened with the custom flags.
+        drop(self.inner.open(path, options)?);
+        let file = StdOpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(self.custom_flags()?)
+            .open(&**path)?;
+        Ok(WriteThroughFile {
+            file: Arc::new(file),
+            manager: self.clone(),
+            position: 0,
+            blocks: Arc::default(),
+        })
+    }
+
+    fn remove_file(&self, path: &PathId) -> io::Result<()> {
+        self.inner.remove_file(path)
+    }
+
+    fn rename(&self, from: &PathId, to: PathId) -> io::Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn sync_all(&self, path: &PathId) -> io::Result<()> {
+        self.inner.sync_all(path)
+    }
+
+    fn available_space_bytes(&self, path: &PathId) -> io::Result<u64> {
+        self.inner.available_space_bytes(path)
+    }
+
+    fn total_space_bytes(&self, path: &PathId) -> io::Result<u64> {
+        self.inner.total_space_bytes(path)
+    }
+}
+
+/// A file opened by a [`WriteThroughFileManager`].
+#[derive(Debug)]
+pub struct WriteThroughFile {
+    file: Arc<fs::File>,
+    manager: WriteThroughFileManager,
+    position: u64,
+    /// The blocks used by [`WriteThrough::Direct`], which are shared with the
+    /// file's clones.
+    blocks: Arc<Mutex<DirectBlocks>>,
+}
+
+impl WriteThroughFile {
+    fn is_direct(&self) -> bool {
+        self.manager.mode == WriteThrough::Direct
+    }
+}
+
+/// Performs reads and writes covering whole blocks, as required by direct
+/// I/O.
+#[derive(Debug, Default)]
+pub(crate) struct DirectBlocks {
+    /// The aligned buffer each read and write is performed with.
+    buffer: AlignedBuffer,
+    /// The contents of the last block written, which allows the next write to
+    /// continue in the same block without reading it from disk.
+    last_block: Option<CachedBlock>,
+}
+
+#[derive(Debug)]
+struct CachedBlock {
+    offset: u64,
+    data: Vec<u8>,
+}
+
+impl DirectBlocks {
+    /// Reads the blocks covering `buf` at `position` into the aligned buffer
+    /// and copies the requested bytes out of it.
+    pub(crate) fn read(
+        &mut self,
+        file: &fs::File,
+        position: u64,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        let start = align_down(position);
+        let end = align_up(position + u64::try_from(buf.len()).to_io()?);
+        let blocks = self.buffer.zeroed(usize::try_from(end - start).to_io()?)?;
+        let bytes_read = read_at_most(file, blocks, start)?;
+
+        let skipped = usize::try_from(position - start).to_io()?;
+        let bytes_to_copy = bytes_read.saturating_sub(skipped).min(buf.len());
+        buf[..bytes_to_copy].copy_from_slice(&blocks[skipped..skipped + bytes_to_copy]);
+        Ok(bytes_to_copy)
+    }
+
+    /// Writes `buf` at `position` by writing the whole blocks it touches. The
+    /// existing contents of the first and last block are preserved.
+    pub(crate) fn write(&mut self, file: &fs::File, position: u64, buf: &[u8]) -> io::Result<()> {
+        let start = align_down(position);
+        let end = position + u64::try_from(buf.len()).to_io()?;
+        let aligned_end = align_up(end);
+        let last_block = self.last_block.take();
+        let data = self
+            .buffer
+            .zeroed(usize::try_from(aligned_end - start).to_io()?)?;
+
+        let write_offset = usize::try_from(position - start).to_io()?;
+        if write_offset > 0 {
+            load_block(file, last_block.as_ref(), start, &mut data[..BLOCK])?;
+        }
+        let last_block_offset = aligned_end - BLOCK as u64;
+        if end < aligned_end && (last_block_offset > start || write_offset == 0) {
+            let last_block_start = usize::try_from(last_block_offset - start).to_io()?;
+            load_block(
+                file,
+                last_block.as_ref(),
+                last_block_offset,
+                &mut data[last_block_start..],
+            )?;
+        }
+        data[write_offset..write_offset + buf.len()].copy_from_slice(buf);
+
+        file.write_all_at(data, start)?;
+        let mut cached = last_block.unwrap_or_else(|| CachedBlock {
+            offset: last_block_offset,
+            data: vec![0; BLOCK],
+        });
+        cached.offset = last_block_offset;
+        cached.data.copy_from_slice(&data[data.len() - BLOCK..]);
+        self.last_block = Some(cached);
+        Ok(())
+    }
+
+    /// Forgets the last block written, which may no longer match the file.
+    pub(crate) fn invalidate(&mut self) {
+        self.last_block = None;
+    }
+}
+
+/// Copies the block at `offset` into `block`, from the cached last block if
+/// possible.
+fn load_block(
+    file: &fs::File,
+    last_block: Option<&CachedBlock>,
+    offset: u64,
+    block: &mut [u8],
+) -> io::Result<()> {
+    match last_block {
+        Some(cached) if cached.offset == offset => {
+            block.copy_from_slice(&cached.data);
+        }
+        _ => {
+            // The block is read directly into place, as it is aligned.
+            read_at_most(file, block, offset)?;
+        }
+    }
+    Ok(())
+}
+
+impl Read for WriteThroughFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            Ok(0)
+        } else if self.is_direct() {
+            let bytes_read = self.blocks.lock().read(&self.file, self.position, buf)?;
+            self.position += u64::try_from(bytes_read).to_io()?;
+            Ok(bytes_read)
+        } else {
+            let bytes_read = self.file.read_at(buf, self.position)?;
+            self.position += u64::try_from(bytes_read).to_io()?;
+            Ok(bytes_read)
+        }
+    }
+}
+
+impl Write for WriteThroughFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            Ok(0)
+        } else if self.is_direct() {
+            self.blocks.lock().write(&self.file, self.position, buf)?;
+            self.position += u64::try_from(buf.len()).to_io()?;
+            Ok(buf.len())
+        } else {
+            let bytes_written = self.file.write_at(buf, self.position)?;
+            self.position += u64::try_from(bytes_written).to_io()?;
+            Ok(bytes_written)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for WriteThroughFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = seek_position(pos, self.position, || self.len())?;
+        Ok(self.position)
+    }
+}
+
+impl File for WriteThroughFile {
+    type Manager = WriteThroughFileManager;
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn set_len(&self, new_length: u64) -> io::Result<()> {
+        let mut blocks = self.blocks.lock();
+        blocks.invalidate();
+        self.file.set_len(new_length)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            file: self.file.clone(),
+            manager: self.manager.clone(),
+            position: self.position,
+            blocks: self.blocks.clone(),
+        })
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        // Every write has already been synchronized.
+        Ok(())
+    }
+}
+
+/// A buffer whose start is aligned to [`BLOCK`], which is reused for each
+/// read and write.
+#[derive(Debug, Default)]
+struct AlignedBuffer {
+    bytes: Vec<u8>,
+    offset: usize,
+}
+
+impl AlignedBuffer {
+    /// Returns `len` zeroed bytes starting at an aligned address, growing the
+    /// buffer if needed.
+    fn zeroed(&mut self, len: usize) -> io::Result<&mut [u8]> {
+        if self.bytes.len() < self.offset + len {
+            self.bytes = vec![0; len + BLOCK];
+            self.offset = self.bytes.as_ptr().align_offset(BLOCK);
+            if self.offset >= BLOCK {
+                return Err(io::Error::new(
+                    ErrorKind::Other,
+                    "unable to allocate an aligned buffer",
+                ));
+            }
+        }
+        let bytes = &mut self.bytes[self.offset..self.offset + len];
+        bytes.fill(0);
+        Ok(bytes)
+    }
+}
+
+/// Reads into `buf` at `offset` until it is full or the end of the file is
+/// reached, returning the number of bytes read.
+fn read_at_most(file: &fs::File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut total_read = 0;
+    while total_read < buf.len() {
+        match file.read_at(&mut buf[total_read..], offset + total_read as u64) {
+            Ok(0) => break,
+            Ok(bytes_read) => total_read += bytes_read,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(total_read)
+}
+
+/// Returns the position that `pos` refers to in a file whose current position
+/// is `position`. `len` is only called when seeking relative to the end of the
+/// file.
+pub(crate) fn seek_position(
+    pos: SeekFrom,
+    position: u64,
+    len: impl FnOnce() -> io::Result<u64>,
+) -> io::Result<u64> {
+    let new_position = match pos {
+        SeekFrom::Start(offset) => Some(offset),
+        SeekFrom::End(offset) => checked_add_signed(len()?, offset),
+        SeekFrom::Current(offset) => checked_add_signed(position, offset),
+    };
+    new_position.ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            "invalid seek to a negative or overflowing position",
+        )
+    })
+}
+
+fn checked_add_signed(position: u64, offset: i64) -> Option<u64> {
+    if offset < 0 {
+        position.checked_sub(offset.unsigned_abs())
+    } else {
+        position.checked_add(offset.unsigned_abs())
+    }
+}
+
+const fn align_down(offset: u64) -> u64 {
+    offset - offset % BLOCK as u64
+}
+
+const fn align_up(offset: u64) -> u64 {
+    align_down(offset + BLOCK as u64 - 1)
+}